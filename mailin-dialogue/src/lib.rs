@@ -0,0 +1,470 @@
+//! Replayable SMTP dialogues for testing mailin handlers and servers
+//!
+//! A `Script` is a scripted conversation made of lines sent by the client and the reply
+//! codes expected from the server. The same script can be run against an in-memory
+//! `mailin::Session` or against a live `mailin_embedded::Server`.
+//!
+//! Scripts are written one step per line:
+//!
+//! ```text
+//! # Lines starting with '#' are comments
+//! S: 220
+//! C: EHLO client.example.com
+//! S: 250
+//! C: QUIT
+//! S: 221 Goodbye
+//! ```
+//!
+//! `C:` lines are sent by the client, the line ending is added by the harness. `S:` lines
+//! give the reply code expected from the server, any text after the code is ignored.
+//! Several `C:` lines can be sent before the replies are checked, as a pipelining client
+//! would do. Lines of a message body do not get a reply.
+//!
+//! # Examples
+//! ```
+//! use mailin::{Handler, SessionBuilder};
+//! use mailin_dialogue::Script;
+//! # use std::net::{IpAddr, Ipv4Addr};
+//!
+//! struct MyHandler {}
+//! impl Handler for MyHandler {}
+//!
+//! let script = Script::parse(
+//!     "greeting",
+//!     "S: 220\nC: HELO client.example.com\nS: 250\n",
+//! )?;
+//! let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+//! let mut session = SessionBuilder::new("server.example.com").build(addr, MyHandler {});
+//! script.run_session(&mut session)?;
+//! # Ok::<(), mailin_dialogue::Error>(())
+//! ```
+
+#![forbid(unsafe_code)]
+#![forbid(missing_docs)]
+
+/// Scenarios that check conformance with RFC 5321
+pub mod rfc5321;
+
+use mailin::{Handler, Response, Session};
+use mailin_embedded::{Server, ShutdownHandle};
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::panic;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+// How long to wait for replies that the script does not expect
+const EXTRA_REPLY_TIMEOUT: Duration = Duration::from_millis(200);
+// How long sessions can continue when a server is stopped
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+
+/// A single step of a scripted dialogue
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// A line sent by the client, without the line ending
+    Send(String),
+    /// The reply code expected from the server
+    Expect(u16),
+}
+
+/// A scripted SMTP dialogue
+#[derive(Clone, Debug)]
+pub struct Script {
+    name: String,
+    // Steps with the line number where they were defined
+    steps: Vec<(usize, Step)>,
+}
+
+/// An error parsing or running a script
+#[derive(Debug)]
+pub struct Error {
+    script: String,
+    line: usize,
+    msg: String,
+}
+
+impl Error {
+    fn new<S: Into<String>>(script: &str, line: usize, msg: S) -> Self {
+        Self {
+            script: script.to_owned(),
+            line,
+            msg: msg.into(),
+        }
+    }
+
+    /// The name of the script that failed
+    pub fn script(&self) -> &str {
+        &self.script
+    }
+
+    /// The line of the script where the failure happened, 0 if it happened after the
+    /// last line
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.script, self.line, self.msg)
+    }
+}
+
+impl error::Error for Error {}
+
+impl Script {
+    /// Create an empty script with the given name, steps are added with `send` and `expect`
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            steps: Vec::new(),
+        }
+    }
+
+    /// Parse a script from text, the name is used in error messages
+    pub fn parse<S: Into<String>>(name: S, text: &str) -> Result<Self, Error> {
+        let mut script = Self::new(name);
+        for (i, line) in text.lines().enumerate() {
+            let line_num = i + 1;
+            let step = if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            } else if let Some(client) = line.strip_prefix("C:") {
+                Step::Send(client.strip_prefix(' ').unwrap_or(client).to_owned())
+            } else if let Some(server) = line.strip_prefix("S:") {
+                let code = server.trim_start().get(0..3).and_then(|c| c.parse().ok());
+                match code {
+                    Some(code) => Step::Expect(code),
+                    None => {
+                        return Err(Error::new(&script.name, line_num, "Missing reply code"));
+                    }
+                }
+            } else {
+                return Err(Error::new(
+                    &script.name,
+                    line_num,
+                    "Lines must start with 'C:', 'S:' or '#'",
+                ));
+            };
+            script.steps.push((line_num, step));
+        }
+        Ok(script)
+    }
+
+    /// Add a line sent by the client
+    pub fn send<S: Into<String>>(&mut self, line: S) -> &mut Self {
+        let line_num = self.steps.len() + 1;
+        self.steps.push((line_num, Step::Send(line.into())));
+        self
+    }
+
+    /// Add a reply code expected from the server
+    pub fn expect(&mut self, code: u16) -> &mut Self {
+        let line_num = self.steps.len() + 1;
+        self.steps.push((line_num, Step::Expect(code)));
+        self
+    }
+
+    /// The name of the script
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The steps of the script
+    pub fn steps(&self) -> impl Iterator<Item = &Step> {
+        self.steps.iter().map(|(_, step)| step)
+    }
+
+    /// Run the script against an in-memory session, starting with the greeting.
+    ///
+    /// Returns an error if a reply code does not match or if the session sends replies
+    /// that the script does not expect.
+    pub fn run_session<H: Handler>(&self, session: &mut Session<H>) -> Result<(), Error> {
+        let mut replies = VecDeque::new();
+        replies.push_back(session.greeting());
+        for (line_num, step) in &self.steps {
+            match step {
+                Step::Send(line) => {
+                    let line = format!("{}\r\n", line);
+                    let response = session.process(line.as_bytes());
+                    if has_reply(&response) {
+                        replies.push_back(response);
+                    }
+                }
+                Step::Expect(code) => match replies.pop_front() {
+                    Some(response) => self.check(*line_num, *code, response.code)?,
+                    None => {
+                        let msg = format!("Expected {} but the session did not reply", code);
+                        return Err(Error::new(&self.name, *line_num, msg));
+                    }
+                },
+            }
+        }
+        match replies.front() {
+            Some(response) => {
+                let msg = format!("Unexpected reply {}", response.code);
+                Err(Error::new(&self.name, 0, msg))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Run the script against a server listening on the given address.
+    ///
+    /// Returns an error if a reply code does not match or if the server sends a reply after
+    /// the last step of the script.
+    pub fn run_server(&self, addr: SocketAddr) -> Result<(), Error> {
+        let io_error = |e: std::io::Error| Error::new(&self.name, 0, e.to_string());
+        let stream = TcpStream::connect(addr).map_err(io_error)?;
+        stream
+            .set_read_timeout(Some(READ_TIMEOUT))
+            .map_err(io_error)?;
+        let mut reader = BufReader::new(stream.try_clone().map_err(io_error)?);
+        let mut writer = stream;
+        for (line_num, step) in &self.steps {
+            let io_error = |e: std::io::Error| Error::new(&self.name, *line_num, e.to_string());
+            match step {
+                Step::Send(line) => {
+                    write!(writer, "{}\r\n", line).map_err(io_error)?;
+                }
+                Step::Expect(code) => {
+                    writer.flush().map_err(io_error)?;
+                    let reply = read_reply(&mut reader).map_err(io_error)?;
+                    match reply {
+                        Some(reply) => self.check(*line_num, *code, reply)?,
+                        None => {
+                            let msg = format!("Expected {} but the connection closed", code);
+                            return Err(Error::new(&self.name, *line_num, msg));
+                        }
+                    }
+                }
+            }
+        }
+        // The server should not send more replies than the script expects
+        let io_error = |e: std::io::Error| Error::new(&self.name, 0, e.to_string());
+        writer.flush().map_err(io_error)?;
+        writer
+            .set_read_timeout(Some(EXTRA_REPLY_TIMEOUT))
+            .map_err(io_error)?;
+        match read_reply(&mut reader) {
+            Ok(Some(reply)) => {
+                let msg = format!("Unexpected reply {}", reply);
+                Err(Error::new(&self.name, 0, msg))
+            }
+            Ok(None) => Ok(()),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(()),
+            Err(e) => Err(io_error(e)),
+        }
+    }
+
+    fn check(&self, line_num: usize, expected: u16, actual: u16) -> Result<(), Error> {
+        if expected == actual {
+            Ok(())
+        } else {
+            let msg = format!("Expected {} but got {}", expected, actual);
+            Err(Error::new(&self.name, line_num, msg))
+        }
+    }
+}
+
+/// Start a server on an ephemeral port of the loopback interface.
+///
+/// The server runs on a background thread until the returned `RunningServer` is stopped or
+/// dropped.
+pub fn start_server<H>(mut server: Server<H>) -> std::io::Result<RunningServer>
+where
+    H: Handler + Clone + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    server.with_tcp_listener(listener);
+    let shutdown = server.shutdown_handle();
+    let thread = thread::spawn(move || server.serve());
+    Ok(RunningServer {
+        addr,
+        shutdown,
+        thread: Some(thread),
+    })
+}
+
+/// A server started by `start_server`.
+///
+/// The server is shut down when this is dropped, dropping panics if the server failed.
+/// Use `stop` to get the error instead.
+pub struct RunningServer {
+    addr: SocketAddr,
+    shutdown: ShutdownHandle,
+    thread: Option<JoinHandle<Result<(), mailin_embedded::err::Error>>>,
+}
+
+impl RunningServer {
+    /// The address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Shut the server down and return the result of `Server::serve`
+    pub fn stop(mut self) -> Result<(), mailin_embedded::err::Error> {
+        match self.join() {
+            Some(Ok(res)) => res,
+            Some(Err(panic)) => panic::resume_unwind(panic),
+            None => Ok(()),
+        }
+    }
+
+    // Shut the server down and wait for it, returns None if it was already stopped
+    fn join(&mut self) -> Option<thread::Result<Result<(), mailin_embedded::err::Error>>> {
+        let thread = self.thread.take()?;
+        self.shutdown.shutdown(SHUTDOWN_GRACE);
+        Some(thread.join())
+    }
+}
+
+impl Drop for RunningServer {
+    fn drop(&mut self) {
+        let res = self.join();
+        if thread::panicking() {
+            return;
+        }
+        match res {
+            Some(Ok(Err(e))) => panic!("Server failed: {}", e),
+            Some(Err(panic)) => panic::resume_unwind(panic),
+            _ => (),
+        }
+    }
+}
+
+// True if the response is written to the client
+fn has_reply(response: &Response) -> bool {
+    response
+        .buffer()
+        .map(|buf| !buf.is_empty())
+        .unwrap_or_default()
+}
+
+/// Read a possibly multiline reply from an SMTP server and return its code.
+///
+/// Returns None if the connection closed before a reply was read and an error if a line of
+/// the reply does not start with a code.
+pub fn read_reply<R: BufRead>(reader: &mut R) -> std::io::Result<Option<u16>> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let code = line.get(0..3).and_then(|c| c.parse().ok());
+        let is_last = line.as_bytes().get(3) != Some(&b'-');
+        match code {
+            Some(code) if is_last => return Ok(Some(code)),
+            Some(_) => (),
+            None => {
+                let msg = format!("Invalid reply: {}", line.trim_end());
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, msg));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailin::response::NO_MAILBOX;
+    use mailin::SessionBuilder;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[derive(Clone)]
+    struct RcptHandler {}
+    impl Handler for RcptHandler {
+        fn rcpt(&mut self, to: &str) -> Response {
+            if to == "nobody@sea.com" {
+                NO_MAILBOX
+            } else {
+                mailin::response::OK
+            }
+        }
+    }
+
+    fn new_session() -> Session<RcptHandler> {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        SessionBuilder::new("server.sea.com").build(addr, RcptHandler {})
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = Script::parse("bad", "S: 220\nHELO a.domain\n").unwrap_err();
+        assert_eq!(err.line(), 2);
+        let err = Script::parse("bad", "# Comment\n\nS: OK\n").unwrap_err();
+        assert_eq!(err.line(), 3);
+        assert_eq!(err.to_string(), "bad:3: Missing reply code");
+    }
+
+    #[test]
+    fn builder() {
+        let mut script = Script::new("built");
+        script
+            .expect(220)
+            .send("HELO a.domain")
+            .expect(250)
+            .send("MAIL FROM:<ship@sea.com>")
+            .send("RCPT TO:<nobody@sea.com>")
+            .expect(250)
+            .expect(550);
+        assert!(script.run_session(&mut new_session()).is_ok());
+    }
+
+    #[test]
+    fn mismatch() {
+        let script = Script::parse("mismatch", "S: 220\nC: HELO a.domain\nS: 500\n").unwrap();
+        let err = script.run_session(&mut new_session()).unwrap_err();
+        assert_eq!(err.to_string(), "mismatch:3: Expected 500 but got 250");
+        let script = Script::parse("unexpected", "S: 220\nC: HELO a.domain\n").unwrap();
+        let err = script.run_session(&mut new_session()).unwrap_err();
+        assert_eq!(err.to_string(), "unexpected:0: Unexpected reply 250");
+    }
+
+    #[test]
+    fn rfc5321_session() {
+        for script in rfc5321::scenarios() {
+            if let Err(e) = script.run_session(&mut new_session()) {
+                panic!("{}", e);
+            }
+        }
+    }
+
+    #[test]
+    fn rfc5321_server() {
+        let mut server = Server::new(RcptHandler {});
+        server.with_name("server.sea.com");
+        let running = start_server(server).unwrap();
+        for script in rfc5321::scenarios() {
+            if let Err(e) = script.run_server(running.addr()) {
+                panic!("{}", e);
+            }
+        }
+        running.stop().unwrap();
+    }
+
+    #[test]
+    fn extra_server_reply() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"220 server.sea.com\r\n").unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            // A duplicated reply
+            stream.write_all(b"250 OK\r\n250 OK\r\n").unwrap();
+            reader.read_line(&mut line).unwrap();
+        });
+        let script = Script::parse("extra", "S: 220\nC: HELO a.domain\nS: 250\n").unwrap();
+        let err = script.run_server(addr).unwrap_err();
+        assert_eq!(err.to_string(), "extra:0: Unexpected reply 250");
+        server.join().unwrap();
+    }
+}
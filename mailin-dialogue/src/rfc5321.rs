@@ -0,0 +1,34 @@
+use crate::Script;
+
+macro_rules! scenario {
+    ($name:literal) => {
+        (
+            $name,
+            include_str!(concat!("../scenarios/rfc5321/", $name, ".smtp")),
+        )
+    };
+}
+
+const SCENARIOS: &[(&str, &str)] = &[
+    scenario!("transaction"),
+    scenario!("helo"),
+    scenario!("multiple_recipients"),
+    scenario!("transparency"),
+    scenario!("rset"),
+    scenario!("sequence"),
+    scenario!("syntax"),
+    scenario!("noop"),
+    scenario!("vrfy"),
+    scenario!("pipelining"),
+];
+
+/// The bundled RFC 5321 scenarios.
+///
+/// The scenarios expect a server that accepts the mail and recipients sent by the client,
+/// as the default `Handler` does.
+pub fn scenarios() -> Vec<Script> {
+    SCENARIOS
+        .iter()
+        .map(|(name, text)| Script::parse(*name, text).expect("Bundled scenario is invalid"))
+        .collect()
+}
@@ -5,7 +5,7 @@ use std::io;
 /// All crate errors are wrapped in this custom error type
 #[derive(Debug)]
 pub struct Error {
-    original: Option<Box<dyn error::Error>>,
+    original: Option<Box<dyn error::Error + Send + Sync>>,
     msg: String,
 }
 
@@ -23,7 +23,7 @@ impl Error {
     pub(crate) fn with_source<S, E>(msg: S, source: E) -> Self
     where
         S: Into<String>,
-        E: error::Error + 'static,
+        E: error::Error + Send + Sync + 'static,
     {
         Self {
             original: Some(Box::new(source)),
@@ -57,6 +57,8 @@ impl From<io::Error> for Error {
 
 impl error::Error for Error {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        self.original.as_ref().map(|o| o.as_ref())
+        self.original
+            .as_ref()
+            .map(|o| o.as_ref() as &(dyn error::Error + 'static))
     }
 }
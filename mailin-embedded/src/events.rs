@@ -0,0 +1,725 @@
+// An engine that runs many sessions on one thread with non-blocking sockets
+use crate::err::Error;
+cfg_if::cfg_if! {
+    if #[cfg(feature = "ossl")] {
+        use crate::ossl::SslImpl;
+    } else {
+        use crate::rtls::SslImpl;
+    }
+}
+use crate::limits::Permit;
+use crate::line::{cancel_auth, Disposition, LineFramer};
+use crate::proxy::{HeaderReader, HEADER_TIMEOUT};
+use crate::running::{peer_ip, reject, ListenerState};
+use crate::shutdown::{ConnectionGuard, ShutdownHandle};
+use crate::ssl::Stream;
+use crate::timeouts::PhaseClock;
+use log::{debug, error, info};
+use mailin::client::{Client, ClientAction};
+use mailin::response::{LINE_TOO_LONG, NO_SERVICE, TIMEOUT, TOO_MANY_CONNECTIONS};
+use mailin::{Action, Handler, Response, Session, TlsFailure};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::mem;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+const LISTENER: Token = Token(0);
+// How often to check for timeouts, delayed responses and shutdowns
+const TICK: Duration = Duration::from_millis(100);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const READ_SIZE: usize = 4096;
+
+// Run the sessions of a listener on the current thread until the server is shut down
+pub(crate) fn run<H>(
+    name: &str,
+    state: &ListenerState,
+    handler: H,
+    ssl: Option<SslImpl>,
+    shutdown: &ShutdownHandle,
+) where
+    H: Handler + Clone + Send,
+{
+    info!(
+        "{} SMTP ({}) started on {}",
+        name, state.label, state.local_addr
+    );
+    let mut engine = Engine {
+        state,
+        handler,
+        ssl,
+        shutdown,
+        connections: HashMap::new(),
+        pending: HashMap::new(),
+        next_token: LISTENER.0 + 1,
+    };
+    if let Err(err) = engine.run() {
+        error!("{} SMTP ({}) failed: {}", name, state.label, err);
+    }
+    info!("{} SMTP ({}) stopped", name, state.label);
+}
+
+struct Engine<'a, H: Handler> {
+    state: &'a ListenerState,
+    handler: H,
+    ssl: Option<SslImpl>,
+    shutdown: &'a ShutdownHandle,
+    connections: HashMap<Token, Connection<H>>,
+    // Connections from trusted proxies waiting for the PROXY protocol header
+    pending: HashMap<Token, Pending>,
+    next_token: usize,
+}
+
+// A connection from a trusted proxy that has not sent the whole PROXY protocol header
+struct Pending {
+    socket: TcpStream,
+    proxy: IpAddr,
+    header: HeaderReader,
+    guard: ConnectionGuard,
+    deadline: Instant,
+}
+
+impl<'a, H: Handler + Clone> Engine<'a, H> {
+    fn run(&mut self) -> Result<(), Error> {
+        let mut poll = Poll::new()?;
+        let std_listener = self.state.listener.try_clone()?;
+        std_listener.set_nonblocking(true)?;
+        let mut listener = TcpListener::from_std(std_listener);
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)?;
+        let mut events = Events::with_capacity(1024);
+        let mut accepting = true;
+        loop {
+            if let Err(err) = poll.poll(&mut events, Some(TICK)) {
+                if err.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err.into());
+            }
+            if accepting && self.shutdown.is_stopping() {
+                accepting = false;
+                poll.registry().deregister(&mut listener)?;
+                self.pending.clear();
+                info!("SMTP ({}) stopping", self.state.label);
+            }
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER if accepting => self.accept(&listener, &poll)?,
+                    LISTENER => (),
+                    token if self.pending.contains_key(&token) => self.read_header(token),
+                    token => {
+                        if let Some(conn) = self.connections.get_mut(&token) {
+                            conn.drive(self.state, self.ssl.as_ref());
+                            if conn.closed {
+                                self.connections.remove(&token);
+                            }
+                        }
+                    }
+                }
+            }
+            self.tick();
+            if !accepting && self.connections.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn accept(&mut self, listener: &TcpListener, poll: &Poll) -> Result<(), Error> {
+        loop {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    error!("Connection failed: {}", err);
+                    return Ok(());
+                }
+            };
+            let stream = std::net::TcpStream::from(stream);
+            let remote = peer_ip(&stream);
+            let permit = if self.state.is_trusted_proxy(remote) {
+                None
+            } else {
+                let Some(permit) = self.state.admission.admit(remote) else {
+                    reject(&stream, self.state);
+                    continue;
+                };
+                Some(permit)
+            };
+            let guard = self.shutdown.register(&stream);
+            let mut socket = TcpStream::from_std(stream);
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            poll.registry().register(
+                &mut socket,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            )?;
+            match permit {
+                Some(permit) => self.open(token, socket, remote, guard, permit),
+                None => {
+                    let pending = Pending {
+                        socket,
+                        proxy: remote,
+                        header: HeaderReader::default(),
+                        guard,
+                        deadline: Instant::now() + HEADER_TIMEOUT,
+                    };
+                    self.pending.insert(token, pending);
+                    self.read_header(token);
+                }
+            }
+        }
+    }
+
+    // Start the session of an admitted connection
+    fn open(
+        &mut self,
+        token: Token,
+        socket: TcpStream,
+        remote: IpAddr,
+        guard: ConnectionGuard,
+        permit: Permit,
+    ) {
+        debug!("New connection from {} on {}", remote, self.state.label);
+        let mut handler = self.handler.clone();
+        handler.listener(&self.state.label);
+        let session = self.state.session_builder.build(remote, handler);
+        let ssl = self.ssl.as_ref();
+        match Connection::new(session, socket, remote, guard, permit, self.state, ssl) {
+            Ok(mut conn) => {
+                conn.drive(self.state, self.ssl.as_ref());
+                if !conn.closed {
+                    self.connections.insert(token, conn);
+                }
+            }
+            Err(err) => debug!("({}) Cannot start session: {}", remote, err),
+        }
+    }
+
+    // Read the PROXY protocol header of a pending connection, the session starts once the
+    // header is complete and the client is admitted
+    fn read_header(&mut self, token: Token) {
+        let Some(pending) = self.pending.get_mut(&token) else {
+            return;
+        };
+        let remote = match pending.header.read(&mut pending.socket) {
+            Ok(source) => source.unwrap_or(pending.proxy),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+            Err(err) => {
+                debug!("({}) Invalid PROXY protocol header: {}", pending.proxy, err);
+                self.pending.remove(&token);
+                return;
+            }
+        };
+        let Some(mut pending) = self.pending.remove(&token) else {
+            return;
+        };
+        let Some(permit) = self.state.admission.admit(remote) else {
+            debug!("Too many connections, rejecting {}", remote);
+            // The reply is short enough to fit in the buffer of a new socket
+            let res = self.state.session_builder.response(TOO_MANY_CONNECTIONS);
+            let _ = res.write_to(&mut pending.socket);
+            return;
+        };
+        self.open(token, pending.socket, remote, pending.guard, permit);
+    }
+
+    // Handle timeouts, delayed responses and shutdowns
+    fn tick(&mut self) {
+        let now = Instant::now();
+        for conn in self.connections.values_mut() {
+            conn.tick(now, self.state, self.ssl.as_ref());
+        }
+        self.connections.retain(|_, conn| !conn.closed);
+        self.pending.retain(|_, pending| {
+            let waiting = now < pending.deadline;
+            if !waiting {
+                debug!("({}) Timeout reading PROXY protocol header", pending.proxy);
+            }
+            waiting
+        });
+    }
+}
+
+enum Socket {
+    Plain(TcpStream),
+    Tls(Box<dyn Stream>),
+    Upgrading,
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Plain(stream) => stream.read(buf),
+            Socket::Tls(stream) => stream.read(buf),
+            Socket::Upgrading => Err(ErrorKind::NotConnected.into()),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Plain(stream) => stream.write(buf),
+            Socket::Tls(stream) => stream.write(buf),
+            Socket::Upgrading => Err(ErrorKind::NotConnected.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Plain(stream) => stream.flush(),
+            Socket::Tls(stream) => stream.flush(),
+            Socket::Upgrading => Err(ErrorKind::NotConnected.into()),
+        }
+    }
+}
+
+// The outcome of reading from a client
+enum Input {
+    Data,
+    Blocked,
+    Eof,
+}
+
+struct Connection<H: Handler> {
+    session: Session<H>,
+    socket: Socket,
+    remote: IpAddr,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    // Has the output been written but not yet flushed?
+    unflushed: bool,
+    // Is the client not reading the output?
+    write_blocked: bool,
+    framer: LineFramer,
+    // Discard input up to the end of the current line
+    skipping: bool,
+    clock: PhaseClock,
+    // When to close the session if nothing happens
+    deadline: Option<Instant>,
+    // When to send the greeting, if it is delayed
+    greet_at: Option<Instant>,
+    // A delayed response and when to send it
+    delayed: Option<(Instant, Response)>,
+    upgrade_tls: bool,
+    // Delivers queued mail to the client after the roles were reversed with ATRN
+    client: Option<Client>,
+    // Has TLS started without the session being told?
+    tls_pending: bool,
+    closing: bool,
+    closed: bool,
+    guard: ConnectionGuard,
+    _permit: Permit,
+}
+
+impl<H: Handler> Connection<H> {
+    fn new(
+        session: Session<H>,
+        socket: TcpStream,
+        remote: IpAddr,
+        guard: ConnectionGuard,
+        permit: Permit,
+        state: &ListenerState,
+        ssl: Option<&SslImpl>,
+    ) -> Result<Self, Error> {
+        let socket = if state.implicit_tls {
+            let Some(ssl) = ssl else {
+                return Error::bail("Cannot start TLS without an SslAcceptor");
+            };
+            Socket::Tls(ssl.accept_nonblocking(socket)?)
+        } else {
+            Socket::Plain(socket)
+        };
+        let greet_at = match state.greeting_delay {
+            Some(delay) if !state.implicit_tls => Some(Instant::now() + delay),
+            _ => None,
+        };
+        let mut conn = Self {
+            session,
+            socket,
+            remote,
+            input: Vec::with_capacity(READ_SIZE),
+            output: Vec::with_capacity(512),
+            unflushed: false,
+            write_blocked: false,
+            framer: LineFramer::default(),
+            skipping: false,
+            clock: PhaseClock::new(&state.timeouts),
+            deadline: None,
+            greet_at,
+            delayed: None,
+            upgrade_tls: false,
+            client: None,
+            tls_pending: state.implicit_tls,
+            closing: false,
+            closed: false,
+            guard,
+            _permit: permit,
+        };
+        if conn.greet_at.is_none() {
+            let greeting = conn.session.greeting();
+            conn.queue(&greeting);
+        }
+        Ok(conn)
+    }
+
+    // Make as much progress as possible without blocking
+    fn drive(&mut self, state: &ListenerState, ssl: Option<&SslImpl>) {
+        if self.closed {
+            return;
+        }
+        if let Err(err) = self.try_drive(state, ssl) {
+            debug!("({}) Session ended: {}", self.remote, err);
+            self.closed = true;
+        }
+    }
+
+    fn try_drive(&mut self, state: &ListenerState, ssl: Option<&SslImpl>) -> Result<(), Error> {
+        loop {
+            if !self.write_output()? {
+                if !self.write_blocked {
+                    self.write_blocked = true;
+                    self.deadline = Some(Instant::now() + WRITE_TIMEOUT);
+                }
+                return Ok(());
+            }
+            self.write_blocked = false;
+            if self.closing {
+                self.closed = true;
+                return Ok(());
+            }
+            if self.upgrade_tls {
+                self.start_tls(ssl)?;
+                continue;
+            }
+            let now = Instant::now();
+            if let Some(greet_at) = self.greet_at {
+                if now < greet_at && self.input.is_empty() {
+                    match self.read_input(state.max_line_length)? {
+                        Input::Data => (),
+                        Input::Blocked => return Ok(()),
+                        Input::Eof => {
+                            self.closed = true;
+                            return Ok(());
+                        }
+                    }
+                }
+                self.greet(now >= greet_at);
+                continue;
+            }
+            if let Some((send_at, _)) = &self.delayed {
+                if now < *send_at {
+                    self.deadline = None;
+                    return Ok(());
+                }
+                if let Some((_, res)) = self.delayed.take() {
+                    self.send(&res);
+                }
+                continue;
+            }
+            if self.guard.should_close() {
+                if self.client.is_none() {
+                    self.close_with(NO_SERVICE);
+                    continue;
+                }
+                // Queued mail can be delivered until the grace period is over
+                if self.guard.is_past_deadline() {
+                    self.finish_delivery();
+                    continue;
+                }
+            }
+            if let Some((line, partial)) = self.next_line(state.max_line_length) {
+                self.process(line, partial, state);
+                continue;
+            }
+            match self.read_input(state.max_line_length)? {
+                Input::Data => (),
+                Input::Blocked => {
+                    let timeout = self.clock.next_timeout(self.session.phase());
+                    if timeout.is_zero() {
+                        self.time_out();
+                        continue;
+                    }
+                    // The rest of a long line gets one timeout however slowly it is sent
+                    if !self.skipping || self.deadline.is_none() {
+                        self.deadline = Some(now + timeout);
+                    }
+                    return Ok(());
+                }
+                Input::Eof if self.client.is_some() => self.finish_delivery(),
+                Input::Eof if self.guard.is_stopping() => self.close_with(NO_SERVICE),
+                Input::Eof => {
+                    self.closed = true;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Check the connection for expired timers
+    fn tick(&mut self, now: Instant, state: &ListenerState, ssl: Option<&SslImpl>) {
+        if self.closed {
+            return;
+        }
+        let expired = |at: Option<Instant>| at.map(|at| now >= at).unwrap_or_default();
+        if expired(self.deadline) {
+            self.deadline = None;
+            if self.write_blocked {
+                debug!("({}) Write timeout", self.remote);
+                self.handshake_failed(TlsFailure::Timeout);
+                self.closed = true;
+                return;
+            }
+            self.time_out();
+        }
+        if self.guard.should_close()
+            || expired(self.greet_at)
+            || expired(self.delayed.as_ref().map(|(at, _)| *at))
+            || self.closing
+        {
+            self.drive(state, ssl);
+        }
+    }
+
+    // Send the greeting, or reply to a client that talked before the greeting
+    fn greet(&mut self, on_time: bool) {
+        self.greet_at = None;
+        if !on_time {
+            debug!("({}) Early talker", self.remote);
+            let res = self.session.early_talker();
+            if res.action == Action::Close {
+                self.send(&res);
+                self.closing = true;
+                return;
+            }
+        }
+        let greeting = self.session.greeting();
+        self.queue(&greeting);
+    }
+
+    fn process(&mut self, mut line: Vec<u8>, partial: bool, state: &ListenerState) {
+        if self.client.is_some() {
+            self.deliver(&line, partial);
+            return;
+        }
+        if mem::take(&mut self.tls_pending) {
+            // The handshake is complete once a line has been read over TLS
+            if let Socket::Tls(stream) = &self.socket {
+                self.session.tls_started(&stream.tls_info());
+            }
+        }
+        let phase = self.session.phase();
+        match self
+            .framer
+            .frame(&mut line, partial, phase, state.long_lines)
+        {
+            Disposition::Process => (),
+            Disposition::ProcessAndSkip => self.skip_line(),
+            Disposition::TooLong => {
+                let res = self.session.response(LINE_TOO_LONG);
+                self.send(&res);
+                self.skip_line();
+                cancel_auth(&mut self.session);
+                return;
+            }
+            Disposition::Reject => {
+                self.close_with(LINE_TOO_LONG);
+                return;
+            }
+            Disposition::Ignore => return,
+        }
+        let res = self.session.process(&line);
+        self.guard
+            .set_receiving_message(self.session.is_receiving_message());
+        match res.action {
+            Action::Reply => self.queue(&res),
+            Action::Close => {
+                self.queue(&res);
+                self.closing = true;
+            }
+            Action::UpgradeTls => {
+                self.queue(&res);
+                self.upgrade_tls = true;
+            }
+            Action::ReverseRoles => {
+                self.queue(&res);
+                self.client = Some(self.session.reverse_roles());
+            }
+            Action::NoReply => (),
+        }
+    }
+
+    // Pass a reply to the client that delivers queued mail
+    fn deliver(&mut self, line: &[u8], partial: bool) {
+        let Some(client) = &mut self.client else {
+            return;
+        };
+        let action = if partial {
+            debug!("({}) Reply line too long", self.remote);
+            ClientAction::Close
+        } else {
+            client.process(line)
+        };
+        match action {
+            ClientAction::Send(buf) => self.output.extend_from_slice(&buf),
+            ClientAction::Wait => (),
+            ClientAction::UpgradeTls | ClientAction::Close => self.finish_delivery(),
+        }
+    }
+
+    // Report the outcome of delivering queued mail and close the connection
+    fn finish_delivery(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.session.reversed_outcomes(client.outcomes());
+        }
+        self.closing = true;
+    }
+
+    // Close a session that has run out of time
+    fn time_out(&mut self) {
+        self.handshake_failed(TlsFailure::Timeout);
+        if self.client.is_some() {
+            self.finish_delivery();
+        } else {
+            self.close_with(TIMEOUT);
+        }
+    }
+
+    // Queue a response, holding it back if it is delayed
+    fn queue(&mut self, res: &Response) {
+        match res.delay {
+            Some(delay) => self.delayed = Some((Instant::now() + delay, res.clone())),
+            None => self.send(res),
+        }
+    }
+
+    fn send(&mut self, res: &Response) {
+        // Writing to a Vec cannot fail
+        let _ = res.write_to(&mut self.output);
+    }
+
+    // Send a built-in response, or its replacement, and close the connection
+    fn close_with(&mut self, builtin: Response) {
+        let res = self.session.response(builtin);
+        self.send(&res);
+        self.closing = true;
+    }
+
+    // Discard input up to the end of the current line
+    fn skip_line(&mut self) {
+        self.skipping = true;
+        self.deadline = None;
+    }
+
+    // Take the next line from the input, returns the line and true if it is incomplete
+    fn next_line(&mut self, max: usize) -> Option<(Vec<u8>, bool)> {
+        if self.skipping {
+            match self.input.iter().position(|b| *b == b'\n') {
+                Some(i) => {
+                    self.input.drain(..=i);
+                    self.skipping = false;
+                }
+                None => {
+                    self.input.clear();
+                    return None;
+                }
+            }
+        }
+        let window = &self.input[..self.input.len().min(max)];
+        if let Some(i) = window.iter().position(|b| *b == b'\n') {
+            return Some((self.input.drain(..=i).collect(), false));
+        }
+        if self.input.len() >= max {
+            return Some((self.input.drain(..max).collect(), true));
+        }
+        None
+    }
+
+    // Read until the socket would block or enough data for a line has been read
+    fn read_input(&mut self, max: usize) -> io::Result<Input> {
+        let mut buf = [0u8; READ_SIZE];
+        loop {
+            if self.input.len() >= max {
+                return Ok(Input::Data);
+            }
+            match self.socket.read(&mut buf) {
+                Ok(0) => {
+                    self.handshake_failed(TlsFailure::ClientAbort);
+                    return Ok(Input::Eof);
+                }
+                Ok(num_bytes) => self.input.extend_from_slice(&buf[..num_bytes]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    return Ok(if self.input.is_empty() {
+                        Input::Blocked
+                    } else {
+                        Input::Data
+                    });
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => {
+                    self.handshake_failed(SslImpl::failure(&err));
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    // Write the queued output, returns false if the socket would block
+    fn write_output(&mut self) -> io::Result<bool> {
+        while !self.output.is_empty() {
+            match self.socket.write(&self.output) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(num_bytes) => {
+                    self.output.drain(..num_bytes);
+                    self.unflushed = true;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => {
+                    self.handshake_failed(SslImpl::failure(&err));
+                    return Err(err);
+                }
+            }
+        }
+        if self.unflushed {
+            match self.socket.flush() {
+                Ok(()) => self.unflushed = false,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(err) => {
+                    self.handshake_failed(SslImpl::failure(&err));
+                    return Err(err);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn start_tls(&mut self, ssl: Option<&SslImpl>) -> Result<(), Error> {
+        self.upgrade_tls = false;
+        // Commands pipelined after STARTTLS must not be processed (RFC 3207)
+        self.input.clear();
+        let Some(ssl) = ssl else {
+            return Error::bail("Cannot upgrade to TLS without an SslAcceptor");
+        };
+        match mem::replace(&mut self.socket, Socket::Upgrading) {
+            Socket::Plain(stream) => self.socket = Socket::Tls(ssl.accept_nonblocking(stream)?),
+            _ => return Error::bail("TLS is already active"),
+        }
+        self.tls_pending = true;
+        Ok(())
+    }
+
+    // Tell the session if the connection fails before the TLS handshake is complete
+    fn handshake_failed(&mut self, failure: TlsFailure) {
+        if let Socket::Tls(stream) = &self.socket {
+            if self.tls_pending && stream.is_handshaking() {
+                self.tls_pending = false;
+                self.session.tls_failed(failure);
+            }
+        }
+    }
+}
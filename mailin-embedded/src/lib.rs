@@ -37,14 +37,44 @@ cfg_if::cfg_if! {
     }
 }
 
+// serve_tokio uses tokio-rustls for STARTTLS
+#[cfg(all(feature = "tokio", feature = "ossl"))]
+compile_error!("the `tokio` and `ossl` features cannot be enabled together");
+
+#[cfg(feature = "mio")]
+mod events;
+mod limits;
+mod line;
+mod listener;
+mod proxy;
+mod reload;
 mod running;
+mod shutdown;
 mod ssl;
+mod timeouts;
+#[cfg(feature = "tokio")]
+mod tokio_server;
 
 use crate::err::Error;
+pub use crate::limits::{ConnectionLimits, RateLimit};
+pub use crate::line::LongLines;
+pub use crate::listener::{Listener, TlsMode};
+pub use crate::reload::TlsReloadHandle;
+pub use crate::shutdown::ShutdownHandle;
 pub use crate::ssl::SslConfig;
+pub use crate::timeouts::Timeouts;
 pub use mailin::response;
-pub use mailin::{Action, AuthMechanism, Handler, Response};
-use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+pub use mailin::{Action, AuthMechanism, Handler, Leniency, Response};
+#[cfg(feature = "ossl")]
+pub use openssl;
+#[cfg(not(feature = "ossl"))]
+pub use rustls;
+use std::net::{TcpListener, ToSocketAddrs};
+#[cfg(not(feature = "ossl"))]
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "tokio")]
+pub use tokio_util::sync::CancellationToken;
 
 /// `Server` is used to configure and start the SMTP server
 pub struct Server<H>
@@ -54,10 +84,12 @@ where
     handler: H,
     name: String,
     ssl: Option<SslImpl>,
-    num_threads: u32,
-    auth: Vec<AuthMechanism>,
-    tcp_listener: Option<TcpListener>,
-    socket_address: Vec<SocketAddr>,
+    listener: Listener,
+    listeners: Vec<Listener>,
+    limits: ConnectionLimits,
+    shutdown: ShutdownHandle,
+    #[cfg(feature = "tokio")]
+    shutdown_grace: Duration,
 }
 
 impl<H> Server<H>
@@ -70,10 +102,12 @@ where
             handler,
             name: "localhost".to_owned(),
             ssl: None,
-            num_threads: 4,
-            auth: Vec::with_capacity(4),
-            tcp_listener: None,
-            socket_address: Vec::with_capacity(4),
+            listener: Listener::new("smtp"),
+            listeners: Vec::new(),
+            limits: ConnectionLimits::default(),
+            shutdown: ShutdownHandle::default(),
+            #[cfg(feature = "tokio")]
+            shutdown_grace: Duration::from_secs(30),
         }
     }
 
@@ -92,22 +126,133 @@ where
         Ok(self)
     }
 
+    /// Use a rustls configuration for TLS instead of loading certificates with `with_ssl`.
+    ///
+    /// This gives full control over TLS, such as the protocol versions, cipher suites,
+    /// session tickets and where the certificates come from. The configuration cannot be
+    /// reloaded with a `TlsReloadHandle`.
+    #[cfg(not(feature = "ossl"))]
+    pub fn with_rustls_config(&mut self, config: Arc<rustls::ServerConfig>) -> &mut Self {
+        self.ssl = Some(SslImpl::from_config(config));
+        self
+    }
+
+    /// Use an openssl acceptor for TLS instead of loading certificates with `with_ssl`.
+    ///
+    /// This gives full control over TLS, such as the protocol versions, cipher suites,
+    /// session tickets and where the certificates come from. The acceptor cannot be
+    /// reloaded with a `TlsReloadHandle`.
+    #[cfg(feature = "ossl")]
+    pub fn with_ssl_acceptor(&mut self, acceptor: openssl::ssl::SslAcceptor) -> &mut Self {
+        self.ssl = Some(SslImpl::from_acceptor(acceptor));
+        self
+    }
+
+    /// Negotiate TLS as soon as a client connects, before the greeting is sent, instead of
+    /// offering STARTTLS. This is implicit TLS for submission on port 465 (RFC 8314).
+    ///
+    /// Authentication is allowed immediately and STARTTLS is not advertised. An SSL
+    /// configuration must be set with `with_ssl`. The greeting delay is not used because
+    /// clients start the TLS handshake without waiting for the server.
+    pub fn with_implicit_tls(&mut self) -> &mut Self {
+        self.listener.with_tls(TlsMode::Implicit);
+        self
+    }
+
     /// Set the size of the threadpool which is equal to the maximum number of
     /// concurrent SMTP sessions.
     pub fn with_num_threads(&mut self, num_threads: u32) -> &mut Self {
-        self.num_threads = num_threads;
+        self.listener.with_num_threads(num_threads);
         self
     }
 
     /// Add an authentication mechanism that will supported by the server
     pub fn with_auth(&mut self, auth: AuthMechanism) -> &mut Self {
-        self.auth.push(auth);
+        self.listener.with_auth(auth);
+        self
+    }
+
+    /// Wait for the given duration before sending the greeting to a new client.
+    ///
+    /// Clients that send data during this time are reported to `Handler::early_talker`,
+    /// which can reject the connection. Well behaved clients wait for the greeting.
+    pub fn with_greeting_delay(&mut self, delay: Duration) -> &mut Self {
+        self.listener.with_greeting_delay(delay);
+        self
+    }
+
+    /// Set how long to wait for clients in each phase of a session, see `Timeouts`
+    pub fn with_timeouts(&mut self, timeouts: Timeouts) -> &mut Self {
+        self.listener.with_timeouts(timeouts);
+        self
+    }
+
+    /// Set the maximum length of a line sent by a client, including the line ending.
+    ///
+    /// Commands that are too long are answered with `500 Line too long`, message lines are
+    /// handled as set by `with_long_lines`. The default is 12288 bytes, lengths below the
+    /// 1000 bytes allowed by RFC 5321 are raised to 1000.
+    pub fn with_max_line_length(&mut self, max_line_length: usize) -> &mut Self {
+        self.listener.with_max_line_length(max_line_length);
         self
     }
 
+    /// Set what happens to message lines longer than the maximum line length, the default is
+    /// `LongLines::Split`
+    pub fn with_long_lines(&mut self, long_lines: LongLines) -> &mut Self {
+        self.listener.with_long_lines(long_lines);
+        self
+    }
+
+    /// Set how strictly commands from clients are parsed, see `Leniency`
+    pub fn with_leniency(&mut self, leniency: Leniency) -> &mut Self {
+        self.listener.with_leniency(leniency);
+        self
+    }
+
+    /// Send an application defined response in place of a built-in response. This covers
+    /// the responses of sessions, such as `SYNTAX_ERROR`, and the responses the server sends
+    /// itself: `TIMEOUT`, `LINE_TOO_LONG`, `TOO_MANY_CONNECTIONS` and `NO_SERVICE`.
+    /// ```
+    /// # use mailin_embedded::{Handler, Response, Server};
+    /// # use mailin_embedded::response::TIMEOUT;
+    /// # #[derive(Clone)]
+    /// # struct MyHandler {}
+    /// # impl Handler for MyHandler{}
+    /// # let mut server = Server::new(MyHandler {});
+    /// let timeout = Response::builder(421)
+    ///     .enhanced_code(4, 4, 2)
+    ///     .line("Timeout exceeded, see https://example.com/smtp")
+    ///     .build();
+    /// server.with_response(TIMEOUT, timeout);
+    /// ```
+    pub fn with_response(&mut self, builtin: Response, replacement: Response) -> &mut Self {
+        self.listener.with_response(builtin, replacement);
+        self
+    }
+
+    /// Accept the PROXY protocol from load balancers in the given network, such as
+    /// `"10.0.0.0/8"`, see `Listener::with_proxy_protocol`
+    /// ```
+    /// # use mailin_embedded::{Server, Handler};
+    /// # use mailin_embedded::err::Error;
+    /// # #[derive(Clone)]
+    /// # struct EmptyHandler {}
+    /// # impl Handler for EmptyHandler {}
+    /// # let mut server = Server::new(EmptyHandler {});
+    /// server
+    ///     .with_addr("0.0.0.0:25")?
+    ///     .with_proxy_protocol("10.0.0.0/8")?;
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn with_proxy_protocol(&mut self, network: &str) -> Result<&mut Self, Error> {
+        self.listener.with_proxy_protocol(network)?;
+        Ok(self)
+    }
+
     /// Set a tcp listener from an already open socket
     pub fn with_tcp_listener(&mut self, listener: TcpListener) -> &mut Self {
-        self.tcp_listener = Some(listener);
+        self.listener.with_tcp_listener(listener);
         self
     }
 
@@ -124,17 +269,116 @@ where
     /// # Ok::<(), Error>(())
     /// ```
     pub fn with_addr<A: ToSocketAddrs>(&mut self, addr: A) -> Result<&mut Self, Error> {
-        for addr in addr
-            .to_socket_addrs()
-            .map_err(|e| Error::with_source("Invalid socket address", e))?
-        {
-            self.socket_address.push(addr);
-        }
+        self.listener.with_addr(addr)?;
         Ok(self)
     }
 
-    /// Start the SMTP server and run forever
+    /// Add a listener with its own addresses and session settings.
+    ///
+    /// The settings of the server, such as `with_auth` and `with_addr`, apply to a default
+    /// listener called `smtp`. The default listener is only started if it has an address
+    /// or if no other listeners were added. The name and SSL configuration of the server are
+    /// shared by all listeners.
+    /// ```
+    /// # use mailin_embedded::{AuthMechanism, Handler, Listener, Server, TlsMode};
+    /// # use mailin_embedded::err::Error;
+    /// # #[derive(Clone)]
+    /// # struct EmptyHandler {}
+    /// # impl Handler for EmptyHandler {}
+    /// # let mut server = Server::new(EmptyHandler {});
+    /// let mut submission = Listener::new("submission");
+    /// submission
+    ///     .with_auth(AuthMechanism::Plain)
+    ///     .with_addr("127.0.0.1:587")?;
+    /// server.with_addr("127.0.0.1:25")?.with_listener(submission);
+    /// # Ok::<(), Error>(())
+    /// ```
+    pub fn with_listener(&mut self, listener: Listener) -> &mut Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    /// Limit the number of connections accepted by the server, see `ConnectionLimits`.
+    ///
+    /// The limits count the connections to all listeners together, use
+    /// `Listener::with_connection_limits` to limit a single listener. Connections are also
+    /// refused when all the threads of a listener are busy.
+    pub fn with_connection_limits(&mut self, limits: ConnectionLimits) -> &mut Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Get a handle that stops the server when `ShutdownHandle::shutdown` is called
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Set how long sessions that are receiving a message can continue after the
+    /// cancellation token of `serve_tokio` is cancelled, the default is 30 seconds
+    #[cfg(feature = "tokio")]
+    pub fn with_shutdown_grace(&mut self, grace: Duration) -> &mut Self {
+        self.shutdown_grace = grace;
+        self
+    }
+
+    /// Get a handle that reloads the TLS certificates while the server runs. Returns None
+    /// if the SSL configuration does not support TLS.
+    ///
+    /// The handle reloads the SSL configuration that is set when this is called.
+    pub fn tls_reload_handle(&self) -> Option<TlsReloadHandle> {
+        self.ssl
+            .as_ref()
+            .map(|ssl| TlsReloadHandle::new(ssl.reloader()))
+    }
+
+    /// Start the SMTP server and run until it is stopped with a `ShutdownHandle`
     pub fn serve(self) -> Result<(), Error> {
         running::serve(self)
     }
+
+    /// Start the SMTP server with an event loop and run until it is stopped with a
+    /// `ShutdownHandle`.
+    ///
+    /// Each listener runs all of its sessions on a single thread using non-blocking sockets,
+    /// which allows many more concurrent sessions than `serve`. The number of threads set
+    /// with `with_num_threads` is ignored, use `with_connection_limits` to limit the number of
+    /// sessions. Handlers should avoid blocking as this holds up every session on the
+    /// listener.
+    #[cfg(feature = "mio")]
+    pub fn serve_events(self) -> Result<(), Error> {
+        running::serve_with(self, events::run)
+    }
+
+    /// Start the SMTP server on the current tokio runtime and run until the cancellation
+    /// token is cancelled or the server is stopped with a `ShutdownHandle`.
+    ///
+    /// Each session runs as a tokio task and STARTTLS uses tokio-rustls, so the `tokio`
+    /// feature cannot be enabled with `ossl`. Cancelling the token is the same as calling
+    /// `ShutdownHandle::shutdown` with the grace period set by `with_shutdown_grace`, the
+    /// returned future completes when all sessions have closed. The number of threads set
+    /// with `with_num_threads` is ignored, use `with_connection_limits` to limit the number
+    /// of sessions. Handlers are called from async tasks and should avoid blocking.
+    /// ```no_run
+    /// # use mailin_embedded::{CancellationToken, Handler, Server};
+    /// # #[derive(Clone)]
+    /// # struct EmptyHandler {}
+    /// # impl Handler for EmptyHandler {}
+    /// # async fn example() -> Result<(), mailin_embedded::err::Error> {
+    /// let mut server = Server::new(EmptyHandler {});
+    /// server.with_addr("127.0.0.1:25")?;
+    /// let cancel = CancellationToken::new();
+    /// let running = tokio::spawn(server.serve_tokio(cancel.clone()));
+    /// // Later
+    /// cancel.cancel();
+    /// running.await.unwrap()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "tokio")]
+    pub async fn serve_tokio(self, cancel: CancellationToken) -> Result<(), Error>
+    where
+        H: 'static,
+    {
+        tokio_server::serve(self, cancel).await
+    }
 }
@@ -0,0 +1,304 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Forget connection rates that are older than the rate interval once this many clients
+// are tracked
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// `ConnectionLimits` sets how many connections the server accepts.
+///
+/// Clients that are over a limit are sent `421 Too many connections` as soon as they
+/// connect. Limits set with `Server::with_connection_limits` count the connections to all
+/// listeners of a server together, limits set with `Listener::with_connection_limits` count
+/// the connections to one listener. By default there are none.
+/// ```
+/// # use mailin_embedded::{ConnectionLimits, RateLimit};
+/// # use std::time::Duration;
+/// let limits = ConnectionLimits {
+///     max_connections: Some(100),
+///     max_per_ip: Some(4),
+///     rate: Some(RateLimit {
+///         connections: 10,
+///         interval: Duration::from_secs(60),
+///     }),
+///     ..ConnectionLimits::default()
+/// };
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// The maximum number of concurrent connections to the server
+    pub max_connections: Option<usize>,
+    /// The maximum number of concurrent connections from a client ip address
+    pub max_per_ip: Option<usize>,
+    /// The maximum number of concurrent connections from a network, which is a /24 for
+    /// IPv4 and a /64 for IPv6
+    pub max_per_network: Option<usize>,
+    /// The maximum rate at which a client ip address can connect
+    pub rate: Option<RateLimit>,
+}
+
+/// `RateLimit` sets the number of connections a client can make in an interval
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    /// The number of connections allowed in each interval
+    pub connections: u32,
+    /// The length of the interval
+    pub interval: Duration,
+}
+
+// Counts the connections to a server or a listener and admits those within the limits
+pub(crate) struct Admission {
+    limits: ConnectionLimits,
+    counts: Mutex<Counts>,
+    // The limits of the server, for the admission of a listener
+    parent: Option<Arc<Admission>>,
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    per_network: HashMap<IpAddr, usize>,
+    recent: HashMap<IpAddr, Window>,
+}
+
+// The connections made by a client since the start of the current interval
+struct Window {
+    start: Instant,
+    connections: u32,
+}
+
+impl Admission {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            limits,
+            counts: Mutex::default(),
+            parent: None,
+        }
+    }
+
+    // Admit connections that are within the given limits and the limits of the parent
+    pub fn within(limits: ConnectionLimits, parent: Arc<Admission>) -> Self {
+        Self {
+            parent: Some(parent),
+            ..Self::new(limits)
+        }
+    }
+
+    // Admit a connection from the given address, the connection is counted until the
+    // permit is dropped. Returns None if the connection is over a limit.
+    pub fn admit(self: &Arc<Self>, remote: IpAddr) -> Option<Permit> {
+        let parent = match &self.parent {
+            Some(parent) => Some(Box::new(parent.admit(remote)?)),
+            None => None,
+        };
+        let ip = remote.to_canonical();
+        let network = network(ip);
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(rate) = &self.limits.rate {
+            if !counts.within_rate(ip, rate) {
+                return None;
+            }
+        }
+        let per_ip = counts.per_ip.get(&ip).copied().unwrap_or_default();
+        let per_network = counts
+            .per_network
+            .get(&network)
+            .copied()
+            .unwrap_or_default();
+        if is_over(counts.total, self.limits.max_connections)
+            || is_over(per_ip, self.limits.max_per_ip)
+            || is_over(per_network, self.limits.max_per_network)
+        {
+            return None;
+        }
+        counts.total += 1;
+        *counts.per_ip.entry(ip).or_default() += 1;
+        *counts.per_network.entry(network).or_default() += 1;
+        Some(Permit {
+            admission: self.clone(),
+            ip,
+            network,
+            _parent: parent,
+        })
+    }
+}
+
+impl Counts {
+    // Count a connection and return false if the client connects too often
+    fn within_rate(&mut self, ip: IpAddr, rate: &RateLimit) -> bool {
+        let now = Instant::now();
+        if self.recent.len() >= PRUNE_THRESHOLD {
+            self.recent
+                .retain(|_, window| now.duration_since(window.start) < rate.interval);
+        }
+        let window = self.recent.entry(ip).or_insert(Window {
+            start: now,
+            connections: 0,
+        });
+        if now.duration_since(window.start) >= rate.interval {
+            window.start = now;
+            window.connections = 0;
+        }
+        window.connections = window.connections.saturating_add(1);
+        window.connections <= rate.connections
+    }
+}
+
+// An admitted connection
+pub(crate) struct Permit {
+    admission: Arc<Admission>,
+    ip: IpAddr,
+    network: IpAddr,
+    // Released after this permit
+    _parent: Option<Box<Permit>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut counts = self.admission.counts.lock().unwrap();
+        counts.total -= 1;
+        release(&mut counts.per_ip, self.ip);
+        release(&mut counts.per_network, self.network);
+    }
+}
+
+fn is_over(count: usize, limit: Option<usize>) -> bool {
+    limit.map(|limit| count >= limit).unwrap_or_default()
+}
+
+fn release(counts: &mut HashMap<IpAddr, usize>, key: IpAddr) {
+    if let Some(count) = counts.get_mut(&key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&key);
+        }
+    }
+}
+
+// The network of an address, a /24 for IPv4 and a /64 for IPv6
+fn network(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4((u32::from(ip) & 0xffff_ff00).into()),
+        IpAddr::V6(ip) => IpAddr::V6((u128::from(ip) & (u128::MAX << 64)).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, last))
+    }
+
+    fn admission(limits: ConnectionLimits) -> Arc<Admission> {
+        Arc::new(Admission::new(limits))
+    }
+
+    #[test]
+    fn unlimited() {
+        let admission = admission(ConnectionLimits::default());
+        let permits: Vec<_> = (0..100).map(|_| admission.admit(ip(1))).collect();
+        assert!(permits.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn max_connections() {
+        let admission = admission(ConnectionLimits {
+            max_connections: Some(2),
+            ..ConnectionLimits::default()
+        });
+        let first = admission.admit(ip(1));
+        let _second = admission.admit(ip(2));
+        assert!(first.is_some());
+        assert!(admission.admit(ip(3)).is_none());
+        drop(first);
+        assert!(admission.admit(ip(3)).is_some());
+    }
+
+    #[test]
+    fn max_per_ip() {
+        let admission = admission(ConnectionLimits {
+            max_per_ip: Some(1),
+            ..ConnectionLimits::default()
+        });
+        let _first = admission.admit(ip(1)).unwrap();
+        assert!(admission.admit(ip(1)).is_none());
+        assert!(admission.admit(ip(2)).is_some());
+        // IPv4 clients connecting over IPv6 are the same client
+        let mapped = IpAddr::V6(Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped());
+        assert!(admission.admit(mapped).is_none());
+    }
+
+    #[test]
+    fn max_per_network() {
+        let admission = admission(ConnectionLimits {
+            max_per_network: Some(2),
+            ..ConnectionLimits::default()
+        });
+        let _first = admission.admit(ip(1)).unwrap();
+        let _second = admission.admit(ip(2)).unwrap();
+        assert!(admission.admit(ip(3)).is_none());
+        assert!(admission
+            .admit(IpAddr::V4(Ipv4Addr::new(192, 0, 3, 1)))
+            .is_some());
+        let v6 = |last| IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0, 0, 0, last));
+        let _first = admission.admit(v6(1)).unwrap();
+        let _second = admission.admit(v6(2)).unwrap();
+        assert!(admission.admit(v6(3)).is_none());
+    }
+
+    #[test]
+    fn rate() {
+        let admission = admission(ConnectionLimits {
+            rate: Some(RateLimit {
+                connections: 2,
+                interval: Duration::from_millis(100),
+            }),
+            ..ConnectionLimits::default()
+        });
+        // Connections count towards the rate after they close
+        assert!(admission.admit(ip(1)).is_some());
+        assert!(admission.admit(ip(1)).is_some());
+        assert!(admission.admit(ip(1)).is_none());
+        assert!(admission.admit(ip(2)).is_some());
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(admission.admit(ip(1)).is_some());
+    }
+
+    #[test]
+    fn within_parent() {
+        let parent = admission(ConnectionLimits {
+            max_connections: Some(2),
+            ..ConnectionLimits::default()
+        });
+        let limits = ConnectionLimits {
+            max_per_ip: Some(1),
+            ..ConnectionLimits::default()
+        };
+        let first = Arc::new(Admission::within(limits.clone(), parent.clone()));
+        let second = Arc::new(Admission::within(limits, parent.clone()));
+        let permit = first.admit(ip(1)).unwrap();
+        assert!(first.admit(ip(1)).is_none());
+        // The limits of a listener do not apply to other listeners
+        let _other = second.admit(ip(1)).unwrap();
+        // The limits of the server apply to all listeners
+        assert!(first.admit(ip(2)).is_none());
+        assert!(parent.admit(ip(2)).is_none());
+        drop(permit);
+        assert!(first.admit(ip(1)).is_some());
+    }
+
+    #[test]
+    fn networks() {
+        let v4 = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 77));
+        assert_eq!(network(v4), IpAddr::V4(Ipv4Addr::new(198, 51, 100, 0)));
+        let v6 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 3, 4, 5, 6));
+        let expected = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 1, 2, 0, 0, 0, 0));
+        assert_eq!(network(v6), expected);
+    }
+}
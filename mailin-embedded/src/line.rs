@@ -0,0 +1,342 @@
+use mailin::{Handler, Phase, Session};
+use std::io::{self, BufRead, ErrorKind};
+use std::time::{Duration, Instant};
+
+/// The default maximum line length, which is large enough for the longest authentication
+/// line accepted by mailin
+pub(crate) const DEFAULT_MAX_LINE_LENGTH: usize = 12288;
+
+/// The smallest maximum line length, the length of a text line in RFC 5321 section 4.5.3.1.6
+/// which is longer than the longest command line
+pub(crate) const MIN_MAX_LINE_LENGTH: usize = 1000;
+
+/// `LongLines` sets what happens to message lines that are longer than the maximum line
+/// length of a listener.
+///
+/// Command lines that are too long are always answered with `500 Line too long`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LongLines {
+    /// Split long lines into several lines of at most the maximum length
+    Split,
+    /// Keep the start of long lines and discard the remainder
+    Truncate,
+    /// Reply `500 Line too long` and close the connection
+    Reject,
+}
+
+// The outcome of reading a line
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ReadLine {
+    // The end of the input was reached without reading any data
+    Eof,
+    // A line, including the line ending, or the data before the end of input
+    Complete,
+    // The maximum length was read without reaching the end of the line
+    Partial,
+}
+
+// How a line read from a client is handled
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Disposition {
+    // Pass the line to the session
+    Process,
+    // Pass the line to the session and discard the rest of the line sent by the client
+    ProcessAndSkip,
+    // Reply `500 Line too long` and discard the rest of the line sent by the client
+    TooLong,
+    // Reply `500 Line too long` and close the connection
+    Reject,
+    // Ignore the line, it is the line ending of a message line that was split
+    Ignore,
+}
+
+// Prepares the lines read from a client for a session, splitting or truncating message
+// lines that are longer than the maximum line length
+#[derive(Default)]
+pub(crate) struct LineFramer {
+    // Is the next line the remainder of a message line that was split?
+    continued: bool,
+    // Was a carriage return held back from the end of a split line?
+    held_cr: bool,
+}
+
+impl LineFramer {
+    // Prepare a line that was read, partial is true if the line was not complete
+    pub fn frame(
+        &mut self,
+        line: &mut Vec<u8>,
+        partial: bool,
+        phase: Phase,
+        long_lines: LongLines,
+    ) -> Disposition {
+        let continued = std::mem::take(&mut self.continued);
+        if std::mem::take(&mut self.held_cr) {
+            if line.as_slice() == b"\n" {
+                // The split fell between CR and LF, the line ending has already been added
+                return Disposition::Ignore;
+            }
+            line.insert(0, b'\r');
+        }
+        let disposition = if !partial {
+            Disposition::Process
+        } else if phase != Phase::Data {
+            return Disposition::TooLong;
+        } else {
+            // A line cut between CR and LF must not leave a bare CR or LF in the message
+            let held_cr = line.last() == Some(&b'\r');
+            match long_lines {
+                LongLines::Split => {
+                    if held_cr {
+                        line.pop();
+                        self.held_cr = true;
+                    }
+                    line.extend_from_slice(b"\r\n");
+                    self.continued = true;
+                    Disposition::Process
+                }
+                LongLines::Truncate => {
+                    if held_cr {
+                        line.pop();
+                    }
+                    line.extend_from_slice(b"\r\n");
+                    Disposition::ProcessAndSkip
+                }
+                LongLines::Reject => return Disposition::Reject,
+            }
+        };
+        if continued && line.starts_with(b".") {
+            // Dot stuff the new line so that it cannot end the message
+            line.insert(0, b'.');
+        }
+        disposition
+    }
+}
+
+// Leave an authentication exchange that was sent a line that is too long
+pub(crate) fn cancel_auth<H: Handler>(session: &mut Session<H>) {
+    if session.phase() == Phase::Auth {
+        session.process(b"*\r\n");
+    }
+}
+
+// Read a line into the given buffer, reading no more than max bytes
+pub(crate) fn read_line<R>(reader: &mut R, buf: &mut Vec<u8>, max: usize) -> io::Result<ReadLine>
+where
+    R: BufRead + ?Sized,
+{
+    loop {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            return Ok(if buf.is_empty() {
+                ReadLine::Eof
+            } else {
+                ReadLine::Complete
+            });
+        }
+        let wanted = available.len().min(max - buf.len());
+        let (used, done) = match available[..wanted].iter().position(|b| *b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (wanted, false),
+        };
+        buf.extend_from_slice(&available[..used]);
+        reader.consume(used);
+        if done {
+            return Ok(ReadLine::Complete);
+        }
+        if buf.len() >= max {
+            return Ok(ReadLine::Partial);
+        }
+    }
+}
+
+// Discard input up to and including the end of the current line. The read timeout of a
+// socket limits each read, so a client that sends a little at a time is stopped once the
+// line has taken longer than the given wait.
+pub(crate) fn skip_line<R>(reader: &mut R, wait: Duration) -> io::Result<()>
+where
+    R: BufRead + ?Sized,
+{
+    let deadline = Instant::now() + wait;
+    loop {
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "Timeout discarding a long line",
+            ));
+        }
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            return Ok(());
+        }
+        match available.iter().position(|b| *b == b'\n') {
+            Some(i) => {
+                reader.consume(i + 1);
+                return Ok(());
+            }
+            None => {
+                let len = available.len();
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+
+    // A reader that returns a few bytes at a time, as a socket might
+    fn reader(input: &[u8]) -> BufReader<&[u8]> {
+        BufReader::with_capacity(4, input)
+    }
+
+    #[test]
+    fn read_lines() {
+        let mut input = reader(b"HELO a.domain\r\nNOOP\r\nQUIT");
+        let mut buf = Vec::new();
+        assert_eq!(
+            read_line(&mut input, &mut buf, 100).unwrap(),
+            ReadLine::Complete
+        );
+        assert_eq!(buf, b"HELO a.domain\r\n");
+        buf.clear();
+        assert_eq!(
+            read_line(&mut input, &mut buf, 100).unwrap(),
+            ReadLine::Complete
+        );
+        assert_eq!(buf, b"NOOP\r\n");
+        buf.clear();
+        // Data without a line ending before the end of input
+        assert_eq!(
+            read_line(&mut input, &mut buf, 100).unwrap(),
+            ReadLine::Complete
+        );
+        assert_eq!(buf, b"QUIT");
+        buf.clear();
+        assert_eq!(read_line(&mut input, &mut buf, 100).unwrap(), ReadLine::Eof);
+    }
+
+    #[test]
+    fn read_long_line() {
+        let mut input = reader(b"0123456789\r\nNOOP\r\n");
+        let mut buf = Vec::new();
+        assert_eq!(
+            read_line(&mut input, &mut buf, 6).unwrap(),
+            ReadLine::Partial
+        );
+        assert_eq!(buf, b"012345");
+        buf.clear();
+        assert_eq!(
+            read_line(&mut input, &mut buf, 6).unwrap(),
+            ReadLine::Complete
+        );
+        assert_eq!(buf, b"6789\r\n");
+    }
+
+    #[test]
+    fn skip_to_end_of_line() {
+        let mut input = reader(b"0123456789\r\nNOOP\r\n");
+        skip_line(&mut input, Duration::from_secs(1)).unwrap();
+        let mut buf = Vec::new();
+        assert_eq!(
+            read_line(&mut input, &mut buf, 100).unwrap(),
+            ReadLine::Complete
+        );
+        assert_eq!(buf, b"NOOP\r\n");
+        skip_line(&mut input, Duration::from_secs(1)).unwrap();
+    }
+
+    // Sends a byte at a time, slowly
+    struct Trickle;
+
+    impl io::Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            std::thread::sleep(Duration::from_millis(10));
+            buf[0] = b'a';
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn skip_slow_line() {
+        let mut input = BufReader::new(Trickle);
+        let err = skip_line(&mut input, Duration::from_millis(100)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn frame_commands() {
+        let mut framer = LineFramer::default();
+        let mut line = b"NOOP\r\n".to_vec();
+        let disposition = framer.frame(&mut line, false, Phase::Mail, LongLines::Split);
+        assert_eq!(disposition, Disposition::Process);
+        let mut line = b"NOOP NOOP NOOP".to_vec();
+        let disposition = framer.frame(&mut line, true, Phase::Mail, LongLines::Split);
+        assert_eq!(disposition, Disposition::TooLong);
+    }
+
+    #[test]
+    fn frame_split() {
+        let mut framer = LineFramer::default();
+        let mut line = b"0123456789".to_vec();
+        let disposition = framer.frame(&mut line, true, Phase::Data, LongLines::Split);
+        assert_eq!(disposition, Disposition::Process);
+        assert_eq!(line, b"0123456789\r\n");
+        // The remainder cannot end the message
+        let mut line = b".\r\n".to_vec();
+        let disposition = framer.frame(&mut line, false, Phase::Data, LongLines::Split);
+        assert_eq!(disposition, Disposition::Process);
+        assert_eq!(line, b"..\r\n");
+        // Lines after the remainder are left alone
+        let mut line = b".\r\n".to_vec();
+        framer.frame(&mut line, false, Phase::Data, LongLines::Split);
+        assert_eq!(line, b".\r\n");
+    }
+
+    #[test]
+    fn frame_truncate_and_reject() {
+        let mut framer = LineFramer::default();
+        let mut line = b"0123456789".to_vec();
+        let disposition = framer.frame(&mut line, true, Phase::Data, LongLines::Truncate);
+        assert_eq!(disposition, Disposition::ProcessAndSkip);
+        assert_eq!(line, b"0123456789\r\n");
+        let mut line = b"0123456789".to_vec();
+        let disposition = framer.frame(&mut line, true, Phase::Data, LongLines::Reject);
+        assert_eq!(disposition, Disposition::Reject);
+    }
+
+    #[test]
+    fn frame_split_line_ending() {
+        // A line of max - 1 bytes followed by CRLF is cut between CR and LF
+        let mut input = reader(b"01234\r\nNOOP\r\n");
+        let mut framer = LineFramer::default();
+        let mut line = Vec::new();
+        assert_eq!(
+            read_line(&mut input, &mut line, 6).unwrap(),
+            ReadLine::Partial
+        );
+        let disposition = framer.frame(&mut line, true, Phase::Data, LongLines::Split);
+        assert_eq!(disposition, Disposition::Process);
+        assert_eq!(line, b"01234\r\n");
+        line.clear();
+        assert_eq!(
+            read_line(&mut input, &mut line, 6).unwrap(),
+            ReadLine::Complete
+        );
+        let disposition = framer.frame(&mut line, false, Phase::Data, LongLines::Split);
+        assert_eq!(disposition, Disposition::Ignore);
+        // A bare CR at the cut is kept
+        let mut line = b"01234\r".to_vec();
+        framer.frame(&mut line, true, Phase::Data, LongLines::Split);
+        assert_eq!(line, b"01234\r\n");
+        let mut line = b"x\r\n".to_vec();
+        framer.frame(&mut line, false, Phase::Data, LongLines::Split);
+        assert_eq!(line, b"\rx\r\n");
+        // Truncated lines end with a single CRLF
+        let mut line = b"01234\r".to_vec();
+        let disposition = framer.frame(&mut line, true, Phase::Data, LongLines::Truncate);
+        assert_eq!(disposition, Disposition::ProcessAndSkip);
+        assert_eq!(line, b"01234\r\n");
+    }
+}
@@ -0,0 +1,186 @@
+use crate::err::Error;
+use crate::limits::ConnectionLimits;
+use crate::line::{LongLines, DEFAULT_MAX_LINE_LENGTH, MIN_MAX_LINE_LENGTH};
+use crate::proxy::Network;
+use crate::timeouts::Timeouts;
+use mailin::{AuthMechanism, Leniency, Response};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::time::Duration;
+
+/// `TlsMode` sets how a listener uses TLS
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsMode {
+    /// Do not offer TLS
+    None,
+    /// Offer STARTTLS when the server has an SSL configuration
+    StartTls,
+    /// Negotiate TLS as soon as a client connects, before the greeting is sent (RFC 8314)
+    Implicit,
+}
+
+/// `Listener` configures a set of addresses with their own session settings.
+///
+/// Listeners are added to a server with `Server::with_listener`, which allows one server to
+/// accept mail on port 25 and submissions on ports 587 and 465. The name of the listener a
+/// connection arrived on is passed to `Handler::listener`.
+/// ```
+/// # use mailin_embedded::{AuthMechanism, Listener, TlsMode};
+/// # use mailin_embedded::err::Error;
+/// let mut submission = Listener::new("submission");
+/// submission
+///     .with_tls(TlsMode::Implicit)
+///     .with_auth(AuthMechanism::Plain)
+///     .with_addr("127.0.0.1:465")?;
+/// # Ok::<(), Error>(())
+/// ```
+pub struct Listener {
+    pub(crate) label: String,
+    pub(crate) name: Option<String>,
+    pub(crate) tls: TlsMode,
+    pub(crate) auth: Vec<AuthMechanism>,
+    pub(crate) num_threads: u32,
+    pub(crate) greeting_delay: Option<Duration>,
+    pub(crate) timeouts: Timeouts,
+    pub(crate) max_line_length: usize,
+    pub(crate) long_lines: LongLines,
+    pub(crate) leniency: Leniency,
+    pub(crate) replacements: Vec<(Response, Response)>,
+    pub(crate) trusted_proxies: Vec<Network>,
+    pub(crate) limits: Option<ConnectionLimits>,
+    pub(crate) tcp_listener: Option<TcpListener>,
+    pub(crate) socket_address: Vec<SocketAddr>,
+}
+
+impl Listener {
+    /// Create a listener, the label is passed to `Handler::listener` for each connection
+    pub fn new<T: Into<String>>(label: T) -> Self {
+        Self {
+            label: label.into(),
+            name: None,
+            tls: TlsMode::StartTls,
+            auth: Vec::with_capacity(4),
+            num_threads: 4,
+            greeting_delay: None,
+            timeouts: Timeouts::default(),
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            long_lines: LongLines::Split,
+            leniency: Leniency::Strict,
+            replacements: Vec::new(),
+            trusted_proxies: Vec::new(),
+            limits: None,
+            tcp_listener: None,
+            socket_address: Vec::with_capacity(4),
+        }
+    }
+
+    /// Give the server a different name on this listener, the default is the name of the
+    /// server
+    pub fn with_name<T: Into<String>>(&mut self, name: T) -> &mut Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Set how the listener uses TLS, the default is `TlsMode::StartTls`
+    pub fn with_tls(&mut self, tls: TlsMode) -> &mut Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Add an authentication mechanism that will be supported on this listener
+    pub fn with_auth(&mut self, auth: AuthMechanism) -> &mut Self {
+        self.auth.push(auth);
+        self
+    }
+
+    /// Set the size of the threadpool of this listener, which is equal to the maximum
+    /// number of concurrent SMTP sessions on the listener.
+    pub fn with_num_threads(&mut self, num_threads: u32) -> &mut Self {
+        self.num_threads = num_threads;
+        self
+    }
+
+    /// Wait for the given duration before sending the greeting to a new client, see
+    /// `Server::with_greeting_delay`
+    pub fn with_greeting_delay(&mut self, delay: Duration) -> &mut Self {
+        self.greeting_delay = Some(delay);
+        self
+    }
+
+    /// Set how long to wait for clients in each phase of a session, see `Timeouts`
+    pub fn with_timeouts(&mut self, timeouts: Timeouts) -> &mut Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Set the maximum length of a line sent by a client, including the line ending.
+    /// The default is 12288 bytes, lengths below the 1000 bytes allowed by RFC 5321 are
+    /// raised to 1000.
+    pub fn with_max_line_length(&mut self, max_line_length: usize) -> &mut Self {
+        self.max_line_length = max_line_length.max(MIN_MAX_LINE_LENGTH);
+        self
+    }
+
+    /// Set what happens to message lines longer than the maximum line length, the default is
+    /// `LongLines::Split`
+    pub fn with_long_lines(&mut self, long_lines: LongLines) -> &mut Self {
+        self.long_lines = long_lines;
+        self
+    }
+
+    /// Set how strictly commands from clients are parsed, see `Leniency`
+    pub fn with_leniency(&mut self, leniency: Leniency) -> &mut Self {
+        self.leniency = leniency;
+        self
+    }
+
+    /// Send an application defined response in place of a built-in response, such as
+    /// `SYNTAX_ERROR` or `TIMEOUT`, see `Server::with_response`
+    pub fn with_response(&mut self, builtin: Response, replacement: Response) -> &mut Self {
+        self.replacements.push((builtin, replacement));
+        self
+    }
+
+    /// Accept the PROXY protocol from load balancers in the given network, such as
+    /// `"10.0.0.0/8"` or `"192.0.2.1"`. Can be called more than once to trust several networks.
+    ///
+    /// Connections from a trusted network must start with a PROXY protocol version 1 or 2
+    /// header, the client address in the header is given to the session and used for the
+    /// connection limits. Connections from other addresses are handled as direct connections
+    /// from clients. Returns an error if the network is not valid.
+    pub fn with_proxy_protocol(&mut self, network: &str) -> Result<&mut Self, Error> {
+        self.trusted_proxies.push(network.parse()?);
+        Ok(self)
+    }
+
+    /// Limit the number of connections accepted by this listener, see `ConnectionLimits`.
+    ///
+    /// These limits only count the connections to this listener, connections must also be
+    /// within the limits set with `Server::with_connection_limits`.
+    pub fn with_connection_limits(&mut self, limits: ConnectionLimits) -> &mut Self {
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Set a tcp listener from an already open socket
+    pub fn with_tcp_listener(&mut self, listener: TcpListener) -> &mut Self {
+        self.tcp_listener = Some(listener);
+        self
+    }
+
+    /// Add ip addresses and ports to listen on.
+    /// Returns an error if the given socket addresses are not valid.
+    pub fn with_addr<A: ToSocketAddrs>(&mut self, addr: A) -> Result<&mut Self, Error> {
+        for addr in addr
+            .to_socket_addrs()
+            .map_err(|e| Error::with_source("Invalid socket address", e))?
+        {
+            self.socket_address.push(addr);
+        }
+        Ok(self)
+    }
+
+    // True if an address or socket was given to the listener
+    pub(crate) fn has_address(&self) -> bool {
+        self.tcp_listener.is_some() || !self.socket_address.is_empty()
+    }
+}
@@ -1,20 +1,55 @@
-use crate::ssl::{SslConfig, Stream};
+use crate::ssl::{io_failure, CertFiles, HandshakeError, Reload, Reloadable, SslConfig, Stream};
 use crate::Error;
+use mailin::{TlsFailure, TlsInfo};
 use openssl::error::ErrorStack;
 use openssl::pkey::PKey;
-use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslMethod, SslStream};
+use openssl::ssl::{
+    self, ErrorCode, NameType, SniError, SslAcceptor, SslAcceptorBuilder, SslMethod, SslStream,
+};
 use openssl::x509::X509;
 use std::fmt::Display;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::net::TcpStream;
+use std::os::raw::c_int;
 use std::path::Path;
 use std::sync::Arc;
 
+// The library and reason codes of openssl errors (err.h and sslerr.h), which are not
+// exported by openssl-sys
+const ERR_LIB_SSL: c_int = 20;
+const NO_SHARED_CIPHER: &[c_int] = &[
+    101, // SSL_R_NO_SUITABLE_KEY_SHARE
+    118, // SSL_R_NO_SUITABLE_SIGNATURE_ALGORITHM
+    193, // SSL_R_NO_SHARED_CIPHER
+    295, // SSL_R_NO_SUITABLE_GROUPS
+    376, // SSL_R_NO_SHARED_SIGNATURE_ALGORITHMS
+    410, // SSL_R_NO_SHARED_GROUPS
+];
+const PROTOCOL_VERSION: &[c_int] = &[
+    156,  // SSL_R_HTTP_REQUEST
+    252,  // SSL_R_UNKNOWN_PROTOCOL
+    258,  // SSL_R_UNSUPPORTED_PROTOCOL
+    267,  // SSL_R_WRONG_VERSION_NUMBER
+    396,  // SSL_R_VERSION_TOO_LOW
+    1070, // SSL_R_TLSV1_ALERT_PROTOCOL_VERSION
+];
+const CERTIFICATE_UNKNOWN: &[c_int] = &[
+    1042, // SSL_R_SSLV3_ALERT_BAD_CERTIFICATE
+    1043, // SSL_R_SSLV3_ALERT_UNSUPPORTED_CERTIFICATE
+    1044, // SSL_R_SSLV3_ALERT_CERTIFICATE_REVOKED
+    1045, // SSL_R_SSLV3_ALERT_CERTIFICATE_EXPIRED
+    1046, // SSL_R_SSLV3_ALERT_CERTIFICATE_UNKNOWN
+    1048, // SSL_R_TLSV1_ALERT_UNKNOWN_CA
+];
+const CLIENT_ABORT: &[c_int] = &[
+    294, // SSL_R_UNEXPECTED_EOF_WHILE_READING
+];
+
 // Openssl wrapper
 #[derive(Clone)]
 pub struct SslImpl {
-    acceptor: Arc<SslAcceptor>,
+    acceptor: Arc<Reloadable<SslAcceptor>>,
 }
 
 impl From<ErrorStack> for Error {
@@ -24,57 +59,155 @@ impl From<ErrorStack> for Error {
     }
 }
 
-impl Stream for SslStream<TcpStream> {}
+impl<S: Read + Write> Stream for SslStream<S> {
+    fn tls_info(&self) -> TlsInfo {
+        let ssl = self.ssl();
+        TlsInfo {
+            protocol: Some(ssl.version_str().to_owned()),
+            cipher: ssl.current_cipher().map(|cipher| cipher.name().to_owned()),
+            server_name: ssl.servername(NameType::HOST_NAME).map(str::to_owned),
+            peer_certificate: ssl.peer_certificate().and_then(|cert| cert.to_der().ok()),
+        }
+    }
+
+    #[cfg(feature = "mio")]
+    fn is_handshaking(&self) -> bool {
+        !self.ssl().is_init_finished()
+    }
+}
 
 impl SslImpl {
     pub fn setup(ssl_config: SslConfig) -> Result<Option<Self>, Error> {
-        let builder = match ssl_config {
-            SslConfig::Trusted {
-                cert_path,
-                key_path,
-                chain_path,
-            } => {
-                let mut builder = ssl_builder(cert_path, key_path)?;
-                let chain_pem = slurp(chain_path)?;
-                let chain = X509::stack_from_pem(&chain_pem)?;
-                for cert in chain {
-                    builder.add_extra_chain_cert(cert.as_ref().to_owned())?;
-                }
-                Some(builder)
+        let ret = Reloadable::setup(ssl_config, build_acceptor)?.map(|acceptor| SslImpl {
+            acceptor: Arc::new(acceptor),
+        });
+        Ok(ret)
+    }
+
+    // Use an openssl acceptor built by the caller
+    pub fn from_acceptor(acceptor: SslAcceptor) -> Self {
+        SslImpl {
+            acceptor: Arc::new(Reloadable::prebuilt(Arc::new(acceptor))),
+        }
+    }
+
+    // Reloads the certificates used for new TLS sessions
+    pub fn reloader(&self) -> Arc<dyn Reload> {
+        self.acceptor.clone()
+    }
+
+    pub fn accept(&self, stream: TcpStream) -> Result<impl Stream, HandshakeError> {
+        self.acceptor.current().accept(stream).map_err(|e| {
+            let failure = match &e {
+                ssl::HandshakeError::SetupFailure(_) => TlsFailure::Other,
+                ssl::HandshakeError::Failure(mid) => ssl_failure(mid.error()),
+                ssl::HandshakeError::WouldBlock(_) => TlsFailure::Timeout,
+            };
+            HandshakeError {
+                failure,
+                error: Error::with_source("Cannot upgrade to TLS", e),
             }
-            SslConfig::SelfSigned {
-                cert_path,
-                key_path,
-            } => {
-                let builder = ssl_builder(cert_path, key_path)?;
-                Some(builder)
+        })
+    }
+
+    // Classify a handshake failure from the error returned by a TLS stream
+    #[cfg(feature = "mio")]
+    pub fn failure(err: &std::io::Error) -> TlsFailure {
+        match err.get_ref().and_then(|e| e.downcast_ref::<ssl::Error>()) {
+            Some(ssl_err) => ssl_failure(ssl_err),
+            None => io_failure(err),
+        }
+    }
+
+    // Start TLS on a non-blocking stream, the handshake takes place as the returned stream
+    // is read and written
+    #[cfg(feature = "mio")]
+    pub fn accept_nonblocking<S>(&self, stream: S) -> Result<Box<dyn Stream>, Error>
+    where
+        S: Read + Write + 'static,
+    {
+        let mut ssl = openssl::ssl::Ssl::new(self.acceptor.current().context())?;
+        ssl.set_accept_state();
+        Ok(Box::new(SslStream::new(ssl, stream)?))
+    }
+}
+
+// Build the openssl acceptor, None if STARTTLS is not supported
+fn build_acceptor(ssl_config: SslConfig) -> Result<Option<SslAcceptor>, Error> {
+    let Some(cert_set) = ssl_config.cert_set()? else {
+        return Ok(None);
+    };
+    let mut builder = ssl_builder(cert_set.default)?;
+    if !cert_set.named.is_empty() {
+        let named = cert_set
+            .named
+            .try_map(|files| Ok(ssl_builder(files)?.build().into_context()))?;
+        builder.set_servername_callback(move |ssl, _alert| {
+            let context = ssl
+                .servername(NameType::HOST_NAME)
+                .and_then(|name| named.find(name));
+            if let Some(context) = context {
+                ssl.set_ssl_context(context)
+                    .map_err(|_| SniError::ALERT_FATAL)?;
             }
-            _ => None,
-        };
-        let ssl = builder.map(|b| SslImpl {
-            acceptor: Arc::new(b.build()),
+            Ok(())
         });
-        Ok(ssl)
     }
+    Ok(Some(builder.build()))
+}
 
-    pub fn accept(&self, stream: TcpStream) -> Result<impl Stream, Error> {
-        let ret = self
-            .acceptor
-            .accept(stream)
-            .map_err(|e| Error::with_source("Cannot upgrade to TLS", e))?;
-        Ok(ret)
+// Classify a handshake failure from an openssl error
+fn ssl_failure(err: &ssl::Error) -> TlsFailure {
+    if let Some(io_err) = err.io_error() {
+        return io_failure(io_err);
+    }
+    match err.code() {
+        ErrorCode::ZERO_RETURN | ErrorCode::SYSCALL => return TlsFailure::ClientAbort,
+        ErrorCode::WANT_READ | ErrorCode::WANT_WRITE => return TlsFailure::Timeout,
+        _ => (),
+    }
+    let reasons: Vec<c_int> = err
+        .ssl_error()
+        .map(|stack| {
+            stack
+                .errors()
+                .iter()
+                .filter(|e| e.library_code() == ERR_LIB_SSL)
+                .map(|e| e.reason_code())
+                .collect()
+        })
+        .unwrap_or_default();
+    let has_reason = |codes: &[c_int]| reasons.iter().any(|reason| codes.contains(reason));
+    if has_reason(NO_SHARED_CIPHER) {
+        TlsFailure::NoSharedCipher
+    } else if has_reason(PROTOCOL_VERSION) {
+        TlsFailure::ProtocolVersion
+    } else if has_reason(CERTIFICATE_UNKNOWN) {
+        TlsFailure::CertificateUnknown
+    } else if has_reason(CLIENT_ABORT) {
+        TlsFailure::ClientAbort
+    } else {
+        TlsFailure::Other
     }
 }
 
-fn ssl_builder(cert_path: String, key_path: String) -> Result<SslAcceptorBuilder, Error> {
+// An acceptor builder for a certificate, followed by its chain, and the private key
+fn ssl_builder(files: CertFiles) -> Result<SslAcceptorBuilder, Error> {
     let mut builder = SslAcceptor::mozilla_modern(SslMethod::tls())?;
-    let cert_pem = slurp(cert_path)?;
+    let cert_pem = slurp(files.cert_path)?;
     let cert = X509::from_pem(&cert_pem)?;
-    let key_pem = slurp(key_path)?;
+    let key_pem = slurp(files.key_path)?;
     let pkey = PKey::private_key_from_pem(&key_pem)?;
     builder.set_private_key(&pkey)?;
     builder.set_certificate(&cert)?;
     builder.check_private_key()?;
+    if let Some(chain_path) = files.chain_path {
+        let chain_pem = slurp(chain_path)?;
+        let chain = X509::stack_from_pem(&chain_pem)?;
+        for cert in chain {
+            builder.add_extra_chain_cert(cert.as_ref().to_owned())?;
+        }
+    }
     Ok(builder)
 }
 
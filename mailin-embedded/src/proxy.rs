@@ -0,0 +1,468 @@
+use crate::err::Error;
+use std::io::{self, ErrorKind, Read};
+use std::net::IpAddr;
+use std::str::{self, FromStr};
+use std::time::Duration;
+
+// Proxies send the header as soon as they connect
+pub(crate) const HEADER_TIMEOUT: Duration = Duration::from_secs(30);
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// The longest version 1 header, including the line ending
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_FIXED_LENGTH: usize = 16;
+const READ_SIZE: usize = 512;
+
+// A network that proxies are trusted to connect from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Network {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = Error;
+
+    // Parse an address with an optional prefix length, such as 10.0.0.0/8
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || Error::new(format!("Invalid network {}", s));
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+// The next read needed to complete a header
+enum Next {
+    // Read up to this many bytes
+    Read(usize),
+    // Read up to the end of the line, which is at most this many bytes away
+    Line(usize),
+    // The header is complete, with the address of the client if the proxy sent one
+    Done(Option<IpAddr>),
+}
+
+// Sockets that can look at incoming data without consuming it
+pub(crate) trait Peek {
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+impl Peek for std::net::TcpStream {
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        std::net::TcpStream::peek(self, buf)
+    }
+}
+
+impl<S: Peek> Peek for &S {
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).peek(buf)
+    }
+}
+
+#[cfg(feature = "mio")]
+impl Peek for mio::net::TcpStream {
+    fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        mio::net::TcpStream::peek(self, buf)
+    }
+}
+
+// Reads a PROXY protocol version 1 or 2 header without reading past its end, so that the
+// rest of the stream can be used for SMTP or TLS
+#[derive(Default)]
+pub(crate) struct HeaderReader {
+    header: Vec<u8>,
+}
+
+impl HeaderReader {
+    // Read the header, returns the address of the client or None if the proxy did not send
+    // one. Non-blocking sockets return WouldBlock until the header is complete.
+    pub fn read<S: Read + Peek>(&mut self, stream: &mut S) -> io::Result<Option<IpAddr>> {
+        let mut buf = [0u8; READ_SIZE];
+        loop {
+            match next(&self.header)? {
+                Next::Done(source) => return Ok(source),
+                Next::Read(len) => {
+                    let num_bytes = stream.read(&mut buf[..len.min(READ_SIZE)])?;
+                    self.append(&buf[..num_bytes])?;
+                }
+                Next::Line(max) => {
+                    let peeked = stream.peek(&mut buf[..max])?;
+                    let len = line_length(&buf[..peeked]);
+                    stream.read_exact(&mut buf[..len])?;
+                    self.append(&buf[..len])?;
+                }
+            }
+        }
+    }
+
+    // Read the header from a tokio socket
+    #[cfg(feature = "tokio")]
+    pub async fn read_async(
+        &mut self,
+        stream: &mut tokio::net::TcpStream,
+    ) -> io::Result<Option<IpAddr>> {
+        use tokio::io::AsyncReadExt;
+        let mut buf = [0u8; READ_SIZE];
+        loop {
+            match next(&self.header)? {
+                Next::Done(source) => return Ok(source),
+                Next::Read(len) => {
+                    let num_bytes = stream.read(&mut buf[..len.min(READ_SIZE)]).await?;
+                    self.append(&buf[..num_bytes])?;
+                }
+                Next::Line(max) => {
+                    let peeked = stream.peek(&mut buf[..max]).await?;
+                    let len = line_length(&buf[..peeked]);
+                    stream.read_exact(&mut buf[..len]).await?;
+                    self.append(&buf[..len])?;
+                }
+            }
+        }
+    }
+
+    fn append(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if bytes.is_empty() {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        self.header.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+// The length of the data up to and including the first newline, or all of it
+fn line_length(buf: &[u8]) -> usize {
+    buf.iter()
+        .position(|b| *b == b'\n')
+        .map(|i| i + 1)
+        .unwrap_or(buf.len())
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+// Check the header read so far and find what to read next
+fn next(header: &[u8]) -> io::Result<Next> {
+    match header.first() {
+        None => Ok(Next::Read(1)),
+        Some(b'P') => next_v1(header),
+        Some(b'\r') => next_v2(header),
+        Some(_) => Err(invalid("Not a PROXY protocol header")),
+    }
+}
+
+// Version 1 is a line of text, such as PROXY TCP4 192.0.2.1 192.0.2.2 56324 25
+fn next_v1(header: &[u8]) -> io::Result<Next> {
+    let checked = header.len().min(V1_PREFIX.len());
+    if header[..checked] != V1_PREFIX[..checked] {
+        return Err(invalid("Not a PROXY protocol header"));
+    }
+    let Some(line) = header.strip_suffix(b"\n") else {
+        if header.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY protocol header too long"));
+        }
+        return Ok(Next::Line(V1_MAX_LENGTH - header.len()));
+    };
+    let line = line
+        .strip_suffix(b"\r")
+        .and_then(|line| str::from_utf8(line).ok())
+        .ok_or_else(|| invalid("Invalid PROXY protocol header"))?;
+    let mut fields = line.split(' ').skip(1);
+    let source = match fields.next() {
+        Some("UNKNOWN") => return Ok(Next::Done(None)),
+        Some("TCP4") => fields
+            .next()
+            .and_then(|addr| addr.parse().ok())
+            .map(IpAddr::V4),
+        Some("TCP6") => fields
+            .next()
+            .and_then(|addr| addr.parse().ok())
+            .map(IpAddr::V6),
+        _ => None,
+    };
+    let rest: Vec<&str> = fields.collect();
+    let valid_rest = matches!(rest[..], [dest, source_port, dest_port]
+        if dest.parse::<IpAddr>().is_ok()
+            && source_port.parse::<u16>().is_ok()
+            && dest_port.parse::<u16>().is_ok());
+    match source {
+        Some(source) if valid_rest => Ok(Next::Done(Some(source))),
+        _ => Err(invalid("Invalid PROXY protocol header")),
+    }
+}
+
+// Version 2 is binary, a signature followed by the command, address family, the length
+// of the addresses and the addresses
+fn next_v2(header: &[u8]) -> io::Result<Next> {
+    let checked = header.len().min(V2_SIGNATURE.len());
+    if header[..checked] != V2_SIGNATURE[..checked] {
+        return Err(invalid("Not a PROXY protocol header"));
+    }
+    if header.len() < V2_FIXED_LENGTH {
+        return Ok(Next::Read(V2_FIXED_LENGTH - header.len()));
+    }
+    let length = V2_FIXED_LENGTH + u16::from_be_bytes([header[14], header[15]]) as usize;
+    if header.len() < length {
+        return Ok(Next::Read(length - header.len()));
+    }
+    if header[12] >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY protocol version"));
+    }
+    match header[12] & 0x0f {
+        // LOCAL connections are made by the proxy itself, such as health checks
+        0 => return Ok(Next::Done(None)),
+        1 => (),
+        _ => return Err(invalid("Invalid PROXY protocol command")),
+    }
+    let addresses = &header[V2_FIXED_LENGTH..];
+    match header[13] >> 4 {
+        1 if addresses.len() >= 12 => {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(&addresses[..4]);
+            Ok(Next::Done(Some(IpAddr::from(octets))))
+        }
+        2 if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            Ok(Next::Done(Some(IpAddr::from(octets))))
+        }
+        // Unspecified and unix socket addresses
+        0 | 3 => Ok(Next::Done(None)),
+        _ => Err(invalid("Invalid PROXY protocol address")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    impl Peek for Cursor<&[u8]> {
+        fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+            let rest = &self.get_ref()[self.position() as usize..];
+            let len = rest.len().min(buf.len());
+            buf[..len].copy_from_slice(&rest[..len]);
+            Ok(len)
+        }
+    }
+
+    // Read a header followed by an SMTP command, returns the client address and the data
+    // left after the header
+    fn read(input: &[u8]) -> io::Result<(Option<IpAddr>, Vec<u8>)> {
+        let mut stream = Cursor::new(input);
+        let source = HeaderReader::default().read(&mut stream)?;
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest)?;
+        Ok((source, rest))
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[command, family]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    fn kind(result: io::Result<(Option<IpAddr>, Vec<u8>)>) -> ErrorKind {
+        result.unwrap_err().kind()
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let (source, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 40000 25\r\nEHLO").unwrap();
+        assert_eq!(source, Some(Ipv4Addr::new(192, 0, 2, 1).into()));
+        assert_eq!(rest, b"EHLO");
+    }
+
+    #[test]
+    fn v1_tcp6() {
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 40000 25\r\nEHLO";
+        let (source, rest) = read(header).unwrap();
+        let expected = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        assert_eq!(source, Some(expected.into()));
+        assert_eq!(rest, b"EHLO");
+        // The address must match the protocol
+        let header = b"PROXY TCP6 192.0.2.1 192.0.2.2 40000 25\r\n";
+        assert_eq!(kind(read(header)), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn v1_unknown() {
+        let (source, rest) = read(b"PROXY UNKNOWN\r\nEHLO").unwrap();
+        assert_eq!(source, None);
+        assert_eq!(rest, b"EHLO");
+        let header = b"PROXY UNKNOWN 2001:db8::1 2001:db8::2 40000 25\r\nEHLO";
+        assert_eq!(read(header).unwrap().0, None);
+    }
+
+    #[test]
+    fn v1_invalid() {
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.1 40000\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 40000 65536\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 40000 25\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 40000 25\r\n",
+            b"PROXIED TCP4\r\n",
+            b"EHLO a.domain\r\n",
+        ] {
+            assert_eq!(kind(read(header)), ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn v1_too_long() {
+        let mut header = b"PROXY UNKNOWN ".to_vec();
+        header.resize(V1_MAX_LENGTH + 10, b'a');
+        header.extend_from_slice(b"\r\n");
+        assert_eq!(kind(read(&header)), ErrorKind::InvalidData);
+        // The longest header is accepted
+        let mut header = b"PROXY UNKNOWN ".to_vec();
+        header.resize(V1_MAX_LENGTH - 2, b'a');
+        header.extend_from_slice(b"\r\n");
+        assert_eq!(read(&header).unwrap().0, None);
+    }
+
+    #[test]
+    fn v2_tcp4() {
+        let addresses = [192, 0, 2, 1, 198, 51, 100, 1, 0x9c, 0x40, 0, 25];
+        let mut input = v2(0x21, 0x11, &addresses);
+        input.extend_from_slice(b"EHLO");
+        let (source, rest) = read(&input).unwrap();
+        assert_eq!(source, Some(Ipv4Addr::new(192, 0, 2, 1).into()));
+        assert_eq!(rest, b"EHLO");
+    }
+
+    #[test]
+    fn v2_tcp6() {
+        let client = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+        let mut addresses = client.octets().to_vec();
+        addresses.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        addresses.extend_from_slice(&[0x9c, 0x40, 0, 25]);
+        // Extra TLVs after the addresses are skipped
+        addresses.extend_from_slice(&[0x04, 0, 1, 0]);
+        let mut input = v2(0x21, 0x21, &addresses);
+        input.extend_from_slice(b"EHLO");
+        let (source, rest) = read(&input).unwrap();
+        assert_eq!(source, Some(client.into()));
+        assert_eq!(rest, b"EHLO");
+    }
+
+    #[test]
+    fn v2_local_and_unspecified() {
+        let (source, rest) = read(&v2(0x20, 0x00, &[])).unwrap();
+        assert_eq!(source, None);
+        assert!(rest.is_empty());
+        assert_eq!(read(&v2(0x21, 0x00, &[])).unwrap().0, None);
+    }
+
+    #[test]
+    fn v2_invalid() {
+        // Version 1 in a binary header
+        let addresses = [192, 0, 2, 1, 198, 51, 100, 1, 0x9c, 0x40, 0, 25];
+        assert_eq!(
+            kind(read(&v2(0x11, 0x11, &addresses))),
+            ErrorKind::InvalidData
+        );
+        // Unknown command
+        assert_eq!(
+            kind(read(&v2(0x22, 0x11, &addresses))),
+            ErrorKind::InvalidData
+        );
+        // Addresses shorter than the family
+        assert_eq!(
+            kind(read(&v2(0x21, 0x11, &addresses[..8]))),
+            ErrorKind::InvalidData
+        );
+        assert_eq!(
+            kind(read(&v2(0x21, 0x21, &addresses))),
+            ErrorKind::InvalidData
+        );
+        // A signature that does not match
+        let mut header = v2(0x21, 0x11, &addresses);
+        header[6] = b'X';
+        assert_eq!(kind(read(&header)), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated() {
+        let addresses = [192, 0, 2, 1, 198, 51, 100, 1, 0x9c, 0x40, 0, 25];
+        let header = v2(0x21, 0x11, &addresses);
+        for len in [0, 5, V2_FIXED_LENGTH - 1, V2_FIXED_LENGTH + 4] {
+            assert_eq!(kind(read(&header[..len])), ErrorKind::UnexpectedEof);
+        }
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 40000 25";
+        assert_eq!(kind(read(header)), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn ipv4_networks() {
+        let network: Network = "192.0.2.0/24".parse().unwrap();
+        assert!(network.contains(Ipv4Addr::new(192, 0, 2, 77).into()));
+        assert!(!network.contains(Ipv4Addr::new(192, 0, 3, 1).into()));
+        // IPv4 addresses of clients connecting over IPv6
+        let mapped = Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped();
+        assert!(network.contains(mapped.into()));
+        let host: Network = "192.0.2.1".parse().unwrap();
+        assert!(host.contains(Ipv4Addr::new(192, 0, 2, 1).into()));
+        assert!(!host.contains(Ipv4Addr::new(192, 0, 2, 2).into()));
+        let all: Network = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(Ipv4Addr::new(203, 0, 113, 1).into()));
+        assert!(!all.contains(Ipv6Addr::LOCALHOST.into()));
+    }
+
+    #[test]
+    fn ipv6_networks() {
+        let network: Network = "2001:db8::/32".parse().unwrap();
+        assert!(network.contains(Ipv6Addr::new(0x2001, 0xdb8, 1, 0, 0, 0, 0, 1).into()));
+        assert!(!network.contains(Ipv6Addr::new(0x2001, 0xdb9, 0, 0, 0, 0, 0, 1).into()));
+        assert!(!network.contains(Ipv4Addr::new(192, 0, 2, 1).into()));
+        let host: Network = "::1".parse().unwrap();
+        assert!(host.contains(Ipv6Addr::LOCALHOST.into()));
+        let all: Network = "::/0".parse().unwrap();
+        assert!(all.contains(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1).into()));
+    }
+
+    #[test]
+    fn invalid_networks() {
+        for network in [
+            "",
+            "192.0.2.0/33",
+            "2001:db8::/129",
+            "192.0.2.0/",
+            "mail/24",
+        ] {
+            assert!(network.parse::<Network>().is_err(), "{}", network);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use crate::err::Error;
+use crate::ssl::Reload;
+use log::{error, info};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// `TlsReloadHandle` reloads the TLS certificates of a server without a restart.
+///
+/// A handle is obtained from `Server::tls_reload_handle` after the SSL configuration is
+/// set. New TLS sessions use the reloaded certificates, sessions that have already started
+/// TLS are not affected.
+/// ```no_run
+/// # use mailin_embedded::{Handler, Server, SslConfig};
+/// # use std::time::Duration;
+/// # #[derive(Clone)]
+/// # struct EmptyHandler {}
+/// # impl Handler for EmptyHandler {}
+/// let mut server = Server::new(EmptyHandler {});
+/// server.with_ssl(SslConfig::SelfSigned {
+///     cert_path: "/etc/mail/cert.pem".to_owned(),
+///     key_path: "/etc/mail/key.pem".to_owned(),
+/// })?;
+/// let reload = server.tls_reload_handle().unwrap();
+/// reload.watch(Duration::from_secs(60));
+/// # Ok::<(), mailin_embedded::err::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct TlsReloadHandle {
+    inner: Arc<dyn Reload>,
+}
+
+impl TlsReloadHandle {
+    pub(crate) fn new(inner: Arc<dyn Reload>) -> Self {
+        Self { inner }
+    }
+
+    /// Reload the certificates from the files of the SSL configuration.
+    ///
+    /// If the certificates cannot be loaded an error is returned and the server keeps using
+    /// the certificates it had before.
+    pub fn reload(&self) -> Result<(), Error> {
+        self.inner.reload()
+    }
+
+    /// Check the certificate files for changes at the given interval and reload the
+    /// certificates when they change.
+    ///
+    /// The check runs on a background thread that stops once the server and every
+    /// `TlsReloadHandle` have been dropped. Errors while reloading are logged.
+    pub fn watch(&self, interval: Duration) {
+        let inner = Arc::downgrade(&self.inner);
+        thread::spawn(move || watch_files(inner, interval));
+    }
+
+    /// Reload the certificates whenever the process receives SIGHUP.
+    ///
+    /// The signal is handled on a background thread, errors while reloading are logged.
+    #[cfg(all(unix, feature = "sighup"))]
+    pub fn reload_on_sighup(&self) -> Result<(), Error> {
+        use signal_hook::consts::SIGHUP;
+        use signal_hook::iterator::Signals;
+        let mut signals = Signals::new([SIGHUP])?;
+        let handle = self.clone();
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                info!("SIGHUP received, reloading TLS certificates");
+                if let Err(err) = handle.reload() {
+                    error!("Cannot reload TLS certificates: {}", err);
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+// Reload the certificates when the modification times of their files change
+fn watch_files(inner: Weak<dyn Reload>, interval: Duration) {
+    let mut last_seen = None;
+    loop {
+        let Some(reload) = inner.upgrade() else {
+            break;
+        };
+        let modified = modification_times(reload.watched_paths());
+        if last_seen.as_ref().is_some_and(|last| *last != modified) {
+            info!("TLS certificate files changed, reloading");
+            if let Err(err) = reload.reload() {
+                error!("Cannot reload TLS certificates: {}", err);
+            }
+        }
+        last_seen = Some(modified);
+        drop(reload);
+        thread::sleep(interval);
+    }
+}
+
+fn modification_times(paths: Vec<PathBuf>) -> Vec<Option<SystemTime>> {
+    paths
+        .into_iter()
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
@@ -1,19 +1,36 @@
-use crate::ssl::{SslConfig, Stream};
+use crate::ssl::{
+    io_failure, CertFiles, HandshakeError, Reload, Reloadable, ServerNames, SslConfig, Stream,
+};
 use crate::Error;
+use mailin::{TlsFailure, TlsInfo};
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
-use rustls::{Error as TLSError, ServerConfig, ServerConnection, StreamOwned};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{
+    AlertDescription, Error as TLSError, PeerIncompatible, ProtocolVersion, ServerConfig,
+    ServerConnection, StreamOwned,
+};
 use std::fs;
-use std::io::BufReader;
+use std::io::{self, BufReader, Read, Write};
 use std::net::TcpStream;
 use std::sync::Arc;
 
 // Rustls wrapper
 #[derive(Clone)]
 pub struct SslImpl {
-    tls_config: Arc<ServerConfig>,
+    tls_config: Arc<Reloadable<ServerConfig>>,
 }
 
-impl Stream for StreamOwned<ServerConnection, TcpStream> {}
+impl<S: Read + Write> Stream for StreamOwned<ServerConnection, S> {
+    fn tls_info(&self) -> TlsInfo {
+        connection_info(&self.conn)
+    }
+
+    #[cfg(feature = "mio")]
+    fn is_handshaking(&self) -> bool {
+        self.conn.is_handshaking()
+    }
+}
 
 impl From<TLSError> for Error {
     fn from(error: TLSError) -> Self {
@@ -24,45 +41,173 @@ impl From<TLSError> for Error {
 
 impl SslImpl {
     pub fn setup(ssl_config: SslConfig) -> Result<Option<Self>, Error> {
-        let config = match ssl_config {
-            SslConfig::Trusted {
-                cert_path,
-                key_path,
-                chain_path,
-            } => {
-                let mut certs = load_certs(&cert_path)?;
-                let mut chain = load_certs(&chain_path)?;
-                certs.append(&mut chain);
-                let key = load_key(&key_path)?;
-                let config = ServerConfig::builder()
-                    .with_no_client_auth()
-                    .with_single_cert(certs, key)?;
-                Some(config)
-            }
-            SslConfig::SelfSigned {
-                cert_path,
-                key_path,
-            } => {
-                let certs = load_certs(&cert_path)?;
-                let key = load_key(&key_path)?;
-                let config = ServerConfig::builder()
-                    .with_no_client_auth()
-                    .with_single_cert(certs, key)?;
-                Some(config)
-            }
-            _ => None,
-        };
-        let ret = config.map(|c| SslImpl {
-            tls_config: Arc::new(c),
+        let ret = Reloadable::setup(ssl_config, build_config)?.map(|config| SslImpl {
+            tls_config: Arc::new(config),
         });
         Ok(ret)
     }
 
-    pub fn accept(&self, stream: TcpStream) -> Result<impl Stream, Error> {
-        let session = ServerConnection::new(self.tls_config.clone())?;
-        let tls_stream = StreamOwned::new(session, stream);
+    // Use a rustls configuration built by the caller
+    pub fn from_config(config: Arc<ServerConfig>) -> Self {
+        SslImpl {
+            tls_config: Arc::new(Reloadable::prebuilt(config)),
+        }
+    }
+
+    // Reloads the certificates used for new TLS sessions
+    pub fn reloader(&self) -> Arc<dyn Reload> {
+        self.tls_config.clone()
+    }
+
+    pub fn accept(&self, stream: TcpStream) -> Result<impl Stream, HandshakeError> {
+        let session =
+            ServerConnection::new(self.tls_config.current()).map_err(|e| HandshakeError {
+                failure: TlsFailure::Other,
+                error: e.into(),
+            })?;
+        let mut tls_stream = StreamOwned::new(session, stream);
+        // Complete the handshake so that the details of the session are known
+        while tls_stream.conn.is_handshaking() {
+            if let Err(err) = tls_stream.conn.complete_io(&mut tls_stream.sock) {
+                return Err(HandshakeError {
+                    failure: Self::failure(&err),
+                    error: Error::with_source("TLS handshake failed", err),
+                });
+            }
+        }
         Ok(tls_stream)
     }
+
+    // Classify a handshake failure from the error returned by a TLS stream
+    pub fn failure(err: &io::Error) -> TlsFailure {
+        let tls_err = err.get_ref().and_then(|e| e.downcast_ref::<TLSError>());
+        match tls_err {
+            None => io_failure(err),
+            Some(TLSError::PeerIncompatible(
+                PeerIncompatible::NoCipherSuitesInCommon
+                | PeerIncompatible::NoKxGroupsInCommon
+                | PeerIncompatible::NoSignatureSchemesInCommon
+                | PeerIncompatible::NoEcPointFormatsInCommon,
+            )) => TlsFailure::NoSharedCipher,
+            Some(TLSError::PeerIncompatible(
+                PeerIncompatible::SupportedVersionsExtensionRequired
+                | PeerIncompatible::Tls12NotOffered
+                | PeerIncompatible::Tls12NotOfferedOrEnabled,
+            )) => TlsFailure::ProtocolVersion,
+            Some(TLSError::InvalidMessage(_)) => TlsFailure::ProtocolVersion,
+            Some(TLSError::AlertReceived(alert)) => match alert {
+                AlertDescription::HandshakeFailure | AlertDescription::InsufficientSecurity => {
+                    TlsFailure::NoSharedCipher
+                }
+                AlertDescription::ProtocolVersion => TlsFailure::ProtocolVersion,
+                AlertDescription::BadCertificate
+                | AlertDescription::UnsupportedCertificate
+                | AlertDescription::CertificateRevoked
+                | AlertDescription::CertificateExpired
+                | AlertDescription::CertificateUnknown
+                | AlertDescription::UnknownCA => TlsFailure::CertificateUnknown,
+                AlertDescription::CloseNotify | AlertDescription::UserCanceled => {
+                    TlsFailure::ClientAbort
+                }
+                _ => TlsFailure::Other,
+            },
+            Some(_) => TlsFailure::Other,
+        }
+    }
+
+    // The configuration used to accept TLS connections
+    #[cfg(feature = "tokio")]
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        self.tls_config.current()
+    }
+
+    // Start TLS on a non-blocking stream, the handshake takes place as the returned stream
+    // is read and written
+    #[cfg(feature = "mio")]
+    pub fn accept_nonblocking<S>(&self, stream: S) -> Result<Box<dyn Stream>, Error>
+    where
+        S: Read + Write + 'static,
+    {
+        let session = ServerConnection::new(self.tls_config.current())?;
+        Ok(Box::new(StreamOwned::new(session, stream)))
+    }
+}
+
+// Build the rustls configuration, None if STARTTLS is not supported
+fn build_config(ssl_config: SslConfig) -> Result<Option<ServerConfig>, Error> {
+    let Some(cert_set) = ssl_config.cert_set()? else {
+        return Ok(None);
+    };
+    let builder = ServerConfig::builder();
+    let config = if cert_set.named.is_empty() {
+        let (certs, key) = load_cert_files(cert_set.default)?;
+        builder.with_no_client_auth().with_single_cert(certs, key)?
+    } else {
+        let provider = builder.crypto_provider().clone();
+        let certified_key = |files| {
+            let (certs, key) = load_cert_files(files)?;
+            let certified = CertifiedKey::from_der(certs, key, &provider)?;
+            Ok(Arc::new(certified))
+        };
+        let resolver = SniResolver {
+            default: certified_key(cert_set.default)?,
+            named: cert_set.named.try_map(certified_key)?,
+        };
+        builder
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver))
+    };
+    Ok(Some(config))
+}
+
+// The details of a TLS session
+pub(crate) fn connection_info(conn: &ServerConnection) -> TlsInfo {
+    let protocol = conn.protocol_version().map(|version| match version {
+        ProtocolVersion::TLSv1_2 => "TLSv1.2".to_owned(),
+        ProtocolVersion::TLSv1_3 => "TLSv1.3".to_owned(),
+        other => format!("{:?}", other),
+    });
+    let cipher = conn
+        .negotiated_cipher_suite()
+        .map(|suite| format!("{:?}", suite.suite()));
+    TlsInfo {
+        protocol,
+        cipher,
+        server_name: conn.server_name().map(str::to_owned),
+        peer_certificate: conn
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(|cert| cert.to_vec()),
+    }
+}
+
+// Selects a certificate with the server name sent by the client
+#[derive(Debug)]
+struct SniResolver {
+    default: Arc<CertifiedKey>,
+    named: ServerNames<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certified = client_hello
+            .server_name()
+            .and_then(|name| self.named.find(name))
+            .unwrap_or(&self.default);
+        Some(certified.clone())
+    }
+}
+
+// Load a certificate, followed by its chain, and the private key
+fn load_cert_files(
+    files: CertFiles,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), Error> {
+    let mut certs = load_certs(&files.cert_path)?;
+    if let Some(chain_path) = files.chain_path {
+        certs.append(&mut load_certs(&chain_path)?);
+    }
+    let key = load_key(&files.key_path)?;
+    Ok((certs, key))
 }
 
 fn load_certs(filename: &str) -> Result<Vec<CertificateDer<'static>>, Error> {
@@ -6,100 +6,353 @@ cfg_if::cfg_if! {
         use crate::rtls::SslImpl;
     }
 }
+use crate::limits::{Admission, ConnectionLimits, Permit};
+use crate::line::{
+    cancel_auth, read_line, skip_line, Disposition, LineFramer, LongLines, ReadLine,
+};
+use crate::listener::{Listener, TlsMode};
+use crate::proxy::{HeaderReader, Network, HEADER_TIMEOUT};
+use crate::shutdown::{ConnectionGuard, ShutdownHandle};
 use crate::ssl::Stream;
+use crate::timeouts::{is_timeout, SessionTimer, Timeouts};
 use crate::Server;
 use bufstream_fresh::BufStream;
 use log::{debug, error, info};
+use mailin::client::ClientAction;
+use mailin::response::{LINE_TOO_LONG, NO_SERVICE, TIMEOUT, TOO_MANY_CONNECTIONS};
 use mailin::{Action, Handler, Response, Session, SessionBuilder};
 use scoped_threadpool::Pool;
-use std::io::{BufRead, Write};
-use std::net::{IpAddr, TcpListener, TcpStream};
+use std::io::{BufRead, ErrorKind, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 
 const FIVE_MINUTES: Duration = Duration::new(5 * 60, 0);
+const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
 
 enum SessionResult {
     Finished,
     UpgradeTls,
 }
 
-struct ServerState<H>
+// A listener ready to accept connections
+pub(crate) struct ListenerState {
+    pub(crate) label: String,
+    pub(crate) listener: TcpListener,
+    pub(crate) local_addr: SocketAddr,
+    pub(crate) session_builder: SessionBuilder,
+    num_threads: u32,
+    pub(crate) implicit_tls: bool,
+    pub(crate) greeting_delay: Option<Duration>,
+    pub(crate) timeouts: Timeouts,
+    pub(crate) max_line_length: usize,
+    pub(crate) long_lines: LongLines,
+    trusted_proxies: Vec<Network>,
+    // Admits connections within the limits of the listener and the server
+    pub(crate) admission: Arc<Admission>,
+    // The number of sessions running on the listener
+    active: AtomicUsize,
+}
+
+impl ListenerState {
+    fn new(
+        config: Listener,
+        name: &str,
+        has_ssl: bool,
+        admission: &Arc<Admission>,
+    ) -> Result<Self, Error> {
+        let implicit_tls = config.tls == TlsMode::Implicit;
+        if implicit_tls && !has_ssl {
+            return Error::bail(format!(
+                "Implicit TLS on listener {} requires an SSL configuration",
+                config.label
+            ));
+        }
+        let name = config.name.unwrap_or_else(|| name.to_owned());
+        let mut session_builder = SessionBuilder::new(name);
+        if has_ssl && config.tls == TlsMode::StartTls {
+            session_builder.enable_start_tls();
+        }
+        for auth in config.auth {
+            session_builder.enable_auth(auth);
+        }
+        session_builder.set_leniency(config.leniency);
+        for (builtin, replacement) in config.replacements {
+            session_builder.replace_response(builtin, replacement);
+        }
+        let listener = if let Some(listener) = config.tcp_listener {
+            listener
+        } else {
+            let addr = config.socket_address;
+            TcpListener::bind(&addr[..])
+                .map_err(|err| Error::with_source("Cannot open listen address", err))?
+        };
+        let local_addr = listener.local_addr()?;
+        let admission = match config.limits {
+            Some(limits) => Arc::new(Admission::within(limits, admission.clone())),
+            None => admission.clone(),
+        };
+        Ok(Self {
+            label: config.label,
+            listener,
+            local_addr,
+            session_builder,
+            num_threads: config.num_threads,
+            implicit_tls,
+            greeting_delay: config.greeting_delay,
+            timeouts: config.timeouts,
+            max_line_length: config.max_line_length,
+            long_lines: config.long_lines,
+            trusted_proxies: config.trusted_proxies,
+            admission,
+            active: AtomicUsize::new(0),
+        })
+    }
+
+    // True if connections from the address start with a PROXY protocol header
+    pub(crate) fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+}
+
+// Accepts the connections of a listener and runs their sessions until a shutdown
+pub(crate) type Run<H> = fn(&str, &ListenerState, H, Option<SslImpl>, &ShutdownHandle);
+
+pub(crate) fn serve<H>(config: Server<H>) -> Result<(), Error>
 where
     H: Handler + Clone + Send,
 {
-    listener: TcpListener,
-    handler: H,
-    session_builder: SessionBuilder,
-    ssl: Option<SslImpl>,
-    num_threads: u32,
+    serve_with(config, run)
 }
 
-pub(crate) fn serve<H>(config: Server<H>) -> Result<(), Error>
+// Start the listeners of a server, each on its own thread
+pub(crate) fn serve_with<H>(config: Server<H>, run: Run<H>) -> Result<(), Error>
 where
     H: Handler + Clone + Send,
 {
-    let mut session_builder = SessionBuilder::new(config.name.clone());
-    if config.ssl.is_some() {
-        session_builder.enable_start_tls();
+    let has_ssl = config.ssl.is_some();
+    let states = open_listeners(
+        config.listener,
+        config.listeners,
+        &config.name,
+        has_ssl,
+        config.limits,
+    )?;
+    let name = &config.name;
+    let handler = &config.handler;
+    let ssl = &config.ssl;
+    let shutdown = &config.shutdown;
+    for state in &states {
+        shutdown.add_listener(state.local_addr);
     }
-    for auth in &config.auth {
-        session_builder.enable_auth(auth.clone());
+    thread::scope(|scope| {
+        for state in &states {
+            let handler = handler.clone();
+            let ssl = ssl.clone();
+            scope.spawn(move || run(name, state, handler, ssl, shutdown));
+        }
+    });
+    Ok(())
+}
+
+// Open the listeners of a server, the default listener is only used if it has an address
+// or there are no other listeners. The limits of the server are shared by the listeners.
+pub(crate) fn open_listeners(
+    default: Listener,
+    others: Vec<Listener>,
+    name: &str,
+    has_ssl: bool,
+    limits: ConnectionLimits,
+) -> Result<Vec<ListenerState>, Error> {
+    let admission = Arc::new(Admission::new(limits));
+    let mut listeners = Vec::with_capacity(others.len() + 1);
+    if others.is_empty() || default.has_address() {
+        listeners.push(default);
     }
-    let listen = if let Some(listener) = config.tcp_listener {
-        listener
-    } else {
-        let addr = config.socket_address;
-        TcpListener::bind(&addr[..])
-            .map_err(|err| Error::with_source("Cannot open listen address", err))?
-    };
-    let server_state = ServerState {
-        listener: listen,
-        handler: config.handler,
-        session_builder,
-        ssl: config.ssl,
-        num_threads: config.num_threads,
-    };
-    run(&config.name, &server_state)
+    listeners.extend(others);
+    listeners
+        .into_iter()
+        .map(|l| ListenerState::new(l, name, has_ssl, &admission))
+        .collect()
 }
 
-fn run<H>(name: &str, server_state: &ServerState<H>) -> Result<(), Error>
-where
+fn run<H>(
+    name: &str,
+    state: &ListenerState,
+    handler: H,
+    ssl: Option<SslImpl>,
+    shutdown: &ShutdownHandle,
+) where
     H: Handler + Clone + Send,
 {
-    let mut pool = Pool::new(server_state.num_threads);
-    let localaddr = server_state.listener.local_addr()?;
-    info!("{} SMTP started on {}", name, localaddr);
+    let mut pool = Pool::new(state.num_threads);
+    info!(
+        "{} SMTP ({}) started on {}",
+        name, state.label, state.local_addr
+    );
     pool.scoped(|scoped| {
-        for conn in server_state.listener.incoming() {
+        // A shutdown before the listener was added to the handle cannot wake the accept
+        while !shutdown.is_stopping() {
+            let conn = state.listener.accept();
+            if shutdown.is_stopping() {
+                break;
+            }
             match conn {
-                Ok(stream) => {
-                    let builder = server_state.session_builder.clone();
-                    let acceptor = server_state.ssl.clone();
-                    let handler_clone = server_state.handler.clone();
+                Ok((stream, _)) => {
+                    let Some((busy, admitted)) = admit(&stream, state) else {
+                        reject(&stream, state);
+                        continue;
+                    };
+                    let acceptor = ssl.clone();
+                    let handler_clone = handler.clone();
                     scoped.execute(move || {
-                        handle_connection(stream, &builder, acceptor, handler_clone)
+                        handle_connection(
+                            stream,
+                            state,
+                            acceptor,
+                            handler_clone,
+                            admitted,
+                            shutdown,
+                        );
+                        drop(busy);
                     });
                 }
                 Err(e) => error!("Connection failed: {}", e),
             }
         }
+        info!("{} SMTP ({}) stopping", name, state.label);
+        shutdown.wait_for_connections();
     });
-    Ok(())
 }
 
-fn handle_session<H, S>(session: &mut Session<H>, stream: &mut S) -> Result<SessionResult, Error>
+// Admit a connection if the listener has a free thread and the connection is within the
+// limits of the listener. Connections from a trusted proxy are checked against the limits
+// once the address of the client is known.
+fn admit<'a>(stream: &TcpStream, state: &'a ListenerState) -> Option<(Busy<'a>, Admitted)> {
+    let busy = Busy::new(&state.active, state.num_threads as usize)?;
+    let remote = peer_ip(stream);
+    if state.is_trusted_proxy(remote) {
+        return Some((busy, Admitted::Proxied));
+    }
+    let permit = state.admission.admit(remote)?;
+    Some((busy, Admitted::Direct(permit)))
+}
+
+// A connection that was admitted when it was accepted, or one from a trusted proxy that is
+// admitted after the PROXY protocol header is read
+enum Admitted {
+    Direct(Permit),
+    Proxied,
+}
+
+// The address of the other end of a connection
+pub(crate) fn peer_ip(stream: &TcpStream) -> IpAddr {
+    stream
+        .peer_addr()
+        .map(|saddr| saddr.ip())
+        .unwrap_or_else(|_| "0.0.0.0".parse().unwrap())
+}
+
+// Read the PROXY protocol header sent by a trusted proxy and admit the client it names.
+// Returns None if the header is invalid or the client is over a connection limit.
+fn admit_proxied(
+    mut stream: &TcpStream,
+    proxy: IpAddr,
+    state: &ListenerState,
+) -> Option<(IpAddr, Permit)> {
+    stream.set_read_timeout(Some(HEADER_TIMEOUT)).ok();
+    let remote = match HeaderReader::default().read(&mut stream) {
+        Ok(source) => source.unwrap_or(proxy),
+        Err(err) => {
+            debug!("({}) Invalid PROXY protocol header: {}", proxy, err);
+            return None;
+        }
+    };
+    let Some(permit) = state.admission.admit(remote) else {
+        reject(stream, state);
+        return None;
+    };
+    Some((remote, permit))
+}
+
+// Tell a client that is over a connection limit to try again later
+pub(crate) fn reject(mut stream: &TcpStream, state: &ListenerState) {
+    if let Ok(remote) = stream.peer_addr() {
+        debug!("Too many connections, rejecting {}", remote);
+    }
+    stream.set_write_timeout(Some(REJECT_TIMEOUT)).ok();
+    let res = state.session_builder.response(TOO_MANY_CONNECTIONS);
+    let _ = write_response(&mut stream, &res);
+}
+
+// Counts a busy thread of a listener until dropped
+struct Busy<'a>(&'a AtomicUsize);
+
+impl<'a> Busy<'a> {
+    // Returns None if all threads are busy
+    fn new(active: &'a AtomicUsize, num_threads: usize) -> Option<Self> {
+        if active.fetch_add(1, Ordering::SeqCst) >= num_threads {
+            active.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Self(active))
+    }
+}
+
+impl Drop for Busy<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn handle_session<H, S>(
+    session: &mut Session<H>,
+    stream: &mut S,
+    state: &ListenerState,
+    conn: &ConnectionGuard,
+    timer: &mut SessionTimer,
+) -> Result<SessionResult, Error>
 where
     S: BufRead + Write,
     H: Handler,
 {
     let mut line = Vec::with_capacity(80);
+    let mut framer = LineFramer::default();
     loop {
+        if conn.should_close() {
+            return close_with(stream, &session.response(NO_SERVICE));
+        }
+        if !timer.before_read(session.phase())? {
+            return close_with(stream, &session.response(TIMEOUT));
+        }
         line.clear();
-        let num_bytes = stream.read_until(b'\n', &mut line)?;
-        if num_bytes == 0 {
+        let read = match read_line(stream, &mut line, state.max_line_length) {
+            Ok(ReadLine::Eof) | Err(_) if conn.is_stopping() => {
+                return close_with(stream, &session.response(NO_SERVICE))
+            }
+            Err(e) if is_timeout(&e) => return close_with(stream, &session.response(TIMEOUT)),
+            res => res?,
+        };
+        if read == ReadLine::Eof {
             break;
         }
+        let partial = read == ReadLine::Partial;
+        match framer.frame(&mut line, partial, session.phase(), state.long_lines) {
+            Disposition::Process => (),
+            Disposition::ProcessAndSkip => skip_line(stream, timer.timeout())?,
+            Disposition::TooLong => {
+                write_response(stream, &session.response(LINE_TOO_LONG))?;
+                skip_line(stream, timer.timeout())?;
+                cancel_auth(session);
+                continue;
+            }
+            Disposition::Reject => return close_with(stream, &session.response(LINE_TOO_LONG)),
+            Disposition::Ignore => continue,
+        }
         let res = session.process(&line);
+        conn.set_receiving_message(session.is_receiving_message());
         match res.action {
             Action::Reply => {
                 write_response(stream, &res)?;
@@ -116,65 +369,187 @@ where
                 write_response(stream, &res)?;
                 return Ok(SessionResult::UpgradeTls);
             }
+            Action::ReverseRoles => {
+                write_response(stream, &res)?;
+                deliver_queued_mail(session, stream, state.max_line_length)?;
+                return Ok(SessionResult::Finished);
+            }
             Action::NoReply => (),
         }
     }
     Error::bail("Unexpected Eof")
 }
 
+// Tell the client that the session is closing because of a shutdown or a timeout
+fn close_with(stream: &mut dyn Write, res: &Response) -> Result<SessionResult, Error> {
+    // The client may already have gone
+    let _ = write_response(stream, res);
+    Ok(SessionResult::Finished)
+}
+
+// Act as a client and deliver queued mail after the roles of the session were reversed
+fn deliver_queued_mail<H, S>(
+    session: &mut Session<H>,
+    stream: &mut S,
+    max_line_length: usize,
+) -> Result<(), Error>
+where
+    S: BufRead + Write,
+    H: Handler,
+{
+    let mut client = session.reverse_roles();
+    let mut line = Vec::with_capacity(80);
+    while !client.is_closed() {
+        line.clear();
+        match read_line(stream, &mut line, max_line_length)? {
+            ReadLine::Eof => break,
+            ReadLine::Complete => (),
+            ReadLine::Partial => return Error::bail("Reply line too long"),
+        }
+        match client.process(&line) {
+            ClientAction::Send(buf) => {
+                stream.write_all(&buf)?;
+                stream.flush()?;
+            }
+            ClientAction::Wait => (),
+            ClientAction::UpgradeTls | ClientAction::Close => break,
+        }
+    }
+    session.reversed_outcomes(client.outcomes());
+    Ok(())
+}
+
 fn write_response(mut writer: &mut dyn Write, res: &Response) -> Result<(), Error> {
+    if let Some(delay) = res.delay {
+        thread::sleep(delay);
+    }
     res.write_to(&mut writer)?;
     writer
         .flush()
         .map_err(|e| Error::with_source("Cannot write response", e))
 }
 
-fn upgrade_tls(stream: TcpStream, ssl: Option<SslImpl>) -> Result<impl Stream, Error> {
-    if let Some(acceptor) = ssl {
-        let ret = acceptor.accept(stream)?;
-        Ok(ret)
-    } else {
-        Error::bail("Cannot upgrade to TLS without an SslAcceptor")
+// Negotiate TLS and tell the session whether the handshake succeeded
+fn upgrade_tls<H: Handler>(
+    session: &mut Session<H>,
+    stream: TcpStream,
+    ssl: Option<SslImpl>,
+) -> Result<impl Stream, Error> {
+    let Some(acceptor) = ssl else {
+        return Error::bail("Cannot upgrade to TLS without an SslAcceptor");
+    };
+    match acceptor.accept(stream) {
+        Ok(tls) => {
+            session.tls_started(&tls.tls_info());
+            Ok(tls)
+        }
+        Err(err) => {
+            session.tls_failed(err.failure);
+            Err(err.into())
+        }
     }
 }
 
+// Wait for the given delay and return true if the client sends data in this time
+fn is_early_talker(stream: &TcpStream, delay: Duration) -> Result<bool, Error> {
+    let previous = stream.read_timeout()?;
+    stream.set_read_timeout(Some(delay))?;
+    let mut buf = [0u8; 1];
+    let peeked = match stream.peek(&mut buf) {
+        Ok(num_bytes) => Ok(num_bytes > 0),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(false),
+        Err(e) => Err(Error::with_source("Cannot check for early talker", e)),
+    };
+    stream.set_read_timeout(previous)?;
+    peeked
+}
+
 fn start_session<H: Handler>(
-    session_builder: &SessionBuilder,
+    state: &ListenerState,
     remote: IpAddr,
     mut stream: BufStream<TcpStream>,
     ssl: Option<SslImpl>,
     handler: H,
+    conn: &ConnectionGuard,
+    timer: &mut SessionTimer,
 ) -> Result<(), Error> {
-    let mut session = session_builder.build(remote, handler);
+    let mut session = state.session_builder.build(remote, handler);
+    if let Some(delay) = state.greeting_delay {
+        if is_early_talker(stream.get_ref(), delay)? {
+            debug!("({}) Early talker", remote);
+            let res = session.early_talker();
+            if res.action == Action::Close {
+                write_response(&mut stream, &res)?;
+                return Ok(());
+            }
+        }
+    }
     write_response(&mut stream, &session.greeting())?;
-    let res = handle_session(&mut session, &mut stream)?;
+    let res = handle_session(&mut session, &mut stream, state, conn, timer)?;
     if let SessionResult::UpgradeTls = res {
         let inner_stream = stream
             .into_inner()
             .map_err(|e| Error::with_source("Cannot flush original TcpStream", e))?;
-        let tls = upgrade_tls(inner_stream, ssl)?;
-        session.tls_active();
+        let tls = upgrade_tls(&mut session, inner_stream, ssl)?;
         let mut buf_tls = BufStream::new(tls);
-        handle_session(&mut session, &mut buf_tls)?;
+        handle_session(&mut session, &mut buf_tls, state, conn, timer)?;
     }
     Ok(())
 }
 
-fn handle_connection<H: Handler>(
+// Start a session that negotiates TLS before the greeting is sent (RFC 8314)
+fn start_tls_session<H: Handler>(
+    state: &ListenerState,
+    remote: IpAddr,
     stream: TcpStream,
-    session_builder: &SessionBuilder,
     ssl: Option<SslImpl>,
     handler: H,
+    conn: &ConnectionGuard,
+    timer: &mut SessionTimer,
+) -> Result<(), Error> {
+    let mut session = state.session_builder.build(remote, handler);
+    let tls = upgrade_tls(&mut session, stream, ssl)?;
+    let mut buf_tls = BufStream::new(tls);
+    write_response(&mut buf_tls, &session.greeting())?;
+    handle_session(&mut session, &mut buf_tls, state, conn, timer)?;
+    Ok(())
+}
+
+fn handle_connection<H: Handler>(
+    stream: TcpStream,
+    state: &ListenerState,
+    ssl: Option<SslImpl>,
+    mut handler: H,
+    admitted: Admitted,
+    shutdown: &ShutdownHandle,
 ) {
-    let remote = stream
-        .peer_addr()
-        .map(|saddr| saddr.ip())
-        .unwrap_or_else(|_| "0.0.0.0".parse().unwrap());
-    debug!("New connection from {}", remote);
+    let peer = peer_ip(&stream);
+    let conn = shutdown.register(&stream);
+    let (remote, _permit) = match admitted {
+        Admitted::Direct(permit) => (peer, permit),
+        Admitted::Proxied => match admit_proxied(&stream, peer, state) {
+            Some(admitted) => admitted,
+            None => return,
+        },
+    };
+    debug!("New connection from {} on {}", remote, state.label);
     stream.set_read_timeout(Some(FIVE_MINUTES)).ok();
     stream.set_write_timeout(Some(FIVE_MINUTES)).ok();
-    let bufstream = BufStream::new(stream);
-    if let Err(err) = start_session(session_builder, remote, bufstream, ssl, handler) {
+    handler.listener(&state.label);
+    let mut timer = match SessionTimer::new(&state.timeouts, &stream) {
+        Ok(timer) => timer,
+        Err(err) => {
+            debug!("({}) Cannot set timeouts: {}", remote, err);
+            return;
+        }
+    };
+    let res = if state.implicit_tls {
+        start_tls_session(state, remote, stream, ssl, handler, &conn, &mut timer)
+    } else {
+        let bufstream = BufStream::new(stream);
+        start_session(state, remote, bufstream, ssl, handler, &conn, &mut timer)
+    };
+    if let Err(err) = res {
         debug!("({}) Cannot start session: {}", remote, err);
     }
 }
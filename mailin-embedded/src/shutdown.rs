@@ -0,0 +1,183 @@
+use log::debug;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often to check whether the connections have closed during a shutdown
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// `ShutdownHandle` stops a running server.
+///
+/// A handle is obtained from `Server::shutdown_handle` before the server is started.
+/// ```no_run
+/// # use mailin_embedded::{Handler, Server};
+/// # use std::thread;
+/// # use std::time::Duration;
+/// # #[derive(Clone)]
+/// # struct EmptyHandler {}
+/// # impl Handler for EmptyHandler {}
+/// let mut server = Server::new(EmptyHandler {});
+/// server.with_addr("127.0.0.1:25").unwrap();
+/// let shutdown = server.shutdown_handle();
+/// let running = thread::spawn(move || server.serve());
+/// // Later
+/// shutdown.shutdown(Duration::from_secs(30));
+/// running.join().unwrap();
+/// ```
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    stopping: AtomicBool,
+    deadline: Mutex<Option<Instant>>,
+    listeners: Mutex<Vec<SocketAddr>>,
+    connections: Mutex<HashMap<u64, Connection>>,
+    next_id: AtomicU64,
+}
+
+struct Connection {
+    stream: TcpStream,
+    receiving_message: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    /// Stop the server.
+    ///
+    /// The server stops accepting connections and idle sessions are sent `421` and closed.
+    /// Sessions that are receiving a message can finish the transaction within the grace
+    /// period, after which they are closed as well. `Server::serve` returns when all sessions
+    /// have closed.
+    pub fn shutdown(&self, grace: Duration) {
+        self.stop_sessions(grace);
+        // Wake the accept loops with a connection
+        for addr in self.inner.listeners.lock().unwrap().iter() {
+            let _ = TcpStream::connect(wake_address(*addr));
+        }
+    }
+
+    // Start a shutdown without waking the accept loops, for servers that stop accepting by
+    // other means. This does not block.
+    pub(crate) fn stop_sessions(&self, grace: Duration) {
+        *self.inner.deadline.lock().unwrap() = Some(Instant::now() + grace);
+        self.inner.stopping.store(true, Ordering::SeqCst);
+        // Idle sessions are blocked reading from the client
+        for conn in self.inner.connections.lock().unwrap().values() {
+            if !conn.receiving_message.load(Ordering::SeqCst) {
+                let _ = conn.stream.shutdown(Shutdown::Read);
+            }
+        }
+    }
+
+    /// True if the server has been asked to stop
+    pub fn is_stopping(&self) -> bool {
+        self.inner.stopping.load(Ordering::SeqCst)
+    }
+
+    // True if the grace period of a shutdown is over
+    fn is_past_deadline(&self) -> bool {
+        self.inner
+            .deadline
+            .lock()
+            .unwrap()
+            .map(|deadline| Instant::now() >= deadline)
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn add_listener(&self, addr: SocketAddr) {
+        self.inner.listeners.lock().unwrap().push(addr);
+    }
+
+    pub(crate) fn register(&self, stream: &TcpStream) -> ConnectionGuard {
+        let id = self.inner.next_id.fetch_add(1, Ordering::SeqCst);
+        let receiving_message = Arc::new(AtomicBool::new(false));
+        if let Ok(stream) = stream.try_clone() {
+            let conn = Connection {
+                stream,
+                receiving_message: receiving_message.clone(),
+            };
+            self.inner.connections.lock().unwrap().insert(id, conn);
+        }
+        ConnectionGuard {
+            id,
+            handle: self.clone(),
+            receiving_message,
+        }
+    }
+
+    // Wait for the sessions to close, closing the remaining sessions after the deadline
+    pub(crate) fn wait_for_connections(&self) {
+        loop {
+            let connections = self.inner.connections.lock().unwrap();
+            if connections.is_empty() {
+                break;
+            }
+            if self.is_past_deadline() {
+                debug!("Closing {} sessions after shutdown", connections.len());
+                for conn in connections.values() {
+                    let _ = conn.stream.shutdown(Shutdown::Both);
+                }
+            }
+            drop(connections);
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+// Tracks a connection during a shutdown, the connection is forgotten when this is dropped
+pub(crate) struct ConnectionGuard {
+    id: u64,
+    handle: ShutdownHandle,
+    receiving_message: Arc<AtomicBool>,
+}
+
+impl ConnectionGuard {
+    pub fn set_receiving_message(&self, receiving: bool) {
+        self.receiving_message.store(receiving, Ordering::SeqCst);
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        self.handle.is_stopping()
+    }
+
+    // True if the grace period of a shutdown is over
+    #[cfg(feature = "mio")]
+    pub fn is_past_deadline(&self) -> bool {
+        self.handle.is_past_deadline()
+    }
+
+    // True if the session should be closed instead of reading the next line
+    pub fn should_close(&self) -> bool {
+        self.handle.is_stopping()
+            && (!self.receiving_message.load(Ordering::SeqCst) || self.handle.is_past_deadline())
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.handle
+            .inner
+            .connections
+            .lock()
+            .unwrap()
+            .remove(&self.id);
+    }
+}
+
+// The address to connect to in order to reach a listener
+fn wake_address(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), addr.port())
+        }
+        _ => addr,
+    }
+}
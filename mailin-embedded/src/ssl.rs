@@ -1,6 +1,13 @@
-use std::io::{Read, Write};
+use crate::err::Error;
+use mailin::{TlsFailure, TlsInfo};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 
 /// `SslConfig` is used to configure the STARTTLS configuration of the server
+#[derive(Clone)]
 pub enum SslConfig {
     /// Do not support STARTTLS
     None,
@@ -20,6 +27,315 @@ pub enum SslConfig {
         /// Path to CA bundle
         chain_path: String,
     },
+    /// Select the certificate with the server name sent by the client (SNI)
+    ///
+    /// Server names can start with a `*.` wildcard label. Clients that send no server name
+    /// or an unknown server name are given the default certificate.
+    Sni {
+        /// The certificate used when no server name matches, `SelfSigned` or `Trusted`
+        default: Box<SslConfig>,
+        /// Server names and their certificates, `SelfSigned` or `Trusted`
+        certificates: Vec<(String, SslConfig)>,
+    },
+    /// Select the certificate with SNI from a directory that has a subdirectory for each
+    /// server name.
+    ///
+    /// Each subdirectory contains `cert.pem`, `privkey.pem` and optionally `chain.pem`,
+    /// which is the layout of the certbot `live` directory. Subdirectories without a
+    /// `cert.pem` and `privkey.pem` are ignored.
+    SniDirectory {
+        /// Path to the directory
+        path: String,
+        /// The subdirectory with the certificate used when no server name matches
+        default: String,
+    },
+}
+
+pub trait Stream: Read + Write {
+    // The details of the TLS session, once the handshake is complete
+    fn tls_info(&self) -> TlsInfo;
+
+    // True until the handshake is complete
+    #[cfg(feature = "mio")]
+    fn is_handshaking(&self) -> bool;
+}
+
+// A TLS handshake that failed
+pub(crate) struct HandshakeError {
+    pub failure: TlsFailure,
+    pub error: Error,
+}
+
+impl From<HandshakeError> for Error {
+    fn from(err: HandshakeError) -> Self {
+        err.error
+    }
+}
+
+// Classify a handshake failure caused by an I/O error
+pub(crate) fn io_failure(err: &io::Error) -> TlsFailure {
+    match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => TlsFailure::Timeout,
+        ErrorKind::UnexpectedEof
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::BrokenPipe => TlsFailure::ClientAbort,
+        _ => TlsFailure::Other,
+    }
+}
+
+// Builds a TLS configuration, None if STARTTLS is not supported
+type Build<T> = fn(SslConfig) -> Result<Option<T>, Error>;
+
+// A TLS configuration that is rebuilt from its SslConfig when the certificates are reloaded
+pub(crate) struct Reloadable<T> {
+    // None if the configuration was built by the caller
+    source: Option<(SslConfig, Build<T>)>,
+    current: RwLock<Arc<T>>,
+}
+
+// Reloads the certificates of a TLS configuration
+pub(crate) trait Reload: Send + Sync {
+    fn reload(&self) -> Result<(), Error>;
+
+    // The files and directories the certificates are loaded from
+    fn watched_paths(&self) -> Vec<PathBuf>;
+}
+
+// The files of a certificate
+#[derive(Clone)]
+pub(crate) struct CertFiles {
+    pub cert_path: String,
+    pub key_path: String,
+    pub chain_path: Option<String>,
+}
+
+// The certificates of a server
+pub(crate) struct CertSet {
+    pub default: CertFiles,
+    pub named: ServerNames<CertFiles>,
+}
+
+// Values indexed by server name
+#[derive(Debug)]
+pub(crate) struct ServerNames<T>(HashMap<String, T>);
+
+impl SslConfig {
+    // The certificates to load, None if STARTTLS is not supported
+    pub(crate) fn cert_set(self) -> Result<Option<CertSet>, Error> {
+        let ret = match self {
+            SslConfig::None => None,
+            SslConfig::Sni {
+                default,
+                certificates,
+            } => {
+                let mut named = ServerNames::default();
+                for (name, config) in certificates {
+                    named.insert(&name, config.cert_files()?);
+                }
+                Some(CertSet {
+                    default: default.cert_files()?,
+                    named,
+                })
+            }
+            SslConfig::SniDirectory { path, default } => Some(read_cert_dir(&path, &default)?),
+            config => Some(CertSet {
+                default: config.cert_files()?,
+                named: ServerNames::default(),
+            }),
+        };
+        Ok(ret)
+    }
+
+    // The files of a single certificate
+    fn cert_files(self) -> Result<CertFiles, Error> {
+        match self {
+            SslConfig::SelfSigned {
+                cert_path,
+                key_path,
+            } => Ok(CertFiles {
+                cert_path,
+                key_path,
+                chain_path: None,
+            }),
+            SslConfig::Trusted {
+                cert_path,
+                key_path,
+                chain_path,
+            } => Ok(CertFiles {
+                cert_path,
+                key_path,
+                chain_path: Some(chain_path),
+            }),
+            _ => Error::bail("SNI certificates must be SelfSigned or Trusted"),
+        }
+    }
+}
+
+impl<T> Reloadable<T> {
+    // Build the TLS configuration, None if STARTTLS is not supported
+    pub fn setup(ssl_config: SslConfig, build: Build<T>) -> Result<Option<Self>, Error> {
+        let ret = build(ssl_config.clone())?.map(|config| Self {
+            source: Some((ssl_config, build)),
+            current: RwLock::new(Arc::new(config)),
+        });
+        Ok(ret)
+    }
+
+    // Use a configuration built by the caller, which cannot be reloaded
+    pub fn prebuilt(config: Arc<T>) -> Self {
+        Self {
+            source: None,
+            current: RwLock::new(config),
+        }
+    }
+
+    // The configuration to use for a new TLS session
+    pub fn current(&self) -> Arc<T> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+impl<T: Send + Sync> Reload for Reloadable<T> {
+    fn reload(&self) -> Result<(), Error> {
+        let Some((ssl_config, build)) = &self.source else {
+            return Error::bail("Cannot reload a TLS configuration that was not built from files");
+        };
+        let Some(config) = build(ssl_config.clone())? else {
+            return Error::bail("No certificates to reload");
+        };
+        *self.current.write().unwrap() = Arc::new(config);
+        Ok(())
+    }
+
+    fn watched_paths(&self) -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+        let Some((ssl_config, _)) = &self.source else {
+            return paths;
+        };
+        if let SslConfig::SniDirectory { path, .. } = ssl_config {
+            paths.push(PathBuf::from(path));
+        }
+        if let Ok(Some(cert_set)) = ssl_config.clone().cert_set() {
+            let named = cert_set.named.0.into_values();
+            for files in std::iter::once(cert_set.default).chain(named) {
+                paths.push(files.cert_path.into());
+                paths.push(files.key_path.into());
+                paths.extend(files.chain_path.map(PathBuf::from));
+            }
+        }
+        // Server names are not kept in order
+        paths.sort();
+        paths
+    }
+}
+
+impl<T> Default for ServerNames<T> {
+    fn default() -> Self {
+        Self(HashMap::new())
+    }
+}
+
+impl<T> ServerNames<T> {
+    pub fn insert(&mut self, name: &str, value: T) {
+        self.0.insert(normalise(name), value);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Find the value for a server name, falling back to a wildcard for the parent domain
+    pub fn find(&self, name: &str) -> Option<&T> {
+        let name = normalise(name);
+        self.0.get(&name).or_else(|| {
+            let (_, parent) = name.split_once('.')?;
+            self.0.get(&format!("*.{}", parent))
+        })
+    }
+
+    pub fn try_map<U, F>(self, mut f: F) -> Result<ServerNames<U>, Error>
+    where
+        F: FnMut(T) -> Result<U, Error>,
+    {
+        let mut ret = HashMap::with_capacity(self.0.len());
+        for (name, value) in self.0 {
+            ret.insert(name, f(value)?);
+        }
+        Ok(ServerNames(ret))
+    }
+}
+
+fn normalise(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+// Read the certificates in a directory with a subdirectory per server name
+fn read_cert_dir(path: &str, default: &str) -> Result<CertSet, Error> {
+    let entries =
+        fs::read_dir(path).map_err(|e| Error::with_source(format!("Cannot read {}", path), e))?;
+    let mut named = ServerNames::default();
+    let mut default_files = None;
+    for entry in entries {
+        let dir = entry?.path();
+        let Some(files) = dir_cert_files(&dir) else {
+            continue;
+        };
+        let Some(name) = dir.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if name == default {
+            default_files = Some(files.clone());
+        }
+        named.insert(name, files);
+    }
+    let Some(default) = default_files else {
+        return Error::bail(format!("No certificate for {} in {}", default, path));
+    };
+    Ok(CertSet { default, named })
+}
+
+// The certificate files in a directory, None if the directory has no certificate
+fn dir_cert_files(dir: &Path) -> Option<CertFiles> {
+    let file = |name: &str| {
+        let path = dir.join(name);
+        path.is_file().then(|| path.to_string_lossy().into_owned())
+    };
+    Some(CertFiles {
+        cert_path: file("cert.pem")?,
+        key_path: file("privkey.pem")?,
+        chain_path: file("chain.pem"),
+    })
 }
 
-pub trait Stream: Read + Write {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_names() {
+        let mut names = ServerNames::default();
+        assert!(names.is_empty());
+        names.insert("mail.example.org.", 1);
+        names.insert("*.Example.com", 2);
+        assert!(!names.is_empty());
+        assert_eq!(names.find("mail.example.org"), Some(&1));
+        assert_eq!(names.find("MAIL.EXAMPLE.ORG."), Some(&1));
+        assert_eq!(names.find("other.example.org"), None);
+        // Wildcards match a single label
+        assert_eq!(names.find("mail.example.com"), Some(&2));
+        assert_eq!(names.find("example.com"), None);
+        assert_eq!(names.find("a.mail.example.com"), None);
+    }
+
+    #[test]
+    fn cert_dir() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/sni");
+        let cert_set = read_cert_dir(path, "localhost").unwrap();
+        assert!(cert_set.default.cert_path.ends_with("localhost/cert.pem"));
+        let named = cert_set.named.find("mail.example.org").unwrap();
+        assert!(named.key_path.ends_with("mail.example.org/privkey.pem"));
+        assert_eq!(named.chain_path, None);
+        assert!(read_cert_dir(path, "missing.example.org").is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use mailin::Phase;
+use std::io;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// `Timeouts` sets how long the server waits for a client in each phase of a session.
+///
+/// The defaults follow RFC 5321 section 4.5.3.2. When a timeout expires the server sends a
+/// `421` reply and closes the connection.
+/// ```
+/// # use mailin_embedded::Timeouts;
+/// # use std::time::Duration;
+/// let timeouts = Timeouts {
+///     session: Some(Duration::from_secs(30 * 60)),
+///     ..Timeouts::default()
+/// };
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Waiting for HELO or EHLO after the greeting was sent, 5 minutes by default
+    pub greeting: Duration,
+    /// Waiting for MAIL or another command outside of a transaction, 5 minutes by default
+    pub mail: Duration,
+    /// Waiting for RCPT or DATA, 5 minutes by default
+    pub rcpt: Duration,
+    /// Waiting for the first line of a message after DATA, 2 minutes by default
+    pub data_init: Duration,
+    /// Waiting for each following line of a message, 3 minutes by default
+    pub data_block: Duration,
+    /// Receiving a complete message up to the final dot, 10 minutes by default
+    pub data_end: Duration,
+    /// The lifetime of a session, unlimited by default
+    pub session: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            greeting: 5 * MINUTE,
+            mail: 5 * MINUTE,
+            rcpt: 5 * MINUTE,
+            data_init: 2 * MINUTE,
+            data_block: 3 * MINUTE,
+            data_end: 10 * MINUTE,
+            session: None,
+        }
+    }
+}
+
+// Works out how long to wait for the next line of a session
+pub(crate) struct PhaseClock {
+    timeouts: Timeouts,
+    started: Instant,
+    data_started: Option<Instant>,
+}
+
+impl PhaseClock {
+    pub fn new(timeouts: &Timeouts) -> Self {
+        Self {
+            timeouts: timeouts.clone(),
+            started: Instant::now(),
+            data_started: None,
+        }
+    }
+
+    // The time to wait for the next line of a session in the given phase.
+    // Returns zero if the session has no time left.
+    pub fn next_timeout(&mut self, phase: Phase) -> Duration {
+        let now = Instant::now();
+        let timeout = match phase {
+            Phase::Data => match self.data_started {
+                Some(started) => {
+                    let remaining = remaining(started, self.timeouts.data_end, now);
+                    self.timeouts.data_block.min(remaining)
+                }
+                None => {
+                    self.data_started = Some(now);
+                    self.timeouts.data_init
+                }
+            },
+            Phase::Greeting => self.timeouts.greeting,
+            Phase::Rcpt => self.timeouts.rcpt,
+            Phase::Mail | Phase::Auth | Phase::Closed => self.timeouts.mail,
+        };
+        if phase != Phase::Data {
+            self.data_started = None;
+        }
+        match self.timeouts.session {
+            Some(lifetime) => timeout.min(remaining(self.started, lifetime, now)),
+            None => timeout,
+        }
+    }
+}
+
+// Sets the read timeout of a connection before each line is read
+pub(crate) struct SessionTimer {
+    clock: PhaseClock,
+    socket: TcpStream,
+    timeout: Duration,
+}
+
+impl SessionTimer {
+    pub fn new(timeouts: &Timeouts, socket: &TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            clock: PhaseClock::new(timeouts),
+            socket: socket.try_clone()?,
+            timeout: Duration::ZERO,
+        })
+    }
+
+    // Set the read timeout for the next line of a session in the given phase.
+    // Returns false if the session has no time left.
+    pub fn before_read(&mut self, phase: Phase) -> io::Result<bool> {
+        let timeout = self.clock.next_timeout(phase);
+        if timeout.is_zero() {
+            return Ok(false);
+        }
+        self.socket.set_read_timeout(Some(timeout))?;
+        self.timeout = timeout;
+        Ok(true)
+    }
+
+    // The time allowed for the line that is being read
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
+
+// Returns true if the error is caused by a read timeout
+pub(crate) fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+fn remaining(started: Instant, limit: Duration, now: Instant) -> Duration {
+    limit.saturating_sub(now.duration_since(started))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timeouts() -> Timeouts {
+        Timeouts {
+            greeting: Duration::from_secs(1),
+            mail: Duration::from_secs(2),
+            rcpt: Duration::from_secs(3),
+            data_init: Duration::from_secs(4),
+            data_block: Duration::from_secs(5),
+            data_end: Duration::from_secs(60),
+            session: None,
+        }
+    }
+
+    #[test]
+    fn phases() {
+        let mut clock = PhaseClock::new(&timeouts());
+        assert_eq!(clock.next_timeout(Phase::Greeting), Duration::from_secs(1));
+        assert_eq!(clock.next_timeout(Phase::Mail), Duration::from_secs(2));
+        assert_eq!(clock.next_timeout(Phase::Auth), Duration::from_secs(2));
+        assert_eq!(clock.next_timeout(Phase::Rcpt), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn data() {
+        let mut clock = PhaseClock::new(&Timeouts {
+            data_end: Duration::from_millis(100),
+            ..timeouts()
+        });
+        assert_eq!(clock.next_timeout(Phase::Data), Duration::from_secs(4));
+        // Later lines are limited by the time left for the message
+        let block = clock.next_timeout(Phase::Data);
+        assert!(block <= Duration::from_millis(100));
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(clock.next_timeout(Phase::Data).is_zero());
+        // The next message starts again
+        clock.next_timeout(Phase::Mail);
+        assert_eq!(clock.next_timeout(Phase::Data), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn session_lifetime() {
+        let mut clock = PhaseClock::new(&Timeouts {
+            session: Some(Duration::from_millis(100)),
+            ..timeouts()
+        });
+        assert!(clock.next_timeout(Phase::Mail) <= Duration::from_millis(100));
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(clock.next_timeout(Phase::Mail).is_zero());
+    }
+}
@@ -0,0 +1,462 @@
+use crate::err::Error;
+use crate::limits::Permit;
+use crate::line::{cancel_auth, Disposition, LineFramer, ReadLine};
+use crate::proxy::{HeaderReader, HEADER_TIMEOUT};
+use crate::rtls::{connection_info, SslImpl};
+use crate::running::{open_listeners, reject, ListenerState};
+use crate::shutdown::{ConnectionGuard, ShutdownHandle};
+use crate::timeouts::PhaseClock;
+use crate::Server;
+use log::{debug, error, info};
+use mailin::client::ClientAction;
+use mailin::response::{LINE_TOO_LONG, NO_SERVICE, TIMEOUT, TOO_MANY_CONNECTIONS};
+use mailin::{Action, Handler, Response, Session, TlsFailure};
+use std::io;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+
+const FIVE_MINUTES: Duration = Duration::new(5 * 60, 0);
+
+enum SessionResult {
+    Finished,
+    UpgradeTls,
+}
+
+// Run the listeners of a server on the current tokio runtime until the token is cancelled
+// or the server is stopped with a ShutdownHandle
+pub(crate) async fn serve<H>(config: Server<H>, cancel: CancellationToken) -> Result<(), Error>
+where
+    H: Handler + Clone + Send + 'static,
+{
+    let has_ssl = config.ssl.is_some();
+    let states = open_listeners(
+        config.listener,
+        config.listeners,
+        &config.name,
+        has_ssl,
+        config.limits,
+    )?;
+    let shutdown = config.shutdown;
+    let mut accepting = JoinSet::new();
+    for state in states {
+        shutdown.add_listener(state.local_addr);
+        let listener = state.listener.try_clone()?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        info!(
+            "{} SMTP ({}) started on {}",
+            config.name, state.label, state.local_addr
+        );
+        let accept = Accept {
+            listener,
+            state: Arc::new(state),
+            handler: config.handler.clone(),
+            ssl: config.ssl.clone(),
+            shutdown: shutdown.clone(),
+            shutdown_grace: config.shutdown_grace,
+        };
+        accepting.spawn(accept.run(config.name.clone(), cancel.clone()));
+    }
+    while accepting.join_next().await.is_some() {}
+    tokio::task::spawn_blocking(move || shutdown.wait_for_connections())
+        .await
+        .map_err(|e| Error::with_source("Cannot wait for sessions to close", e))
+}
+
+// Accepts the connections of a listener
+struct Accept<H> {
+    listener: TcpListener,
+    state: Arc<ListenerState>,
+    handler: H,
+    ssl: Option<SslImpl>,
+    shutdown: ShutdownHandle,
+    // How long sessions that are receiving a message can continue after a cancellation
+    shutdown_grace: Duration,
+}
+
+impl<H> Accept<H>
+where
+    H: Handler + Clone + Send + 'static,
+{
+    async fn run(self, name: String, cancel: CancellationToken) {
+        // A shutdown before the listener was added to the handle cannot wake the accept
+        while !self.shutdown.is_stopping() {
+            let accepted = tokio::select! {
+                accepted = self.listener.accept() => accepted,
+                _ = cancel.cancelled() => {
+                    // Every accept loop sees the cancellation, so none need waking
+                    if !self.shutdown.is_stopping() {
+                        self.shutdown.stop_sessions(self.shutdown_grace);
+                    }
+                    break;
+                }
+            };
+            if self.shutdown.is_stopping() {
+                break;
+            }
+            match accepted {
+                Ok((stream, remote)) => self.spawn_session(stream, remote.ip()),
+                Err(e) => error!("Connection failed: {}", e),
+            }
+        }
+        info!("{} SMTP ({}) stopping", name, self.state.label);
+    }
+
+    fn spawn_session(&self, stream: TcpStream, remote: IpAddr) {
+        // The shutdown handle and the reject path work with std sockets
+        let stream = match stream.into_std() {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Connection failed: {}", e);
+                return;
+            }
+        };
+        let permit = if self.state.is_trusted_proxy(remote) {
+            None
+        } else {
+            let Some(permit) = self.state.admission.admit(remote) else {
+                reject(&stream, &self.state);
+                return;
+            };
+            Some(permit)
+        };
+        let conn = self.shutdown.register(&stream);
+        let mut stream = match TcpStream::from_std(stream) {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Connection failed: {}", e);
+                return;
+            }
+        };
+        let state = self.state.clone();
+        let ssl = self.ssl.clone();
+        let handler = self.handler.clone();
+        tokio::spawn(async move {
+            let (remote, permit) = match permit {
+                Some(permit) => (remote, permit),
+                None => match admit_proxied(&mut stream, remote, &state).await {
+                    Some(admitted) => admitted,
+                    None => return,
+                },
+            };
+            let res = handle_connection(stream, remote, &state, ssl, handler, &conn).await;
+            if let Err(err) = res {
+                debug!("({}) Cannot start session: {}", remote, err);
+            }
+            drop(permit);
+        });
+    }
+}
+
+// Read the PROXY protocol header sent by a trusted proxy and admit the client it names.
+// Returns None if the header is invalid or the client is over a connection limit.
+async fn admit_proxied(
+    stream: &mut TcpStream,
+    proxy: IpAddr,
+    state: &ListenerState,
+) -> Option<(IpAddr, Permit)> {
+    let remote = match timeout(HEADER_TIMEOUT, HeaderReader::default().read_async(stream)).await {
+        Ok(Ok(source)) => source.unwrap_or(proxy),
+        Ok(Err(err)) => {
+            debug!("({}) Invalid PROXY protocol header: {}", proxy, err);
+            return None;
+        }
+        Err(_) => {
+            debug!("({}) Timeout reading PROXY protocol header", proxy);
+            return None;
+        }
+    };
+    let Some(permit) = state.admission.admit(remote) else {
+        debug!("Too many connections, rejecting {}", remote);
+        let res = state.session_builder.response(TOO_MANY_CONNECTIONS);
+        let _ = write_response(stream, &res).await;
+        return None;
+    };
+    Some((remote, permit))
+}
+
+async fn handle_connection<H: Handler>(
+    stream: TcpStream,
+    remote: IpAddr,
+    state: &ListenerState,
+    ssl: Option<SslImpl>,
+    mut handler: H,
+    conn: &ConnectionGuard,
+) -> Result<(), Error> {
+    debug!("New connection from {} on {}", remote, state.label);
+    handler.listener(&state.label);
+    let mut session = state.session_builder.build(remote, handler);
+    let mut clock = PhaseClock::new(&state.timeouts);
+    if state.implicit_tls {
+        let mut stream = BufReader::new(upgrade_tls(&mut session, stream, ssl).await?);
+        write_response(&mut stream, &session.greeting()).await?;
+        handle_session(&mut session, &mut stream, state, conn, &mut clock).await?;
+        return Ok(());
+    }
+    if let Some(delay) = state.greeting_delay {
+        if is_early_talker(&stream, delay).await? {
+            debug!("({}) Early talker", remote);
+            let res = session.early_talker();
+            if res.action == Action::Close {
+                let mut stream = stream;
+                write_response(&mut stream, &res).await?;
+                return Ok(());
+            }
+        }
+    }
+    let mut stream = BufReader::new(stream);
+    write_response(&mut stream, &session.greeting()).await?;
+    let res = handle_session(&mut session, &mut stream, state, conn, &mut clock).await?;
+    if let SessionResult::UpgradeTls = res {
+        // Input pipelined after STARTTLS is discarded with the buffer
+        let tls = upgrade_tls(&mut session, stream.into_inner(), ssl).await?;
+        let mut stream = BufReader::new(tls);
+        handle_session(&mut session, &mut stream, state, conn, &mut clock).await?;
+    }
+    Ok(())
+}
+
+async fn handle_session<H, S>(
+    session: &mut Session<H>,
+    stream: &mut BufReader<S>,
+    state: &ListenerState,
+    conn: &ConnectionGuard,
+    clock: &mut PhaseClock,
+) -> Result<SessionResult, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    H: Handler,
+{
+    let mut line = Vec::with_capacity(80);
+    let mut framer = LineFramer::default();
+    loop {
+        if conn.should_close() {
+            return close_with(stream, &session.response(NO_SERVICE)).await;
+        }
+        let wait = clock.next_timeout(session.phase());
+        if wait.is_zero() {
+            return close_with(stream, &session.response(TIMEOUT)).await;
+        }
+        line.clear();
+        let read = match timeout(wait, read_line(stream, &mut line, state.max_line_length)).await {
+            Err(_) => return close_with(stream, &session.response(TIMEOUT)).await,
+            Ok(Ok(ReadLine::Eof) | Err(_)) if conn.is_stopping() => {
+                return close_with(stream, &session.response(NO_SERVICE)).await
+            }
+            Ok(res) => res?,
+        };
+        if read == ReadLine::Eof {
+            break;
+        }
+        let partial = read == ReadLine::Partial;
+        match framer.frame(&mut line, partial, session.phase(), state.long_lines) {
+            Disposition::Process => (),
+            Disposition::ProcessAndSkip => skip_line(stream, wait).await?,
+            Disposition::TooLong => {
+                write_response(stream, &session.response(LINE_TOO_LONG)).await?;
+                skip_line(stream, wait).await?;
+                cancel_auth(session);
+                continue;
+            }
+            Disposition::Reject => {
+                return close_with(stream, &session.response(LINE_TOO_LONG)).await
+            }
+            Disposition::Ignore => continue,
+        }
+        let res = session.process(&line);
+        conn.set_receiving_message(session.is_receiving_message());
+        match res.action {
+            Action::Reply => {
+                write_response(stream, &res).await?;
+            }
+            Action::Close => {
+                write_response(stream, &res).await?;
+                if res.is_error {
+                    return Error::bail("SMTP error");
+                } else {
+                    return Ok(SessionResult::Finished);
+                }
+            }
+            Action::UpgradeTls => {
+                write_response(stream, &res).await?;
+                return Ok(SessionResult::UpgradeTls);
+            }
+            Action::ReverseRoles => {
+                write_response(stream, &res).await?;
+                deliver_queued_mail(session, stream, state.max_line_length).await?;
+                return Ok(SessionResult::Finished);
+            }
+            Action::NoReply => (),
+        }
+    }
+    Error::bail("Unexpected Eof")
+}
+
+// Tell the client that the session is closing because of a shutdown or a timeout
+async fn close_with<W>(stream: &mut W, res: &Response) -> Result<SessionResult, Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    // The client may already have gone
+    let _ = write_response(stream, res).await;
+    Ok(SessionResult::Finished)
+}
+
+// Act as a client and deliver queued mail after the roles of the session were reversed
+async fn deliver_queued_mail<H, S>(
+    session: &mut Session<H>,
+    stream: &mut BufReader<S>,
+    max_line_length: usize,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    H: Handler,
+{
+    let mut client = session.reverse_roles();
+    let mut line = Vec::with_capacity(80);
+    while !client.is_closed() {
+        line.clear();
+        let read = timeout(FIVE_MINUTES, read_line(stream, &mut line, max_line_length))
+            .await
+            .map_err(|_| Error::new("Timeout waiting for a reply"))??;
+        match read {
+            ReadLine::Eof => break,
+            ReadLine::Complete => (),
+            ReadLine::Partial => return Error::bail("Reply line too long"),
+        }
+        match client.process(&line) {
+            ClientAction::Send(buf) => write_all(stream, &buf).await?,
+            ClientAction::Wait => (),
+            ClientAction::UpgradeTls | ClientAction::Close => break,
+        }
+    }
+    session.reversed_outcomes(client.outcomes());
+    Ok(())
+}
+
+async fn write_response<W>(writer: &mut W, res: &Response) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    if let Some(delay) = res.delay {
+        sleep(delay).await;
+    }
+    write_all(writer, &res.buffer()?).await
+}
+
+async fn write_all<W>(writer: &mut W, buf: &[u8]) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let write = async {
+        writer.write_all(buf).await?;
+        writer.flush().await
+    };
+    timeout(FIVE_MINUTES, write)
+        .await
+        .map_err(|_| Error::new("Timeout writing response"))?
+        .map_err(|e| Error::with_source("Cannot write response", e))
+}
+
+// Negotiate TLS and tell the session whether the handshake succeeded
+async fn upgrade_tls<H: Handler>(
+    session: &mut Session<H>,
+    stream: TcpStream,
+    ssl: Option<SslImpl>,
+) -> Result<tokio_rustls::server::TlsStream<TcpStream>, Error> {
+    let Some(ssl) = ssl else {
+        return Error::bail("Cannot upgrade to TLS without an SslAcceptor");
+    };
+    // The configuration is fetched for each session to pick up reloaded certificates
+    let acceptor = TlsAcceptor::from(ssl.server_config());
+    match timeout(FIVE_MINUTES, acceptor.accept(stream)).await {
+        Ok(Ok(tls)) => {
+            session.tls_started(&connection_info(tls.get_ref().1));
+            Ok(tls)
+        }
+        Ok(Err(err)) => {
+            session.tls_failed(SslImpl::failure(&err));
+            Err(err.into())
+        }
+        Err(_) => {
+            session.tls_failed(TlsFailure::Timeout);
+            Error::bail("Timeout during TLS handshake")
+        }
+    }
+}
+
+// Wait for the given delay and return true if the client sends data in this time
+async fn is_early_talker(stream: &TcpStream, delay: Duration) -> Result<bool, Error> {
+    let mut buf = [0u8; 1];
+    match timeout(delay, stream.peek(&mut buf)).await {
+        Err(_) => Ok(false),
+        Ok(Ok(num_bytes)) => Ok(num_bytes > 0),
+        Ok(Err(e)) => Err(Error::with_source("Cannot check for early talker", e)),
+    }
+}
+
+// Read a line into the given buffer, reading no more than max bytes
+async fn read_line<R>(reader: &mut R, buf: &mut Vec<u8>, max: usize) -> io::Result<ReadLine>
+where
+    R: AsyncBufRead + Unpin,
+{
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(if buf.is_empty() {
+                ReadLine::Eof
+            } else {
+                ReadLine::Complete
+            });
+        }
+        let wanted = available.len().min(max - buf.len());
+        let (used, done) = match available[..wanted].iter().position(|b| *b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (wanted, false),
+        };
+        buf.extend_from_slice(&available[..used]);
+        reader.consume(used);
+        if done {
+            return Ok(ReadLine::Complete);
+        }
+        if buf.len() >= max {
+            return Ok(ReadLine::Partial);
+        }
+    }
+}
+
+// Discard input up to and including the end of the current line
+async fn skip_line<R>(reader: &mut R, wait: Duration) -> Result<(), Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let skip = async {
+        loop {
+            let available = reader.fill_buf().await?;
+            if available.is_empty() {
+                return Ok::<_, io::Error>(());
+            }
+            match available.iter().position(|b| *b == b'\n') {
+                Some(i) => {
+                    reader.consume(i + 1);
+                    return Ok(());
+                }
+                None => {
+                    let len = available.len();
+                    reader.consume(len);
+                }
+            }
+        }
+    };
+    timeout(wait, skip)
+        .await
+        .map_err(|_| Error::new("Timeout discarding a long line"))??;
+    Ok(())
+}
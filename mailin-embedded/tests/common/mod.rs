@@ -0,0 +1,239 @@
+// Shared by the integration tests: a server on each engine, a handler that records what it
+// is told and SMTP clients. Each test binary uses a different part of this module.
+#![allow(dead_code)]
+
+use mailin::{TlsFailure, TlsInfo};
+pub use mailin_dialogue::read_reply;
+use mailin_embedded::response::{NO_MAILBOX, OK};
+use mailin_embedded::{Handler, Response, Server, ShutdownHandle, SslConfig};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+pub const READ_TIMEOUT: Duration = Duration::from_secs(10);
+pub const CERTS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs");
+
+// The ways of running a server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Engine {
+    Threads,
+    #[cfg(feature = "mio")]
+    Events,
+    #[cfg(feature = "tokio")]
+    Tokio,
+}
+
+// A server running on a background thread
+pub struct Running {
+    pub addr: SocketAddr,
+    shutdown: ShutdownHandle,
+    thread: JoinHandle<bool>,
+}
+
+impl Engine {
+    // The engines built with the enabled features
+    pub fn all() -> Vec<Engine> {
+        #[allow(unused_mut)]
+        let mut engines = vec![Engine::Threads];
+        #[cfg(feature = "mio")]
+        engines.push(Engine::Events);
+        #[cfg(feature = "tokio")]
+        engines.push(Engine::Tokio);
+        engines
+    }
+
+    // Start the server on an ephemeral port of the loopback interface
+    pub fn start<H>(self, mut server: Server<H>) -> Running
+    where
+        H: Handler + Clone + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.with_tcp_listener(listener);
+        let shutdown = server.shutdown_handle();
+        let thread = thread::spawn(move || match self {
+            Engine::Threads => server.serve().is_ok(),
+            #[cfg(feature = "mio")]
+            Engine::Events => server.serve_events().is_ok(),
+            #[cfg(feature = "tokio")]
+            Engine::Tokio => {
+                let runtime = tokio::runtime::Builder::new_multi_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap();
+                let cancel = mailin_embedded::CancellationToken::new();
+                let serving = runtime.spawn(server.serve_tokio(cancel));
+                matches!(runtime.block_on(serving), Ok(Ok(())))
+            }
+        });
+        Running {
+            addr,
+            shutdown,
+            thread,
+        }
+    }
+}
+
+impl Running {
+    // Shut the server down, returns true if it stopped without an error
+    pub fn stop(self, grace: Duration) -> bool {
+        self.shutdown.shutdown(grace);
+        self.thread.join().unwrap()
+    }
+}
+
+// What a Recorder was told
+#[derive(Clone, Debug, Default)]
+pub struct Calls {
+    pub listeners: Vec<String>,
+    pub helo_ips: Vec<IpAddr>,
+    pub tls_started: Vec<(IpAddr, TlsInfo)>,
+    pub tls_failed: Vec<TlsFailure>,
+    pub data: Vec<u8>,
+}
+
+// A handler that records its calls, it refuses mail to nobody@sea.com
+#[derive(Clone, Default)]
+pub struct Recorder(Arc<Mutex<Calls>>);
+
+impl Recorder {
+    pub fn calls(&self) -> Calls {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Handler for Recorder {
+    fn listener(&mut self, name: &str) {
+        self.0.lock().unwrap().listeners.push(name.to_owned());
+    }
+
+    fn tls_started(&mut self, ip: IpAddr, info: &TlsInfo) {
+        self.0.lock().unwrap().tls_started.push((ip, info.clone()));
+    }
+
+    fn tls_failed(&mut self, _ip: IpAddr, failure: TlsFailure) {
+        self.0.lock().unwrap().tls_failed.push(failure);
+    }
+
+    fn helo(&mut self, ip: IpAddr, _domain: &str) -> Response {
+        self.0.lock().unwrap().helo_ips.push(ip);
+        OK
+    }
+
+    fn rcpt(&mut self, to: &str) -> Response {
+        if to == "nobody@sea.com" {
+            NO_MAILBOX
+        } else {
+            OK
+        }
+    }
+
+    fn data(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.lock().unwrap().data.extend_from_slice(buf);
+        Ok(())
+    }
+}
+
+// A server with a Recorder as its handler
+pub fn recorded_server() -> (Server<Recorder>, Recorder) {
+    let recorder = Recorder::default();
+    (Server::new(recorder.clone()), recorder)
+}
+
+// Read a reply and return its lines
+pub fn read_reply_lines<R: BufRead>(reader: &mut R) -> io::Result<Vec<String>> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let is_last = line.as_bytes().get(3) != Some(&b'-');
+        lines.push(line.trim_end().to_owned());
+        if is_last {
+            return Ok(lines);
+        }
+    }
+}
+
+// Connect and return the code of the greeting
+pub fn greeting_code(addr: SocketAddr) -> (Option<u16>, TcpStream) {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    (read_reply(&mut reader).unwrap(), stream)
+}
+
+// Connect and check the greeting
+pub fn connect(addr: SocketAddr) -> (TcpStream, BufReader<TcpStream>) {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    assert_eq!(read_reply(&mut reader).unwrap(), Some(220));
+    (stream, reader)
+}
+
+// Send the given lines and check the reply to each of them
+pub fn exchange<W: Write, R: BufRead>(writer: &mut W, reader: &mut R, lines: &[(&str, u16)]) {
+    for (line, _) in lines {
+        write!(writer, "{}\r\n", line).unwrap();
+    }
+    writer.flush().unwrap();
+    for (line, code) in lines {
+        assert_eq!(read_reply(reader).unwrap(), Some(*code), "{}", line);
+    }
+}
+
+pub type TlsStream = BufReader<StreamOwned<ClientConnection, TcpStream>>;
+
+// The certificate for localhost
+pub fn ssl_config() -> SslConfig {
+    SslConfig::SelfSigned {
+        cert_path: format!("{}/cert.pem", CERTS),
+        key_path: format!("{}/key.pem", CERTS),
+    }
+}
+
+pub fn tls_client(stream: TcpStream) -> TlsStream {
+    tls_client_for(stream, "localhost")
+}
+
+// A client that trusts the test certificates and sends the given server name
+pub fn tls_client_for(stream: TcpStream, server_name: &str) -> TlsStream {
+    let mut roots = RootCertStore::empty();
+    for path in ["cert.pem", "sni/mail.example.org/cert.pem"] {
+        let cert_file = File::open(format!("{}/{}", CERTS, path)).unwrap();
+        for cert in rustls_pemfile::certs(&mut BufReader::new(cert_file)) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = ServerName::try_from(server_name.to_owned()).unwrap();
+    let conn = ClientConnection::new(Arc::new(config), name).unwrap();
+    BufReader::new(StreamOwned::new(conn, stream))
+}
+
+// Start TLS sending the given server name, returns None if the certificate is not trusted
+pub fn starttls_session(addr: SocketAddr, server_name: &str) -> Option<TlsStream> {
+    let (mut stream, mut reader) = connect(addr);
+    exchange(
+        &mut stream,
+        &mut reader,
+        &[("EHLO a.domain", 250), ("STARTTLS", 220)],
+    );
+    let mut tls = tls_client_for(stream, server_name);
+    write!(tls.get_mut(), "EHLO a.domain\r\n").ok()?;
+    (read_reply(&mut tls).ok()? == Some(250)).then_some(tls)
+}
+
+// Check that a STARTTLS handshake sending the given server name is trusted
+pub fn starttls_as(addr: SocketAddr, server_name: &str) {
+    assert!(starttls_session(addr, server_name).is_some());
+}
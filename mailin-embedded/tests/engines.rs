@@ -0,0 +1,548 @@
+mod common;
+
+use common::*;
+use mailin::client::{Outcome, Transaction};
+use mailin::SessionBuilder;
+use mailin_dialogue::{rfc5321, Script};
+use mailin_embedded::response::{
+    AUTH_OK, INVALID_CREDENTIALS, LINE_TOO_LONG, OK, TIMEOUT, TOO_MANY_CONNECTIONS,
+};
+use mailin_embedded::{
+    Action, AuthMechanism, ConnectionLimits, Handler, Listener, LongLines, RateLimit, Response,
+    Server, Timeouts,
+};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn rfc5321_scenarios() {
+    for engine in Engine::all() {
+        let mut server = recorded_server().0;
+        server.with_name("server.sea.com");
+        let running = engine.start(server);
+        for script in rfc5321::scenarios() {
+            if let Err(e) = script.run_server(running.addr) {
+                panic!("{:?}: {}", engine, e);
+            }
+        }
+    }
+}
+
+#[test]
+fn listeners() {
+    for engine in Engine::all() {
+        let (mut server, recorder) = recorded_server();
+        let mut submission = Listener::new("submission");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let submission_addr = listener.local_addr().unwrap();
+        submission
+            .with_name("submission.sea.com")
+            .with_tcp_listener(listener);
+        server.with_listener(submission);
+        let running = engine.start(server);
+        let script = Script::parse("listener", "S: 220\nC: QUIT\nS: 221\n").unwrap();
+        script.run_server(running.addr).unwrap();
+        script.run_server(submission_addr).unwrap();
+        let listeners = recorder.calls().listeners;
+        assert!(listeners.contains(&"smtp".to_owned()), "{:?}", engine);
+        assert!(listeners.contains(&"submission".to_owned()), "{:?}", engine);
+    }
+}
+
+#[test]
+fn shutdown() {
+    for engine in Engine::all() {
+        let running = engine.start(recorded_server().0);
+        let addr = running.addr;
+        let (mut idle, mut idle_reader) = connect(addr);
+        exchange(&mut idle, &mut idle_reader, &[("HELO a.domain", 250)]);
+        let (mut busy, mut busy_reader) = connect(addr);
+        exchange(
+            &mut busy,
+            &mut busy_reader,
+            &[
+                ("HELO a.domain", 250),
+                ("MAIL FROM:<ship@sea.com>", 250),
+                ("RCPT TO:<fish@sea.com>", 250),
+                ("DATA", 354),
+            ],
+        );
+        let stopping = thread::spawn(move || running.stop(Duration::from_secs(10)));
+        assert_eq!(read_reply(&mut idle_reader).unwrap(), Some(421));
+        // Messages that are being sent can be finished
+        write!(busy, "Hello\r\n.\r\n").unwrap();
+        assert_eq!(read_reply(&mut busy_reader).unwrap(), Some(250));
+        assert_eq!(read_reply(&mut busy_reader).unwrap(), Some(421));
+        assert!(stopping.join().unwrap(), "{:?}", engine);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
+
+#[test]
+fn immediate_shutdown() {
+    for engine in Engine::all() {
+        let running = engine.start(recorded_server().0);
+        let (stopped, wait) = mpsc::channel();
+        thread::spawn(move || stopped.send(running.stop(Duration::from_secs(10))));
+        // The server stops without waiting for a client to connect
+        let stopped = wait.recv_timeout(Duration::from_secs(5));
+        assert_eq!(stopped, Ok(true), "{:?}", engine);
+    }
+}
+
+#[test]
+fn phase_timeouts() {
+    for engine in Engine::all() {
+        let mut server = recorded_server().0;
+        server.with_timeouts(Timeouts {
+            rcpt: Duration::from_millis(200),
+            ..Timeouts::default()
+        });
+        let running = engine.start(server);
+        let (mut stream, mut reader) = connect(running.addr);
+        // The mail phase has the default timeout
+        exchange(&mut stream, &mut reader, &[("HELO a.domain", 250)]);
+        thread::sleep(Duration::from_millis(400));
+        exchange(
+            &mut stream,
+            &mut reader,
+            &[("MAIL FROM:<ship@sea.com>", 250)],
+        );
+        // Idle while waiting for RCPT
+        assert_eq!(read_reply(&mut reader).unwrap(), Some(421));
+        assert_eq!(read_reply(&mut reader).unwrap(), None);
+    }
+}
+
+// Refuses clients that talk before the greeting
+#[derive(Clone)]
+struct Impatient;
+
+impl Handler for Impatient {
+    fn early_talker(&mut self, _ip: IpAddr) -> Response {
+        Response::custom(554, "Too eager".to_owned())
+    }
+}
+
+#[test]
+fn greeting_delay() {
+    let delay = Duration::from_millis(300);
+    for engine in Engine::all() {
+        let mut server = Server::new(Impatient);
+        server.with_greeting_delay(delay);
+        let running = engine.start(server);
+        // An early talker gets the reply of the handler and is disconnected
+        let mut stream = TcpStream::connect(running.addr).unwrap();
+        stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        stream.write_all(b"EHLO a.domain\r\n").unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        assert_eq!(read_reply(&mut reader).unwrap(), Some(554), "{:?}", engine);
+        // The unread command can make the close a reset
+        assert!(!matches!(read_reply(&mut reader), Ok(Some(_))));
+        // A patient client is greeted after the delay
+        let start = Instant::now();
+        let (mut stream, mut reader) = connect(running.addr);
+        assert!(start.elapsed() >= delay, "{:?}", engine);
+        exchange(&mut stream, &mut reader, &[("HELO a.domain", 250)]);
+    }
+}
+
+#[test]
+fn unterminated_line() {
+    for engine in Engine::all() {
+        let mut server = recorded_server().0;
+        server.with_max_line_length(1024);
+        let running = engine.start(server);
+        let (mut stream, mut reader) = connect(running.addr);
+        let mut writer = stream.try_clone().unwrap();
+        let streaming = thread::spawn(move || {
+            let chunk = [b'a'; 4096];
+            for _ in 0..256 {
+                writer.write_all(&chunk).unwrap();
+            }
+        });
+        // The reply is sent without waiting for the end of the line
+        assert_eq!(read_reply(&mut reader).unwrap(), Some(500), "{:?}", engine);
+        streaming.join().unwrap();
+        write!(stream, "\r\nNOOP\r\n").unwrap();
+        assert_eq!(read_reply(&mut reader).unwrap(), Some(250), "{:?}", engine);
+    }
+}
+
+#[test]
+fn long_message_lines() {
+    let send_message = |engine: Engine, long_lines, message: &[u8]| {
+        let (mut server, recorder) = recorded_server();
+        server
+            .with_max_line_length(1000)
+            .with_long_lines(long_lines);
+        let running = engine.start(server);
+        let (mut stream, mut reader) = connect(running.addr);
+        exchange(
+            &mut stream,
+            &mut reader,
+            &[
+                ("HELO a.domain", 250),
+                ("MAIL FROM:<ship@sea.com>", 250),
+                ("RCPT TO:<fish@sea.com>", 250),
+                ("DATA", 354),
+            ],
+        );
+        stream.write_all(message).unwrap();
+        write!(stream, "short\r\n.\r\n").unwrap();
+        let code = read_reply(&mut reader).unwrap();
+        (code, recorder.calls().data)
+    };
+    let long = [b'a'; 1000];
+    let mut message = long.to_vec();
+    message.extend_from_slice(b".\r\n");
+    for engine in Engine::all() {
+        let (code, data) = send_message(engine, LongLines::Split, &message);
+        assert_eq!(code, Some(250));
+        assert_eq!(data, [&long[..], b"\r\n.\r\nshort\r\n"].concat());
+        let (code, data) = send_message(engine, LongLines::Truncate, &message);
+        assert_eq!(code, Some(250));
+        assert_eq!(data, [&long[..], b"\r\nshort\r\n"].concat());
+        let (code, _) = send_message(engine, LongLines::Reject, &message);
+        assert_eq!(code, Some(500));
+    }
+    // A line of the maximum length less one byte, followed by CRLF, is cut between CR and LF
+    let mut message = long[..999].to_vec();
+    message.extend_from_slice(b"\r\n");
+    for engine in Engine::all() {
+        for long_lines in [LongLines::Split, LongLines::Truncate] {
+            let (code, data) = send_message(engine, long_lines, &message);
+            assert_eq!(code, Some(250));
+            assert_eq!(
+                data,
+                [&long[..999], b"\r\nshort\r\n"].concat(),
+                "{:?} {:?}",
+                engine,
+                long_lines
+            );
+        }
+    }
+}
+
+#[test]
+fn connection_limits() {
+    for engine in Engine::all() {
+        let mut server = recorded_server().0;
+        server.with_connection_limits(ConnectionLimits {
+            max_per_ip: Some(2),
+            ..ConnectionLimits::default()
+        });
+        let running = engine.start(server);
+        let addr = running.addr;
+        let (code, first) = greeting_code(addr);
+        assert_eq!(code, Some(220));
+        let (code, _second) = greeting_code(addr);
+        assert_eq!(code, Some(220));
+        assert_eq!(greeting_code(addr).0, Some(421), "{:?}", engine);
+        // The connection is admitted once another one closes
+        drop(first);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(greeting_code(addr).0, Some(220), "{:?}", engine);
+    }
+}
+
+#[test]
+fn listener_connection_limits() {
+    for engine in Engine::all() {
+        let mut server = recorded_server().0;
+        let mut submission = Listener::new("submission");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let submission_addr = listener.local_addr().unwrap();
+        submission
+            .with_tcp_listener(listener)
+            .with_connection_limits(ConnectionLimits {
+                max_per_ip: Some(1),
+                ..ConnectionLimits::default()
+            });
+        server.with_listener(submission);
+        let running = engine.start(server);
+        let (code, _first) = greeting_code(submission_addr);
+        assert_eq!(code, Some(220));
+        assert_eq!(greeting_code(submission_addr).0, Some(421), "{:?}", engine);
+        // The limit of the submission listener does not apply to the default listener
+        let (code, _second) = greeting_code(running.addr);
+        assert_eq!(code, Some(220), "{:?}", engine);
+        let (code, _third) = greeting_code(running.addr);
+        assert_eq!(code, Some(220), "{:?}", engine);
+    }
+}
+
+#[test]
+fn replaced_responses() {
+    let custom = |code, text: &str| Response::custom(code, text.to_owned());
+    for engine in Engine::all() {
+        let mut server = recorded_server().0;
+        server
+            .with_max_line_length(1024)
+            .with_timeouts(Timeouts {
+                greeting: Duration::from_millis(300),
+                ..Timeouts::default()
+            })
+            .with_connection_limits(ConnectionLimits {
+                max_per_ip: Some(1),
+                ..ConnectionLimits::default()
+            })
+            .with_response(LINE_TOO_LONG, custom(500, "Too long for us"))
+            .with_response(TIMEOUT, custom(421, "Too slow for us"))
+            .with_response(TOO_MANY_CONNECTIONS, custom(421, "Too busy for us"));
+        let running = engine.start(server);
+        let (mut stream, mut reader) = connect(running.addr);
+        let second = TcpStream::connect(running.addr).unwrap();
+        second.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        let busy = read_reply_lines(&mut BufReader::new(second)).unwrap();
+        assert_eq!(busy, ["421 Too busy for us"], "{:?}", engine);
+        write!(stream, "{}\r\n", "a".repeat(2000)).unwrap();
+        let too_long = read_reply_lines(&mut reader).unwrap();
+        assert_eq!(too_long, ["500 Too long for us"], "{:?}", engine);
+        let timeout = read_reply_lines(&mut reader).unwrap();
+        assert_eq!(timeout, ["421 Too slow for us"], "{:?}", engine);
+    }
+}
+
+#[test]
+fn connection_rate() {
+    for engine in Engine::all() {
+        let mut server = recorded_server().0;
+        server.with_connection_limits(ConnectionLimits {
+            rate: Some(RateLimit {
+                connections: 2,
+                interval: Duration::from_secs(60),
+            }),
+            ..ConnectionLimits::default()
+        });
+        let addr = engine.start(server).addr;
+        assert_eq!(greeting_code(addr).0, Some(220));
+        assert_eq!(greeting_code(addr).0, Some(220));
+        assert_eq!(greeting_code(addr).0, Some(421), "{:?}", engine);
+    }
+}
+
+#[test]
+fn busy_threads() {
+    let mut server = recorded_server().0;
+    server.with_num_threads(1);
+    let addr = Engine::Threads.start(server).addr;
+    let (code, _first) = greeting_code(addr);
+    assert_eq!(code, Some(220));
+    assert_eq!(greeting_code(addr).0, Some(421));
+}
+
+// The engines that do not use a thread per session ignore the number of threads
+#[test]
+fn many_sessions() {
+    for engine in Engine::all() {
+        if engine == Engine::Threads {
+            continue;
+        }
+        let mut server = recorded_server().0;
+        server.with_num_threads(1);
+        let addr = engine.start(server).addr;
+        let mut sessions: Vec<_> = (0..64).map(|_| connect(addr)).collect();
+        for (stream, reader) in &mut sessions {
+            exchange(stream, reader, &[("NOOP", 250)]);
+        }
+    }
+}
+
+#[test]
+fn starttls() {
+    for engine in Engine::all() {
+        let mut server = recorded_server().0;
+        server.with_ssl(ssl_config()).unwrap();
+        let running = engine.start(server);
+        let mut tls = starttls_session(running.addr, "localhost").unwrap();
+        write!(tls.get_mut(), "MAIL FROM:<ship@sea.com>\r\nQUIT\r\n").unwrap();
+        assert_eq!(read_reply(&mut tls).unwrap(), Some(250), "{:?}", engine);
+        assert_eq!(read_reply(&mut tls).unwrap(), Some(221), "{:?}", engine);
+    }
+}
+
+#[test]
+fn implicit_tls() {
+    for engine in Engine::all() {
+        let mut server = Server::new(RelayHandler::default());
+        server
+            .with_ssl(ssl_config())
+            .unwrap()
+            .with_implicit_tls()
+            .with_auth(AuthMechanism::Plain);
+        let running = engine.start(server);
+        let stream = TcpStream::connect(running.addr).unwrap();
+        stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        // The greeting is sent over TLS
+        let mut tls = tls_client(stream);
+        assert_eq!(read_reply(&mut tls).unwrap(), Some(220), "{:?}", engine);
+        write!(tls.get_mut(), "EHLO a.domain\r\n").unwrap();
+        let ehlo = read_reply_lines(&mut tls).unwrap();
+        assert!(ehlo.iter().all(|l| l.starts_with("250")), "{:?}", ehlo);
+        assert!(!ehlo.iter().any(|l| l.contains("STARTTLS")), "{:?}", engine);
+        assert!(ehlo.iter().any(|l| l[4..].starts_with("AUTH PLAIN")));
+        // AUTH is allowed without STARTTLS
+        write!(tls.get_mut(), "AUTH PLAIN dGVzdAB0ZXN0ADEyMzQ=\r\n").unwrap();
+        assert_eq!(read_reply(&mut tls).unwrap(), Some(235), "{:?}", engine);
+    }
+}
+
+// Accepts the password 1234 and queues a message for clients that collect their mail with ATRN
+#[derive(Clone, Default)]
+struct RelayHandler(Arc<Mutex<Vec<bool>>>);
+
+impl Handler for RelayHandler {
+    fn auth_plain(&mut self, _: &str, _: &str, password: &str) -> Response {
+        if password == "1234" {
+            AUTH_OK
+        } else {
+            INVALID_CREDENTIALS
+        }
+    }
+
+    fn atrn(&mut self, _domains: &[&str]) -> Response {
+        OK
+    }
+
+    fn atrn_transactions(&mut self) -> Vec<Transaction> {
+        vec![Transaction {
+            from: "ship@ocean.com".to_owned(),
+            to: vec!["fish@sea.com".to_owned()],
+            message: b"Subject: Queued\r\n\r\nHello\r\n".to_vec(),
+        }]
+    }
+
+    fn atrn_outcomes(&mut self, outcomes: &[Outcome]) {
+        let delivered = outcomes.iter().map(Outcome::is_delivered);
+        self.0.lock().unwrap().extend(delivered);
+    }
+}
+
+#[test]
+fn atrn() {
+    for engine in Engine::all() {
+        let relay = RelayHandler::default();
+        let mut server = Server::new(relay.clone());
+        server
+            .with_ssl(ssl_config())
+            .unwrap()
+            .with_implicit_tls()
+            .with_auth(AuthMechanism::Plain);
+        let running = engine.start(server);
+        let stream = TcpStream::connect(running.addr).unwrap();
+        stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        let ip = stream.local_addr().unwrap().ip();
+        let mut tls = tls_client(stream);
+        assert_eq!(read_reply(&mut tls).unwrap(), Some(220));
+        for (line, code) in [
+            ("EHLO a.domain", 250),
+            ("AUTH PLAIN dGVzdAB0ZXN0ADEyMzQ=", 235),
+            ("ATRN sea.com", 250),
+        ] {
+            write!(tls.get_mut(), "{}\r\n", line).unwrap();
+            assert_eq!(read_reply(&mut tls).unwrap(), Some(code), "{:?}", engine);
+        }
+        // The client now acts as a server and receives the queued mail
+        let recorder = Recorder::default();
+        let mut session = SessionBuilder::new("a.domain").build(ip, recorder.clone());
+        tls.get_mut()
+            .write_all(&session.greeting().buffer().unwrap())
+            .unwrap();
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if tls.read_until(b'\n', &mut line).unwrap() == 0 {
+                break;
+            }
+            let res = session.process(&line);
+            tls.get_mut().write_all(&res.buffer().unwrap()).unwrap();
+            if res.action == Action::Close {
+                break;
+            }
+        }
+        assert_eq!(recorder.calls().data, b"Subject: Queued\r\n\r\nHello\r\n");
+        // The outcome is reported when the server closes the connection
+        let _ = io::copy(&mut tls, &mut io::sink());
+        assert_eq!(*relay.0.lock().unwrap(), [true], "{:?}", engine);
+    }
+}
+
+#[test]
+fn shutdown_during_atrn() {
+    for engine in Engine::all() {
+        let mut server = Server::new(RelayHandler::default());
+        server
+            .with_ssl(ssl_config())
+            .unwrap()
+            .with_implicit_tls()
+            .with_auth(AuthMechanism::Plain);
+        let running = engine.start(server);
+        let stream = TcpStream::connect(running.addr).unwrap();
+        stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        let mut tls = tls_client(stream);
+        assert_eq!(read_reply(&mut tls).unwrap(), Some(220));
+        for (line, code) in [
+            ("EHLO a.domain", 250),
+            ("AUTH PLAIN dGVzdAB0ZXN0ADEyMzQ=", 235),
+            ("ATRN sea.com", 250),
+        ] {
+            write!(tls.get_mut(), "{}\r\n", line).unwrap();
+            assert_eq!(read_reply(&mut tls).unwrap(), Some(code), "{:?}", engine);
+        }
+        // The client greets the server that delivers the queued mail, then stalls
+        tls.get_mut().write_all(b"220 a.domain ESMTP\r\n").unwrap();
+        let mut line = Vec::new();
+        tls.read_until(b'\n', &mut line).unwrap();
+        assert!(line.starts_with(b"EHLO"), "{:?}", engine);
+        let (stopped, wait) = mpsc::channel();
+        thread::spawn(move || stopped.send(running.stop(Duration::from_millis(300))));
+        let stopped = wait.recv_timeout(Duration::from_secs(5));
+        assert_eq!(stopped, Ok(true), "{:?}", engine);
+    }
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn tokio_cancel() {
+    use mailin_embedded::CancellationToken;
+
+    let mut server = recorded_server().0;
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    server
+        .with_tcp_listener(listener)
+        .with_shutdown_grace(Duration::from_millis(300));
+    let cancel = CancellationToken::new();
+    let token = cancel.clone();
+    let running = thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let serving = runtime.spawn(server.serve_tokio(token));
+        matches!(runtime.block_on(serving), Ok(Ok(())))
+    });
+    let (_idle, mut idle_reader) = connect(addr);
+    let (mut busy, mut busy_reader) = connect(addr);
+    exchange(
+        &mut busy,
+        &mut busy_reader,
+        &[
+            ("HELO a.domain", 250),
+            ("MAIL FROM:<ship@sea.com>", 250),
+            ("RCPT TO:<fish@sea.com>", 250),
+            ("DATA", 354),
+        ],
+    );
+    let start = Instant::now();
+    cancel.cancel();
+    assert_eq!(read_reply(&mut idle_reader).unwrap(), Some(421));
+    // The message is not finished within the grace period
+    assert!(!matches!(read_reply(&mut busy_reader), Ok(Some(250))));
+    assert!(running.join().unwrap());
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
@@ -0,0 +1,107 @@
+mod common;
+
+use common::*;
+use mailin_embedded::ConnectionLimits;
+use std::io::{BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+
+const PROXY_V1: &[u8] = b"PROXY TCP4 192.0.2.1 127.0.0.1 40000 25\r\n";
+const PROXY_LOCAL: &[u8] = b"\r\n\r\n\0\r\nQUIT\n\x20\0\0\0";
+
+fn proxy_v2(source: Ipv6Addr) -> Vec<u8> {
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\0\x24".to_vec();
+    header.extend_from_slice(&source.octets());
+    header.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+    header.extend_from_slice(&[0x9c, 0x40, 0, 25]);
+    header
+}
+
+// Connect as a proxy that sends the given header, then send EHLO if there is a greeting.
+// Returns the greeting code and the stream.
+fn proxied(addr: SocketAddr, header: &[u8]) -> (Option<u16>, TcpStream) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    // The EHLO is sent with the header to check that the server does not read past it
+    let mut buf = header.to_vec();
+    buf.extend_from_slice(b"EHLO a.domain\r\n");
+    stream.write_all(&buf).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    // The connection is reset if the server closes it without reading the EHLO
+    let code = read_reply(&mut reader).ok().flatten();
+    if code == Some(220) {
+        assert_eq!(read_reply(&mut reader).unwrap(), Some(250));
+    }
+    (code, stream)
+}
+
+#[test]
+fn proxy_protocol() {
+    let client = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+    for engine in Engine::all() {
+        let (mut server, recorder) = recorded_server();
+        server
+            .with_proxy_protocol("127.0.0.0/8")
+            .unwrap()
+            .with_num_threads(8)
+            .with_connection_limits(ConnectionLimits {
+                max_per_ip: Some(1),
+                ..ConnectionLimits::default()
+            });
+        let addr = engine.start(server).addr;
+        let (code, _v1) = proxied(addr, PROXY_V1);
+        assert_eq!(code, Some(220));
+        let (code, _v2) = proxied(addr, &proxy_v2(client));
+        assert_eq!(code, Some(220));
+        // The limits apply to the client, not to the proxy
+        assert_eq!(proxied(addr, PROXY_V1).0, Some(421));
+        // Health checks from the proxy itself
+        let (code, _local) = proxied(addr, PROXY_LOCAL);
+        assert_eq!(code, Some(220));
+        assert_eq!(proxied(addr, b"").0, None);
+        assert_eq!(proxied(addr, b"PROXY TCP4 192.0.2.1\r\n").0, None);
+        let expected: [IpAddr; 3] = [
+            Ipv4Addr::new(192, 0, 2, 1).into(),
+            client.into(),
+            Ipv4Addr::LOCALHOST.into(),
+        ];
+        assert_eq!(recorder.calls().helo_ips, expected, "{:?}", engine);
+    }
+}
+
+#[test]
+fn proxy_protocol_tls() {
+    for engine in Engine::all() {
+        let (mut server, recorder) = recorded_server();
+        server
+            .with_ssl(ssl_config())
+            .unwrap()
+            .with_implicit_tls()
+            .with_proxy_protocol("127.0.0.1")
+            .unwrap();
+        let addr = engine.start(server).addr;
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        stream.write_all(PROXY_V1).unwrap();
+        let mut tls = tls_client(stream);
+        assert_eq!(read_reply(&mut tls).unwrap(), Some(220));
+        write!(tls.get_mut(), "EHLO a.domain\r\n").unwrap();
+        assert_eq!(read_reply(&mut tls).unwrap(), Some(250));
+        let expected = [IpAddr::from(Ipv4Addr::new(192, 0, 2, 1))];
+        assert_eq!(recorder.calls().helo_ips, expected, "{:?}", engine);
+    }
+}
+
+#[test]
+fn proxy_untrusted() {
+    let (mut server, recorder) = recorded_server();
+    server.with_proxy_protocol("192.0.2.0/24").unwrap();
+    assert!(server.with_proxy_protocol("192.0.2.0/33").is_err());
+    let addr = Engine::Threads.start(server).addr;
+    // A header from an address that is not trusted is not accepted
+    let (mut stream, mut reader) = connect(addr);
+    stream.write_all(PROXY_V1).unwrap();
+    assert_eq!(read_reply(&mut reader).unwrap(), Some(500));
+    exchange(&mut stream, &mut reader, &[("EHLO a.domain", 250)]);
+    let expected = [IpAddr::from(Ipv4Addr::LOCALHOST)];
+    assert_eq!(recorder.calls().helo_ips, expected);
+}
@@ -0,0 +1,208 @@
+mod common;
+
+use common::*;
+use mailin::TlsFailure;
+use mailin_embedded::SslConfig;
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::thread;
+use std::time::Duration;
+
+#[test]
+fn sni_certificates() {
+    for engine in Engine::all() {
+        let mut server = recorded_server().0;
+        server
+            .with_ssl(SslConfig::Sni {
+                default: Box::new(ssl_config()),
+                certificates: vec![(
+                    "*.example.org".to_owned(),
+                    SslConfig::SelfSigned {
+                        cert_path: format!("{}/sni/mail.example.org/cert.pem", CERTS),
+                        key_path: format!("{}/sni/mail.example.org/privkey.pem", CERTS),
+                    },
+                )],
+            })
+            .unwrap();
+        let addr = engine.start(server).addr;
+        starttls_as(addr, "mail.example.org");
+        starttls_as(addr, "localhost");
+    }
+}
+
+#[test]
+fn sni_directory() {
+    for engine in Engine::all() {
+        let mut server = recorded_server().0;
+        server
+            .with_ssl(SslConfig::SniDirectory {
+                path: format!("{}/sni", CERTS),
+                default: "localhost".to_owned(),
+            })
+            .unwrap();
+        let addr = engine.start(server).addr;
+        starttls_as(addr, "MAIL.example.org");
+        starttls_as(addr, "localhost");
+    }
+    let missing = SslConfig::SniDirectory {
+        path: format!("{}/sni", CERTS),
+        default: "missing.example.org".to_owned(),
+    };
+    assert!(recorded_server().0.with_ssl(missing).is_err());
+}
+
+#[test]
+fn tls_reload() {
+    let dir = std::env::temp_dir().join(format!("mailin-reload-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let cert_path = dir.join("cert.pem");
+    let key_path = dir.join("key.pem");
+    let install = |cert: &str, key: &str| {
+        std::fs::copy(format!("{}/{}", CERTS, cert), &cert_path).unwrap();
+        std::fs::copy(format!("{}/{}", CERTS, key), &key_path).unwrap();
+    };
+    install("cert.pem", "key.pem");
+    let mut server = recorded_server().0;
+    server
+        .with_ssl(SslConfig::SelfSigned {
+            cert_path: cert_path.to_string_lossy().into_owned(),
+            key_path: key_path.to_string_lossy().into_owned(),
+        })
+        .unwrap();
+    let reload = server.tls_reload_handle().unwrap();
+    let addr = Engine::Threads.start(server).addr;
+    let mut existing = starttls_session(addr, "localhost").unwrap();
+    assert!(starttls_session(addr, "mail.example.org").is_none());
+    // Reload on demand
+    install(
+        "sni/mail.example.org/cert.pem",
+        "sni/mail.example.org/privkey.pem",
+    );
+    reload.reload().unwrap();
+    starttls_as(addr, "mail.example.org");
+    write!(existing.get_mut(), "NOOP\r\n").unwrap();
+    assert_eq!(read_reply(&mut existing).unwrap(), Some(250));
+    // A failed reload keeps the current certificate
+    std::fs::write(&key_path, "not a key").unwrap();
+    assert!(reload.reload().is_err());
+    starttls_as(addr, "mail.example.org");
+    // Reload when the files change
+    reload.watch(Duration::from_millis(20));
+    thread::sleep(Duration::from_millis(100));
+    install("cert.pem", "key.pem");
+    let reloaded = (0..50).any(|_| {
+        thread::sleep(Duration::from_millis(100));
+        starttls_session(addr, "localhost").is_some()
+    });
+    assert!(reloaded);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[cfg(not(feature = "ossl"))]
+#[test]
+fn own_tls_config() {
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::sync::Arc;
+
+    let cert_file = File::open(format!("{}/cert.pem", CERTS)).unwrap();
+    let certs = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let key_file = File::open(format!("{}/key.pem", CERTS)).unwrap();
+    let key = rustls_pemfile::private_key(&mut BufReader::new(key_file))
+        .unwrap()
+        .unwrap();
+    let config = rustls::ServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .unwrap();
+    let mut server = recorded_server().0;
+    server.with_rustls_config(Arc::new(config));
+    let reload = server.tls_reload_handle().unwrap();
+    let addr = Engine::Threads.start(server).addr;
+    let tls = starttls_session(addr, "localhost").unwrap();
+    let version = tls.get_ref().conn.protocol_version();
+    assert_eq!(version, Some(rustls::ProtocolVersion::TLSv1_3));
+    assert!(reload.reload().is_err());
+}
+
+#[cfg(feature = "ossl")]
+#[test]
+fn own_ssl_acceptor() {
+    use mailin_embedded::openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslVersion};
+
+    let mut acceptor = SslAcceptor::mozilla_modern_v5(SslMethod::tls()).unwrap();
+    acceptor
+        .set_private_key_file(format!("{}/key.pem", CERTS), SslFiletype::PEM)
+        .unwrap();
+    acceptor
+        .set_certificate_chain_file(format!("{}/cert.pem", CERTS))
+        .unwrap();
+    acceptor
+        .set_min_proto_version(Some(SslVersion::TLS1_3))
+        .unwrap();
+    let mut server = recorded_server().0;
+    server.with_ssl_acceptor(acceptor.build());
+    let reload = server.tls_reload_handle().unwrap();
+    let addr = Engine::Threads.start(server).addr;
+    let tls = starttls_session(addr, "localhost").unwrap();
+    let version = tls.get_ref().conn.protocol_version();
+    assert_eq!(version, Some(rustls::ProtocolVersion::TLSv1_3));
+    assert!(reload.reload().is_err());
+}
+
+#[test]
+fn tls_details() {
+    for engine in Engine::all() {
+        let (mut server, recorder) = recorded_server();
+        server.with_ssl(ssl_config()).unwrap();
+        let addr = engine.start(server).addr;
+        starttls_as(addr, "localhost");
+        let (ip, info) = recorder.calls().tls_started.pop().unwrap();
+        assert!(ip.is_loopback());
+        assert!(info.protocol.unwrap().starts_with("TLSv1."));
+        assert!(info.cipher.is_some());
+        assert_eq!(info.server_name.as_deref(), Some("localhost"));
+        assert_eq!(info.peer_certificate, None);
+    }
+}
+
+// Send STARTTLS, then write the given bytes and wait for the server to close
+fn failed_handshake(addr: SocketAddr, bytes: &[u8]) {
+    let (mut stream, mut reader) = connect(addr);
+    exchange(
+        &mut stream,
+        &mut reader,
+        &[("EHLO a.domain", 250), ("STARTTLS", 220)],
+    );
+    stream.write_all(bytes).unwrap();
+    // The server may already have closed the connection
+    let _ = stream.shutdown(Shutdown::Write);
+    let _ = io::copy(&mut reader, &mut io::sink());
+}
+
+#[test]
+fn tls_failures() {
+    for engine in Engine::all() {
+        let (mut server, recorder) = recorded_server();
+        server.with_ssl(ssl_config()).unwrap();
+        let addr = engine.start(server).addr;
+        failed_handshake(addr, b"EHLO a.domain\r\n");
+        failed_handshake(addr, b"");
+        starttls_as(addr, "localhost");
+        assert_eq!(
+            recorder.calls().tls_failed,
+            [TlsFailure::ProtocolVersion, TlsFailure::ClientAbort],
+            "{:?}",
+            engine
+        );
+    }
+}
+
+#[test]
+fn implicit_tls_without_certificates() {
+    let mut server = recorded_server().0;
+    server.with_implicit_tls();
+    assert!(server.serve().is_err());
+}
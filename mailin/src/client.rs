@@ -0,0 +1,853 @@
+use crate::parser::parse_reply;
+use crate::response::{Response, ResponseBuilder};
+use crate::transcript::redact_command;
+use crate::AuthMechanism;
+use log::trace;
+use std::collections::VecDeque;
+use std::mem;
+
+//------ Types -----------------------------------------------------------------
+
+// The server does not offer STARTTLS but TLS is required
+const TLS_UNAVAILABLE: Response = Response::fixed(530, "Server does not offer STARTTLS");
+// The server does not offer the configured authentication mechanism
+const AUTH_UNAVAILABLE: Response =
+    Response::fixed(504, "Server does not offer the authentication mechanism");
+// The message is larger than the SIZE advertised by the server
+const MESSAGE_TOO_BIG: Response =
+    Response::fixed(552, "Message exceeds the maximum size of the server");
+// The transaction has no recipients
+const NO_RECIPIENTS: Response = Response::fixed(554, "No valid recipients");
+// The server sent a line that is not a valid reply
+const BAD_REPLY: Response = Response::fixed(421, "Unparseable reply from server");
+
+/// ClientAction indicates what the code driving a `Client` should do next
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientAction {
+    /// Send the bytes to the server and then read the replies
+    Send(Vec<u8>),
+    /// Wait for the server to send more lines
+    Wait,
+    /// Upgrade the connection to TLS and then call `Client::tls_active()`
+    UpgradeTls,
+    /// Close the connection, the client has finished
+    Close,
+}
+
+/// A mail message to be sent by a `Client`
+#[derive(Clone, Debug)]
+pub struct Transaction {
+    /// The reverse path
+    pub from: String,
+    /// The forward paths
+    pub to: Vec<String>,
+    /// The message with CRLF line endings, without dot stuffing
+    pub message: Vec<u8>,
+}
+
+/// The result of sending a `Transaction`
+#[derive(Clone, Debug)]
+pub struct Outcome {
+    /// The reply to the RCPT command of each recipient that was sent to the server
+    pub recipients: Vec<(String, Response)>,
+    /// The reply to the end of the message, or the reply that aborted the transaction
+    pub response: Response,
+}
+
+impl Outcome {
+    /// Was the message accepted by the server?
+    pub fn is_delivered(&self) -> bool {
+        !self.response.is_error
+    }
+
+    /// The recipients that were accepted by the server
+    pub fn accepted(&self) -> impl Iterator<Item = &str> {
+        self.recipients
+            .iter()
+            .filter(|(_, res)| !res.is_error)
+            .map(|(to, _)| to.as_str())
+    }
+}
+
+#[derive(Clone, PartialEq)]
+enum TlsPolicy {
+    Disabled,
+    Opportunistic,
+    Required,
+}
+
+#[derive(Clone)]
+struct Login {
+    mechanism: AuthMechanism,
+    username: String,
+    password: String,
+}
+
+// Extensions advertised by the server in response to EHLO
+#[derive(Default)]
+struct Extensions {
+    start_tls: bool,
+    pipelining: bool,
+    eight_bit_mime: bool,
+    size: Option<usize>,
+    auth: Vec<String>,
+}
+
+impl Extensions {
+    fn parse(lines: &[String]) -> Self {
+        let mut ret = Self::default();
+        // The first line of the reply is a greeting
+        for line in lines.iter().skip(1) {
+            let mut words = line.split_whitespace();
+            let keyword = words.next().unwrap_or_default().to_ascii_uppercase();
+            match keyword.as_str() {
+                "STARTTLS" => ret.start_tls = true,
+                "PIPELINING" => ret.pipelining = true,
+                "8BITMIME" => ret.eight_bit_mime = true,
+                "SIZE" => ret.size = Some(words.next().and_then(|s| s.parse().ok()).unwrap_or(0)),
+                "AUTH" => ret.auth = words.map(|w| w.to_ascii_uppercase()).collect(),
+                _ => (),
+            }
+        }
+        ret
+    }
+}
+
+enum State {
+    Greeting,
+    Ehlo,
+    Helo,
+    StartTls,
+    TlsHandshake,
+    AuthPlain,
+    AuthLoginUsername,
+    AuthLoginPassword,
+    Transaction,
+    Rset,
+    Quit,
+    Closed,
+}
+
+// The reply expected for a command sent during a transaction
+enum Expect {
+    Mail,
+    Rcpt(usize),
+    Data,
+    DataEnd,
+}
+
+// A transaction that is being sent
+struct Current {
+    transaction: Transaction,
+    pending: VecDeque<Expect>,
+    recipients: Vec<(String, Response)>,
+    failure: Option<Response>,
+}
+
+impl Current {
+    fn has_accepted(&self) -> bool {
+        self.recipients.iter().any(|(_, res)| !res.is_error)
+    }
+
+    fn into_outcome(self, response: Response) -> Outcome {
+        Outcome {
+            recipients: self.recipients,
+            response: self.failure.unwrap_or(response),
+        }
+    }
+}
+
+#[derive(Clone)]
+/// Builds an smtp `Client`
+///
+/// # Examples
+/// ```
+/// # use mailin::client::{ClientBuilder, Transaction};
+/// # use mailin::AuthMechanism;
+/// // Create a client builder that holds the configuration
+/// let mut builder = ClientBuilder::new("client.domain");
+/// builder
+///     .enable_start_tls()
+///     .enable_auth(AuthMechanism::Plain, "username", "password");
+/// // Then for each connection to a server
+/// let transaction = Transaction {
+///     from: "ship@sea.com".to_owned(),
+///     to: vec!["fish@sea.com".to_owned()],
+///     message: b"Subject: Hello\r\n\r\nHello fish\r\n".to_vec(),
+/// };
+/// let mut client = builder.build(vec![transaction]);
+/// ```
+pub struct ClientBuilder {
+    name: String,
+    tls: TlsPolicy,
+    login: Option<Login>,
+}
+
+impl ClientBuilder {
+    /// Create a new client that greets servers with the given name
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self {
+            name: name.into(),
+            tls: TlsPolicy::Disabled,
+            login: None,
+        }
+    }
+
+    /// Use STARTTLS if the server offers it
+    pub fn enable_start_tls(&mut self) -> &mut Self {
+        self.tls = TlsPolicy::Opportunistic;
+        self
+    }
+
+    /// Use STARTTLS and abort if the server does not offer it
+    pub fn require_tls(&mut self) -> &mut Self {
+        self.tls = TlsPolicy::Required;
+        self
+    }
+
+    /// Authenticate with the server using the given mechanism and credentials
+    pub fn enable_auth<U, P>(
+        &mut self,
+        mechanism: AuthMechanism,
+        username: U,
+        password: P,
+    ) -> &mut Self
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        self.login = Some(Login {
+            mechanism,
+            username: username.into(),
+            password: password.into(),
+        });
+        self
+    }
+
+    /// Build a new client that will send the given transactions over a single connection
+    pub fn build(&self, transactions: Vec<Transaction>) -> Client {
+        Client {
+            name: self.name.clone(),
+            tls: self.tls.clone(),
+            tls_active: false,
+            tls_refused: false,
+            login: self.login.clone(),
+            authenticated: false,
+            extensions: Extensions::default(),
+            state: State::Greeting,
+            reply: Vec::new(),
+            transactions: transactions.into(),
+            current: None,
+            outcomes: Vec::new(),
+        }
+    }
+}
+
+/// A sans-IO smtp client that sends mail to a single server
+///
+/// The code using the client reads lines sent by the server and passes them to
+/// `Client::process()`, which returns the next `ClientAction`.
+///
+/// # Pseudo Code
+/// ```rust,ignore
+/// let mut client = ClientBuilder::new("client.domain").build(transactions);
+/// loop {
+///     let line = read_line(tcp_connection);
+///     match client.process(line) {
+///         ClientAction::Send(buf) => write(tcp_connection, &buf),
+///         ClientAction::Wait => (),
+///         ClientAction::UpgradeTls => {
+///             tcp_connection = upgrade_tls(tcp_connection);
+///             if let ClientAction::Send(buf) = client.tls_active() {
+///                 write(tcp_connection, &buf);
+///             }
+///         }
+///         ClientAction::Close => break,
+///     }
+/// }
+/// for outcome in client.outcomes() {
+///     println!("Delivered: {}", outcome.is_delivered());
+/// }
+/// ```
+pub struct Client {
+    name: String,
+    tls: TlsPolicy,
+    tls_active: bool,
+    tls_refused: bool,
+    login: Option<Login>,
+    authenticated: bool,
+    extensions: Extensions,
+    state: State,
+    // Lines of a multiline reply that has not been completed
+    reply: Vec<String>,
+    transactions: VecDeque<Transaction>,
+    current: Option<Current>,
+    outcomes: Vec<Outcome>,
+}
+
+impl Client {
+    /// Process a line sent by the server.
+    ///
+    /// Returns the action the code driving the client should take next.
+    pub fn process(&mut self, line: &[u8]) -> ClientAction {
+        trace!("< {}", String::from_utf8_lossy(line));
+        let (code, is_last, text) = match parse_reply(line) {
+            Ok(reply) => reply,
+            Err(_) => return self.close(BAD_REPLY),
+        };
+        self.reply.push(text.to_owned());
+        if !is_last {
+            return ClientAction::Wait;
+        }
+        let lines = mem::take(&mut self.reply);
+        let mut builder = ResponseBuilder::new(code);
+        for line in &lines {
+            builder.line(line.as_str());
+        }
+        let res = builder.build();
+        if code == 421 {
+            return self.close(res);
+        }
+        self.reply_received(res, &lines)
+    }
+
+    /// TLS is active after an `UpgradeTls` action.
+    ///
+    /// Returns the action the code driving the client should take next.
+    pub fn tls_active(&mut self) -> ClientAction {
+        self.tls_active = true;
+        self.ehlo()
+    }
+
+    /// The outcome of each transaction that has been finished
+    pub fn outcomes(&self) -> &[Outcome] {
+        &self.outcomes
+    }
+
+    /// Has the client finished with the connection?
+    pub fn is_closed(&self) -> bool {
+        matches!(self.state, State::Closed)
+    }
+
+    fn reply_received(&mut self, res: Response, lines: &[String]) -> ClientAction {
+        match self.state {
+            State::Greeting if res.code == 220 => self.ehlo(),
+            State::Ehlo if res.code == 250 => {
+                self.extensions = Extensions::parse(lines);
+                self.after_hello()
+            }
+            // The server does not understand EHLO, fall back to HELO
+            State::Ehlo if res.code == 500 || res.code == 502 => {
+                self.state = State::Helo;
+                self.send(format!("HELO {}\r\n", self.name).into_bytes())
+            }
+            State::Helo if res.code == 250 => {
+                self.extensions = Extensions::default();
+                self.after_hello()
+            }
+            State::StartTls if res.code == 220 => {
+                self.state = State::TlsHandshake;
+                ClientAction::UpgradeTls
+            }
+            State::StartTls if self.tls == TlsPolicy::Opportunistic => {
+                self.tls_refused = true;
+                self.after_hello()
+            }
+            State::AuthPlain | State::AuthLoginPassword if res.code == 235 => {
+                self.authenticated = true;
+                self.start_transaction()
+            }
+            State::AuthLoginUsername if res.code == 334 => {
+                let password = self
+                    .login
+                    .as_ref()
+                    .map(|l| l.password.as_str())
+                    .unwrap_or_default();
+                self.state = State::AuthLoginPassword;
+                self.send(format!("{}\r\n", base64::encode(password)).into_bytes())
+            }
+            State::Transaction => self.transaction_reply(res),
+            State::Rset => self.start_transaction(),
+            State::Quit | State::Closed => {
+                self.state = State::Closed;
+                ClientAction::Close
+            }
+            State::TlsHandshake => ClientAction::Wait,
+            _ => self.fail_all(res),
+        }
+    }
+
+    fn ehlo(&mut self) -> ClientAction {
+        self.state = State::Ehlo;
+        self.send(format!("EHLO {}\r\n", self.name).into_bytes())
+    }
+
+    // Decide what to do once the server has accepted EHLO or HELO
+    fn after_hello(&mut self) -> ClientAction {
+        let use_tls = self.tls != TlsPolicy::Disabled && !self.tls_active && !self.tls_refused;
+        if use_tls && self.extensions.start_tls {
+            self.state = State::StartTls;
+            return self.send(b"STARTTLS\r\n".to_vec());
+        }
+        if self.tls == TlsPolicy::Required && !self.tls_active {
+            return self.fail_all(TLS_UNAVAILABLE);
+        }
+        match self.login {
+            Some(ref login) if !self.authenticated => {
+                if !self
+                    .extensions
+                    .auth
+                    .iter()
+                    .any(|m| m == login.mechanism.extension())
+                {
+                    return self.fail_all(AUTH_UNAVAILABLE);
+                }
+                let cmd = match login.mechanism {
+                    AuthMechanism::Plain => {
+                        self.state = State::AuthPlain;
+                        let creds = format!("\0{}\0{}", login.username, login.password);
+                        format!("AUTH PLAIN {}\r\n", base64::encode(&creds))
+                    }
+                    AuthMechanism::Login => {
+                        self.state = State::AuthLoginUsername;
+                        format!("AUTH LOGIN {}\r\n", base64::encode(&login.username))
+                    }
+                };
+                self.send(cmd.into_bytes())
+            }
+            _ => self.start_transaction(),
+        }
+    }
+
+    // Start sending the next transaction, or quit if there are none left
+    fn start_transaction(&mut self) -> ClientAction {
+        while let Some(transaction) = self.transactions.pop_front() {
+            let error = match self.extensions.size {
+                Some(max) if max > 0 && transaction.message.len() > max => Some(MESSAGE_TOO_BIG),
+                _ if transaction.to.is_empty() => Some(NO_RECIPIENTS),
+                _ => None,
+            };
+            if let Some(response) = error {
+                self.outcomes.push(Outcome {
+                    recipients: Vec::new(),
+                    response,
+                });
+                continue;
+            }
+            let mut buf = format!("MAIL FROM:<{}>", transaction.from);
+            if self.extensions.size.is_some() {
+                buf += &format!(" SIZE={}", transaction.message.len());
+            }
+            if self.extensions.eight_bit_mime && transaction.message.iter().any(|b| *b >= 0x80) {
+                buf += " BODY=8BITMIME";
+            }
+            buf += "\r\n";
+            let mut pending = VecDeque::from([Expect::Mail]);
+            if self.extensions.pipelining {
+                for (i, to) in transaction.to.iter().enumerate() {
+                    buf += &format!("RCPT TO:<{to}>\r\n");
+                    pending.push_back(Expect::Rcpt(i));
+                }
+                buf += "DATA\r\n";
+                pending.push_back(Expect::Data);
+            }
+            self.current = Some(Current {
+                transaction,
+                pending,
+                recipients: Vec::new(),
+                failure: None,
+            });
+            self.state = State::Transaction;
+            return self.send(buf.into_bytes());
+        }
+        self.quit()
+    }
+
+    fn transaction_reply(&mut self, res: Response) -> ClientAction {
+        let pipelining = self.extensions.pipelining;
+        let Some(current) = self.current.as_mut() else {
+            return self.fail_all(res);
+        };
+        let cmd = match current.pending.pop_front() {
+            Some(Expect::Mail) => {
+                if res.is_error {
+                    current.failure = Some(res);
+                    None
+                } else if pipelining {
+                    None
+                } else {
+                    current.pending.push_back(Expect::Rcpt(0));
+                    Some(rcpt(&current.transaction, 0))
+                }
+            }
+            Some(Expect::Rcpt(i)) => {
+                let to = current.transaction.to[i].clone();
+                current.recipients.push((to, res.clone()));
+                let is_last = i + 1 == current.transaction.to.len();
+                if is_last && !current.has_accepted() {
+                    // The last rejection is the outcome, with or without pipelining
+                    current.failure.get_or_insert(res);
+                    None
+                } else if pipelining {
+                    None
+                } else if !is_last {
+                    current.pending.push_back(Expect::Rcpt(i + 1));
+                    Some(rcpt(&current.transaction, i + 1))
+                } else {
+                    current.pending.push_back(Expect::Data);
+                    Some(b"DATA\r\n".to_vec())
+                }
+            }
+            Some(Expect::Data) if res.code == 354 => {
+                current.pending.push_back(Expect::DataEnd);
+                if current.failure.is_none() && current.has_accepted() {
+                    Some(dot_stuff(&current.transaction.message))
+                } else {
+                    // Pipelined DATA was accepted even though the transaction failed
+                    current.failure.get_or_insert(NO_RECIPIENTS);
+                    Some(b".\r\n".to_vec())
+                }
+            }
+            Some(Expect::Data) => {
+                current.failure.get_or_insert(res);
+                None
+            }
+            Some(Expect::DataEnd) => {
+                let current = self.current.take().unwrap();
+                self.outcomes.push(current.into_outcome(res));
+                return self.start_transaction();
+            }
+            None => return ClientAction::Wait,
+        };
+        match cmd {
+            Some(cmd) => self.send(cmd),
+            None if !current.pending.is_empty() => ClientAction::Wait,
+            None => {
+                // The transaction failed, reset the server before the next transaction
+                let current = self.current.take().unwrap();
+                self.outcomes.push(current.into_outcome(NO_RECIPIENTS));
+                if self.transactions.is_empty() {
+                    self.quit()
+                } else {
+                    self.state = State::Rset;
+                    self.send(b"RSET\r\n".to_vec())
+                }
+            }
+        }
+    }
+
+    // Fail the current and remaining transactions with the given response, then quit
+    fn fail_all(&mut self, response: Response) -> ClientAction {
+        self.record_failure(response);
+        self.quit()
+    }
+
+    // Fail the current and remaining transactions with the given response, then close
+    fn close(&mut self, response: Response) -> ClientAction {
+        self.record_failure(response);
+        self.state = State::Closed;
+        ClientAction::Close
+    }
+
+    fn record_failure(&mut self, response: Response) {
+        if let Some(current) = self.current.take() {
+            self.outcomes.push(current.into_outcome(response.clone()));
+        }
+        for _ in self.transactions.drain(..) {
+            self.outcomes.push(Outcome {
+                recipients: Vec::new(),
+                response: response.clone(),
+            });
+        }
+    }
+
+    fn quit(&mut self) -> ClientAction {
+        self.state = State::Quit;
+        self.send(b"QUIT\r\n".to_vec())
+    }
+
+    fn send(&self, buf: Vec<u8>) -> ClientAction {
+        if matches!(self.state, State::AuthLoginPassword) {
+            trace!("> _auth_");
+        } else {
+            trace!("> {}", redact_command(&String::from_utf8_lossy(&buf)));
+        }
+        ClientAction::Send(buf)
+    }
+}
+
+fn rcpt(transaction: &Transaction, i: usize) -> Vec<u8> {
+    format!("RCPT TO:<{}>\r\n", transaction.to[i]).into_bytes()
+}
+
+// Dot stuff a message and append the end of data marker
+fn dot_stuff(message: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(message.len() + 16);
+    for line in message.split_inclusive(|b| *b == b'\n') {
+        if line.starts_with(b".") {
+            ret.push(b'.');
+        }
+        ret.extend(line);
+    }
+    if !ret.is_empty() && !ret.ends_with(b"\r\n") {
+        ret.extend(b"\r\n");
+    }
+    ret.extend(b".\r\n");
+    ret
+}
+
+//----- Tests ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::{AUTH_OK, INVALID_CREDENTIALS, NO_MAILBOX, OK};
+    use crate::{Handler, Session, SessionBuilder};
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr};
+    use ternop::ternary;
+
+    #[derive(Default)]
+    struct StoreHandler {
+        from: String,
+        to: Vec<String>,
+        message: Vec<u8>,
+    }
+
+    impl Handler for &mut StoreHandler {
+        fn rcpt(&mut self, to: &str) -> Response {
+            ternary!(to == "kraken@sea.com", NO_MAILBOX, OK)
+        }
+
+        fn data_start(
+            &mut self,
+            _domain: &str,
+            from: &str,
+            _is8bit: bool,
+            to: &[String],
+        ) -> Response {
+            self.from = from.to_owned();
+            self.to = to.to_vec();
+            OK
+        }
+
+        fn data(&mut self, buf: &[u8]) -> io::Result<()> {
+            self.message.extend(buf);
+            Ok(())
+        }
+
+        fn auth_plain(&mut self, _: &str, username: &str, password: &str) -> Response {
+            ternary!(
+                username == "test" && password == "1234",
+                AUTH_OK,
+                INVALID_CREDENTIALS
+            )
+        }
+
+        fn auth_login(&mut self, username: &str, password: &str) -> Response {
+            ternary!(
+                username == "test" && password == "1234",
+                AUTH_OK,
+                INVALID_CREDENTIALS
+            )
+        }
+    }
+
+    fn new_server<'a>(
+        builder: &SessionBuilder,
+        handler: &'a mut StoreHandler,
+    ) -> Session<&'a mut StoreHandler> {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        builder.build(addr, handler)
+    }
+
+    fn transaction(to: &[&str]) -> Transaction {
+        Transaction {
+            from: "ship@sea.com".to_owned(),
+            to: to.iter().map(|s| s.to_string()).collect(),
+            message: b"Subject: Hello\r\n\r\nHello fish\r\n.hidden\r\n".to_vec(),
+        }
+    }
+
+    fn lines(buf: &[u8]) -> impl Iterator<Item = &[u8]> {
+        buf.split_inclusive(|b| *b == b'\n')
+    }
+
+    // Pass replies from the server to the client and return the last client action
+    fn reply(client: &mut Client, buf: &[u8]) -> ClientAction {
+        let mut action = ClientAction::Wait;
+        for line in lines(buf) {
+            assert_eq!(action, ClientAction::Wait, "Client did not wait for reply");
+            action = client.process(line);
+        }
+        action
+    }
+
+    // Run a client against an in-process server until the client closes the connection
+    fn converse<H: Handler>(client: &mut Client, mut server: Session<H>) {
+        let mut action = reply(client, &server.greeting().buffer().unwrap());
+        loop {
+            action = match action {
+                ClientAction::Send(buf) => {
+                    let mut replies = Vec::new();
+                    for line in lines(&buf) {
+                        server.process(line).write_to(&mut replies).unwrap();
+                    }
+                    reply(client, &replies)
+                }
+                ClientAction::UpgradeTls => {
+                    server.tls_active();
+                    client.tls_active()
+                }
+                ClientAction::Close => break,
+                ClientAction::Wait => panic!("Client is waiting for a reply that will not come"),
+            }
+        }
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn send_mail() {
+        let mut handler = StoreHandler::default();
+        let server = new_server(&SessionBuilder::new("server.domain"), &mut handler);
+        let mut client = ClientBuilder::new("client.domain")
+            .build(vec![transaction(&["fish@sea.com", "kraken@sea.com"])]);
+        converse(&mut client, server);
+        let outcome = &client.outcomes()[0];
+        assert!(outcome.is_delivered());
+        assert_eq!(outcome.accepted().collect::<Vec<_>>(), vec!["fish@sea.com"]);
+        assert_eq!(outcome.recipients[1].1.code, 550);
+        assert_eq!(handler.from, "ship@sea.com");
+        assert_eq!(handler.to, vec!["fish@sea.com".to_owned()]);
+        assert_eq!(
+            handler.message,
+            b"Subject: Hello\r\n\r\nHello fish\r\n.hidden\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn no_valid_recipients() {
+        let mut handler = StoreHandler::default();
+        let server = new_server(&SessionBuilder::new("server.domain"), &mut handler);
+        let mut client = ClientBuilder::new("client.domain").build(vec![
+            transaction(&["kraken@sea.com"]),
+            transaction(&["fish@sea.com"]),
+        ]);
+        converse(&mut client, server);
+        let outcomes = client.outcomes();
+        assert!(!outcomes[0].is_delivered());
+        assert_eq!(outcomes[0].response.code, 550);
+        assert!(outcomes[1].is_delivered());
+    }
+
+    #[test]
+    fn start_tls_and_auth() {
+        for mechanism in [AuthMechanism::Plain, AuthMechanism::Login] {
+            let mut builder = SessionBuilder::new("server.domain");
+            builder
+                .enable_start_tls()
+                .enable_auth(AuthMechanism::Plain)
+                .enable_auth(AuthMechanism::Login);
+            let mut handler = StoreHandler::default();
+            let server = new_server(&builder, &mut handler);
+            let mut client = ClientBuilder::new("client.domain")
+                .require_tls()
+                .enable_auth(mechanism, "test", "1234")
+                .build(vec![transaction(&["fish@sea.com"])]);
+            converse(&mut client, server);
+            assert!(client.tls_active);
+            assert!(client.outcomes()[0].is_delivered());
+        }
+    }
+
+    #[test]
+    fn bad_credentials() {
+        let mut builder = SessionBuilder::new("server.domain");
+        builder.enable_start_tls().enable_auth(AuthMechanism::Plain);
+        let mut handler = StoreHandler::default();
+        let server = new_server(&builder, &mut handler);
+        let mut client = ClientBuilder::new("client.domain")
+            .enable_start_tls()
+            .enable_auth(AuthMechanism::Plain, "test", "bad")
+            .build(vec![transaction(&["fish@sea.com"])]);
+        converse(&mut client, server);
+        assert_eq!(client.outcomes()[0].response.code, 535);
+    }
+
+    #[test]
+    fn tls_unavailable() {
+        let mut handler = StoreHandler::default();
+        let server = new_server(&SessionBuilder::new("server.domain"), &mut handler);
+        let mut client = ClientBuilder::new("client.domain")
+            .require_tls()
+            .build(vec![transaction(&["fish@sea.com"])]);
+        converse(&mut client, server);
+        assert_eq!(client.outcomes()[0].response, TLS_UNAVAILABLE);
+        assert!(handler.message.is_empty());
+    }
+
+    #[test]
+    fn pipelining_and_size() {
+        let mut client = ClientBuilder::new("client.domain").build(vec![
+            transaction(&["fish@sea.com", "kraken@sea.com"]),
+            Transaction {
+                message: vec![b'x'; 200],
+                ..transaction(&["fish@sea.com"])
+            },
+        ]);
+        let action = reply(&mut client, b"220 server.domain ESMTP\r\n");
+        assert_eq!(
+            action,
+            ClientAction::Send(b"EHLO client.domain\r\n".to_vec())
+        );
+        let action = reply(
+            &mut client,
+            b"250-server.domain\r\n250-PIPELINING\r\n250 SIZE 100\r\n",
+        );
+        let expected = b"MAIL FROM:<ship@sea.com> SIZE=39\r\n\
+                         RCPT TO:<fish@sea.com>\r\n\
+                         RCPT TO:<kraken@sea.com>\r\n\
+                         DATA\r\n";
+        assert_eq!(action, ClientAction::Send(expected.to_vec()));
+        let action = reply(&mut client, b"250 OK\r\n250 OK\r\n550 No\r\n354 Go\r\n");
+        let expected = b"Subject: Hello\r\n\r\nHello fish\r\n..hidden\r\n.\r\n";
+        assert_eq!(action, ClientAction::Send(expected.to_vec()));
+        let action = reply(&mut client, b"250 Queued\r\n");
+        assert_eq!(action, ClientAction::Send(b"QUIT\r\n".to_vec()));
+        let action = reply(&mut client, b"221 Bye\r\n");
+        assert_eq!(action, ClientAction::Close);
+        let outcomes = client.outcomes();
+        assert!(outcomes[0].is_delivered());
+        assert_eq!(outcomes[0].accepted().count(), 1);
+        assert_eq!(outcomes[1].response, MESSAGE_TOO_BIG);
+    }
+
+    #[test]
+    fn pipelining_no_valid_recipients() {
+        let mut client = ClientBuilder::new("client.domain").build(vec![
+            transaction(&["kraken@sea.com", "squid@sea.com"]),
+            transaction(&["fish@sea.com"]),
+        ]);
+        reply(&mut client, b"220 server.domain ESMTP\r\n");
+        reply(&mut client, b"250-server.domain\r\n250 PIPELINING\r\n");
+        let action = reply(
+            &mut client,
+            b"250 OK\r\n550 No\r\n551 Not here\r\n503 No recipients\r\n",
+        );
+        assert_eq!(action, ClientAction::Send(b"RSET\r\n".to_vec()));
+        // The outcome is the last RCPT reply, as it is without pipelining
+        let outcome = &client.outcomes()[0];
+        assert!(!outcome.is_delivered());
+        assert_eq!(outcome.response.code, 551);
+        assert_eq!(outcome.recipients.len(), 2);
+    }
+
+    #[test]
+    fn service_closing() {
+        let mut client =
+            ClientBuilder::new("client.domain").build(vec![transaction(&["fish@sea.com"])]);
+        let action = reply(&mut client, b"421 Too busy\r\n");
+        assert_eq!(action, ClientAction::Close);
+        assert_eq!(client.outcomes()[0].response.code, 421);
+    }
+}
@@ -1,8 +1,12 @@
-use crate::parser::{decode_sasl_login, decode_sasl_plain, parse, parse_auth_response};
+use crate::parser::{
+    decode_sasl_login, decode_sasl_plain, is_tls_not_required, parse, parse_auth_response,
+    parse_lenient,
+};
 use crate::response::*;
 
-use crate::smtp::Cmd;
-use crate::{AuthMechanism, Handler, Response};
+use crate::smtp::{Cmd, Phase};
+use crate::transcript::redact_command;
+use crate::{AuthMechanism, Envelope, Handler, Leniency, Response};
 use either::*;
 use log::{error, trace};
 use std::borrow::BorrowMut;
@@ -22,6 +26,14 @@ pub(crate) enum SmtpState {
     Data,
 }
 
+// The kind of line a state expects from the client
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LineKind {
+    Command,
+    Credentials,
+    Body,
+}
+
 #[derive(PartialEq)]
 enum TlsState {
     Unavailable,
@@ -47,16 +59,31 @@ trait State: Send + Sync {
         cmd: Cmd,
     ) -> (Response, Option<Box<dyn State>>);
 
+    // Most states expect command lines
+    fn line_kind(&self) -> LineKind {
+        LineKind::Command
+    }
+
+    // Most states are outside of a mail transaction
+    fn phase(&self) -> Phase {
+        Phase::Mail
+    }
+
     // Most state will convert an input line into a command.
     // Some states, e.g Data, need to process input lines differently and will
     // override this method.
     fn process_line<'a>(
         &mut self,
         _handler: &mut dyn Handler,
+        leniency: Leniency,
         line: &'a [u8],
     ) -> Either<Cmd<'a>, Response> {
-        trace!("> {}", String::from_utf8_lossy(line));
-        parse(line).map(Left).unwrap_or_else(Right)
+        trace!("> {}", redact_command(&String::from_utf8_lossy(line)));
+        let cmd = match leniency {
+            Leniency::Strict => parse(line),
+            Leniency::Lenient => parse_lenient(line),
+        };
+        cmd.map(Left).unwrap_or_else(Right)
     }
 }
 
@@ -135,6 +162,35 @@ fn handle_rset(fsm: &StateMachine, domain: &str) -> (Response, Option<Box<dyn St
     }
 }
 
+fn handle_etrn(
+    current: Box<dyn State>,
+    handler: &mut dyn Handler,
+    node: &str,
+) -> (Response, Option<Box<dyn State>>) {
+    (handler.etrn(node), Some(current))
+}
+
+fn handle_atrn(
+    current: Box<dyn State>,
+    fsm: &StateMachine,
+    handler: &mut dyn Handler,
+    domains: &[&str],
+) -> (Response, Option<Box<dyn State>>) {
+    match fsm.auth_state {
+        AuthState::Authenticated => {
+            let mut res = handler.atrn(domains);
+            if res.code == 250 {
+                // The session ends and the client becomes the server
+                res.action = Action::ReverseRoles;
+                (res, None)
+            } else {
+                (res, Some(current))
+            }
+        }
+        _ => (AUTHENTICATION_REQUIRED, Some(current)),
+    }
+}
+
 fn handle_helo(
     current: Box<dyn State>,
     fsm: &StateMachine,
@@ -222,6 +278,10 @@ impl State for Idle {
         SmtpState::Idle
     }
 
+    fn phase(&self) -> Phase {
+        Phase::Greeting
+    }
+
     fn handle(
         self: Box<Self>,
         fsm: &mut StateMachine,
@@ -258,9 +318,13 @@ impl State for Hello {
         cmd: Cmd,
     ) -> (Response, Option<Box<dyn State>>) {
         match cmd {
+            Cmd::Mail { require_tls, .. } if require_tls && fsm.tls != TlsState::Active => {
+                (ENCRYPTION_REQUIRED, Some(self))
+            }
             Cmd::Mail {
                 reverse_path,
                 is8bit,
+                require_tls,
             } => {
                 let res = handler.mail(fsm.ip, &self.domain, reverse_path);
                 transform_state(self, res, |s| {
@@ -268,11 +332,15 @@ impl State for Hello {
                         domain: s.domain,
                         reverse_path: reverse_path.to_owned(),
                         is8bit,
+                        require_tls,
                     })
                 })
             }
             Cmd::StartTls if fsm.tls == TlsState::Inactive => (START_TLS, Some(Box::new(Idle {}))),
-            Cmd::Vrfy => (VERIFY_RESPONSE, Some(self)),
+            Cmd::Vrfy { address } => (handler.vrfy(address), Some(self)),
+            Cmd::Expn { list } => (handler.expn(list), Some(self)),
+            Cmd::Etrn { node } => handle_etrn(self, handler, node),
+            Cmd::Atrn { ref domains } => handle_atrn(self, fsm, handler, domains),
             Cmd::Rset => handle_rset(fsm, &self.domain),
             _ => default_handler(self, fsm, handler, &cmd),
         }
@@ -341,6 +409,9 @@ impl State for HelloAuth {
                     })),
                 )
             }
+            Cmd::AuthError { response } => (response, Some(self)),
+            Cmd::Etrn { node } => handle_etrn(self, handler, node),
+            Cmd::Atrn { ref domains } => handle_atrn(self, fsm, handler, domains),
             Cmd::Rset => handle_rset(fsm, &self.domain),
             _ => default_handler(self, fsm, handler, &cmd),
         }
@@ -361,6 +432,14 @@ impl State for Auth {
         SmtpState::Auth
     }
 
+    fn phase(&self) -> Phase {
+        Phase::Auth
+    }
+
+    fn line_kind(&self) -> LineKind {
+        LineKind::Credentials
+    }
+
     fn handle(
         mut self: Box<Self>,
         fsm: &mut StateMachine,
@@ -370,7 +449,7 @@ impl State for Auth {
         match cmd {
             Cmd::AuthResponse { response } => match self.mechanism {
                 AuthMechanism::Plain => {
-                    let creds = decode_sasl_plain(response);
+                    let creds = decode_sasl_plain(&response);
                     let res = authenticate_plain(
                         fsm,
                         handler,
@@ -395,7 +474,7 @@ impl State for Auth {
                     }
                 }
                 AuthMechanism::Login => {
-                    let credential = decode_sasl_login(response);
+                    let credential = decode_sasl_login(&response);
                     if let Some(username) = self.username {
                         let res = authenticate_login(fsm, handler, &username, &credential);
                         let domain = self.domain.clone();
@@ -410,6 +489,18 @@ impl State for Auth {
                     }
                 }
             },
+            Cmd::AuthCancel => (
+                AUTH_CANCELLED,
+                Some(Box::new(HelloAuth {
+                    domain: self.domain,
+                })),
+            ),
+            Cmd::AuthError { response } => (
+                response,
+                Some(Box::new(HelloAuth {
+                    domain: self.domain,
+                })),
+            ),
             _ => unhandled(self),
         }
     }
@@ -417,12 +508,12 @@ impl State for Auth {
     fn process_line<'a>(
         &mut self,
         _handler: &mut dyn Handler,
+        _leniency: Leniency,
         line: &'a [u8],
     ) -> Either<Cmd<'a>, Response> {
-        trace!("> {}", String::from_utf8_lossy(line));
-        parse_auth_response(line)
-            .map(|r| Left(Cmd::AuthResponse { response: r }))
-            .unwrap_or_else(Right)
+        trace!("> _auth_");
+        let cmd = parse_auth_response(line).unwrap_or_else(|response| Cmd::AuthError { response });
+        Left(cmd)
     }
 }
 
@@ -432,6 +523,7 @@ struct Mail {
     domain: String,
     reverse_path: String,
     is8bit: bool,
+    require_tls: bool,
 }
 
 impl State for Mail {
@@ -440,6 +532,10 @@ impl State for Mail {
         SmtpState::Mail
     }
 
+    fn phase(&self) -> Phase {
+        Phase::Rcpt
+    }
+
     fn handle(
         self: Box<Self>,
         fsm: &mut StateMachine,
@@ -455,6 +551,7 @@ impl State for Mail {
                         domain: s.domain,
                         reverse_path: s.reverse_path,
                         is8bit: s.is8bit,
+                        require_tls: s.require_tls,
                         forward_path: fp,
                     })
                 })
@@ -471,6 +568,7 @@ struct Rcpt {
     domain: String,
     reverse_path: String,
     is8bit: bool,
+    require_tls: bool,
     forward_path: Vec<String>,
 }
 
@@ -480,6 +578,10 @@ impl State for Rcpt {
         SmtpState::Rcpt
     }
 
+    fn phase(&self) -> Phase {
+        Phase::Rcpt
+    }
+
     fn handle(
         self: Box<Self>,
         fsm: &mut StateMachine,
@@ -488,14 +590,20 @@ impl State for Rcpt {
     ) -> (Response, Option<Box<dyn State>>) {
         match cmd {
             Cmd::Data => {
-                let res = handler.data_start(
-                    &self.domain,
-                    &self.reverse_path,
-                    self.is8bit,
-                    &self.forward_path,
-                );
+                let res = handler.data_start_envelope(&Envelope {
+                    domain: &self.domain,
+                    from: &self.reverse_path,
+                    is8bit: self.is8bit,
+                    require_tls: self.require_tls,
+                    to: &self.forward_path,
+                });
                 let res = ternary!(res.is_error, res, START_DATA);
-                transform_state(self, res, |s| Box::new(Data { domain: s.domain }))
+                transform_state(self, res, |s| {
+                    Box::new(Data {
+                        domain: s.domain,
+                        in_header: true,
+                    })
+                })
             }
             Cmd::Rcpt { forward_path } => {
                 let res = handler.rcpt(forward_path);
@@ -506,6 +614,7 @@ impl State for Rcpt {
                         domain: s.domain,
                         reverse_path: s.reverse_path,
                         is8bit: s.is8bit,
+                        require_tls: s.require_tls,
                         forward_path: fp,
                     })
                 })
@@ -520,6 +629,8 @@ impl State for Rcpt {
 
 struct Data {
     domain: String,
+    // Are message headers being received?
+    in_header: bool,
 }
 
 impl State for Data {
@@ -528,6 +639,14 @@ impl State for Data {
         SmtpState::Data
     }
 
+    fn phase(&self) -> Phase {
+        Phase::Data
+    }
+
+    fn line_kind(&self) -> LineKind {
+        LineKind::Body
+    }
+
     fn handle(
         self: Box<Self>,
         _fsm: &mut StateMachine,
@@ -546,6 +665,7 @@ impl State for Data {
     fn process_line<'a>(
         &mut self,
         handler: &mut dyn Handler,
+        _leniency: Leniency,
         mut line: &'a [u8],
     ) -> Either<Cmd<'a>, Response> {
         if line == b".\r\n" {
@@ -555,6 +675,13 @@ impl State for Data {
             if line.starts_with(b".") {
                 line = &line[1..];
             }
+            if self.in_header {
+                if line == b"\r\n" {
+                    self.in_header = false;
+                } else if is_tls_not_required(line) {
+                    handler.tls_not_required();
+                }
+            }
             match handler.data(line) {
                 Ok(_) => Right(EMPTY_RESPONSE),
                 Err(e) => {
@@ -576,6 +703,9 @@ pub(crate) struct StateMachine {
     auth_plain: bool,
     auth_login: bool,
     insecure_allow_plaintext_auth: bool,
+    ehlo_greeting: String,
+    extra_extensions: Vec<String>,
+    leniency: Leniency,
 }
 
 impl StateMachine {
@@ -584,6 +714,9 @@ impl StateMachine {
         auth_mechanisms: Vec<AuthMechanism>,
         allow_start_tls: bool,
         insecure_allow_plaintext_auth: bool,
+        ehlo_greeting: String,
+        extra_extensions: Vec<String>,
+        leniency: Leniency,
     ) -> Self {
         let auth_state = ternary!(
             auth_mechanisms.is_empty(),
@@ -602,6 +735,9 @@ impl StateMachine {
             auth_plain,
             auth_login,
             insecure_allow_plaintext_auth,
+            ehlo_greeting,
+            extra_extensions,
+            leniency,
         }
     }
 
@@ -623,12 +759,33 @@ impl StateMachine {
         match self.smtp {
             Some(ref mut s) => {
                 let s: &mut dyn State = s.borrow_mut();
-                s.process_line(handler, line)
+                s.process_line(handler, self.leniency, line)
             }
             None => Right(INVALID_STATE),
         }
     }
 
+    // The kind of line expected from the client
+    pub fn line_kind(&self) -> LineKind {
+        self.smtp
+            .as_ref()
+            .map(|s| s.line_kind())
+            .unwrap_or(LineKind::Command)
+    }
+
+    // The phase of the session
+    pub fn phase(&self) -> Phase {
+        self.smtp
+            .as_ref()
+            .map(|s| s.phase())
+            .unwrap_or(Phase::Closed)
+    }
+
+    // The ip address of the client
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+
     #[cfg(test)]
     pub fn current_state(&self) -> SmtpState {
         let id = self.smtp.as_ref().map(|s| s.id());
@@ -640,6 +797,9 @@ impl StateMachine {
         if self.tls == TlsState::Inactive {
             extensions.push("STARTTLS".to_string());
         }
+        if self.tls == TlsState::Active {
+            extensions.push("REQUIRETLS".to_string());
+        }
 
         if self.allow_auth() && !self.auth_mechanisms.is_empty() {
             let mut auth_available = "AUTH".to_string();
@@ -649,7 +809,8 @@ impl StateMachine {
             }
             extensions.push(auth_available);
         }
-        Response::dynamic(250, "server offers extensions:".to_string(), extensions)
+        extensions.extend(self.extra_extensions.iter().cloned());
+        Response::dynamic(250, self.ehlo_greeting.clone(), extensions)
     }
 
     fn allow_auth_plain(&self) -> bool {
@@ -664,3 +825,100 @@ impl StateMachine {
         self.insecure_allow_plaintext_auth || (self.tls == TlsState::Active)
     }
 }
+
+//------------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::MAX_AUTH_LINE;
+
+    struct EmptyHandler {}
+    impl Handler for EmptyHandler {}
+
+    fn auth_state(mechanism: AuthMechanism) -> Auth {
+        Auth {
+            domain: "a.domain".to_owned(),
+            mechanism,
+            username: None,
+        }
+    }
+
+    fn auth_response(line: &[u8]) -> Cmd<'_> {
+        let mut state = auth_state(AuthMechanism::Plain);
+        match state.process_line(&mut EmptyHandler {}, Leniency::Strict, line) {
+            Left(cmd) => cmd,
+            Right(res) => panic!("Unexpected response {:?}", res),
+        }
+    }
+
+    #[test]
+    fn auth_data() {
+        match auth_response(b"dGVzdAB0ZXN0ADEyMzQ=\r\n") {
+            Cmd::AuthResponse { response } => assert_eq!(response, b"test\0test\x001234"),
+            _ => panic!("Auth response incorrectly parsed"),
+        }
+    }
+
+    #[test]
+    fn auth_cancel() {
+        assert!(matches!(auth_response(b"*\r\n"), Cmd::AuthCancel));
+    }
+
+    #[test]
+    fn auth_empty_response() {
+        for line in [b"=\r\n" as &[u8], b"\r\n"] {
+            match auth_response(line) {
+                Cmd::AuthResponse { response } => assert!(response.is_empty()),
+                _ => panic!("Empty auth response incorrectly parsed"),
+            }
+        }
+    }
+
+    #[test]
+    fn auth_invalid_base64() {
+        for line in [b"dGVzd\r\n" as &[u8], b"dGVz!A==\r\n", b"**\r\n", b"==\r\n"] {
+            match auth_response(line) {
+                Cmd::AuthError { response } => assert_eq!(response.code, 501),
+                _ => panic!("Invalid base64 accepted"),
+            }
+        }
+    }
+
+    #[test]
+    fn auth_line_too_long() {
+        let mut line = vec![b'A'; MAX_AUTH_LINE];
+        line.extend_from_slice(b"\r\n");
+        match auth_response(&line) {
+            Cmd::AuthError { response } => assert_eq!(response, AUTH_LINE_TOO_LONG),
+            _ => panic!("Overlong auth line accepted"),
+        }
+    }
+
+    #[test]
+    fn cancel_leaves_auth() {
+        let mut fsm = StateMachine::new(
+            IpAddr::V4(std::net::Ipv4Addr::LOCALHOST),
+            vec![AuthMechanism::Login],
+            false,
+            true,
+            String::new(),
+            Vec::new(),
+            Leniency::Strict,
+        );
+        let mut handler = EmptyHandler {};
+        fsm.smtp = Some(Box::new(auth_state(AuthMechanism::Login)));
+        let res = fsm.command(&mut handler, Cmd::AuthCancel);
+        assert_eq!(res, AUTH_CANCELLED);
+        assert!(matches!(fsm.current_state(), SmtpState::HelloAuth));
+        fsm.smtp = Some(Box::new(auth_state(AuthMechanism::Login)));
+        let res = fsm.command(
+            &mut handler,
+            Cmd::AuthError {
+                response: INVALID_BASE64,
+            },
+        );
+        assert_eq!(res, INVALID_BASE64);
+        assert!(matches!(fsm.current_state(), SmtpState::HelloAuth));
+    }
+}
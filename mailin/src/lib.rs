@@ -22,6 +22,11 @@
 //! // Send the line to the session
 //! let res = session.process(line);
 //!
+//! // Wait if the handler asked for the response to be delayed
+//! if let Some(delay) = res.delay {
+//!     sleep(delay);
+//! }
+//!
 //! // Act on the response
 //! match res.action {
 //!     Action::Reply => {
@@ -32,6 +37,10 @@
 //!         close(tcp_connection);
 //!     }
 //!     Action::NoReply => (), // No response needed
+//!     Action::ReverseRoles => {
+//!         write_response(tcp_connection, &res)?;
+//!         // Deliver queued mail with session.reverse_roles()
+//!     }
 //! }
 //! ```
 
@@ -40,15 +49,19 @@
 
 use std::io;
 use std::net::IpAddr;
+/// Client contains a sans-IO SMTP client for sending mail to a server.
+pub mod client;
 mod fsm;
 mod parser;
 /// Response contains a selection of SMTP responses for use in handlers.
 pub mod response;
 mod smtp;
+/// Transcript records the commands and responses of a session.
+pub mod transcript;
 
 pub use crate::{
-    response::{Action, Response},
-    smtp::{Session, SessionBuilder},
+    response::{Action, Response, ResponseBuilder},
+    smtp::{Phase, Session, SessionBuilder},
 };
 
 /// A `Handler` makes decisions about incoming mail commands.
@@ -84,6 +97,29 @@ pub use crate::{
 /// }
 /// ```
 pub trait Handler {
+    /// Called by servers with several listeners when a connection is accepted, with the
+    /// name of the listener the connection arrived on.
+    fn listener(&mut self, _name: &str) {}
+
+    /// Called when a client sends data before the greeting has been sent.
+    ///
+    /// Return an error response, such as `NO_SERVICE`, to reject the connection or `OK` to
+    /// continue the session, e.g after noting the client for scoring.
+    fn early_talker(&mut self, _ip: IpAddr) -> Response {
+        response::OK
+    }
+
+    /// Called when TLS has been negotiated with the client, with the details of the TLS
+    /// session. This is called before the first command sent over TLS is handled.
+    fn tls_started(&mut self, _ip: IpAddr, _info: &TlsInfo) {}
+
+    /// Called when the TLS handshake with a client fails, with the reason it failed. The
+    /// connection is closed after this is called.
+    ///
+    /// This can be used to find senders that cannot use TLS and to decide whether to keep
+    /// offering STARTTLS to them.
+    fn tls_failed(&mut self, _ip: IpAddr, _failure: TlsFailure) {}
+
     /// Called when a client sends a ehlo or helo message
     fn helo(&mut self, _ip: IpAddr, _domain: &str) -> Response {
         response::OK
@@ -99,6 +135,14 @@ pub trait Handler {
         response::OK
     }
 
+    /// Called when a data command is received with the envelope of the message.
+    ///
+    /// The default calls `data_start`, implement this instead to see the whole envelope,
+    /// such as whether the message must only be relayed over TLS.
+    fn data_start_envelope(&mut self, envelope: &Envelope) -> Response {
+        self.data_start(envelope.domain, envelope.from, envelope.is8bit, envelope.to)
+    }
+
     /// Called when a data command is received
     fn data_start(
         &mut self,
@@ -115,11 +159,55 @@ pub trait Handler {
         Ok(())
     }
 
+    /// Called when the message header contains `TLS-Required: No` (RFC 8689), which asks for
+    /// TLS policies to be ignored when relaying the message.
+    fn tls_not_required(&mut self) {}
+
     /// Called at the end of receiving data
     fn data_end(&mut self) -> Response {
         response::OK
     }
 
+    /// Called when a client sends a VRFY command.
+    ///
+    /// Return a 250 response containing the verified mailbox, e.g.
+    /// `Response::custom(250, "Sea Captain <captain@sea.com>".to_owned())`, a refusal such as
+    /// `NO_MAILBOX` or, by default, the non-committal `VERIFY_RESPONSE`.
+    fn vrfy(&mut self, _address: &str) -> Response {
+        response::VERIFY_RESPONSE
+    }
+
+    /// Called when a client sends an EXPN command.
+    ///
+    /// Return `Response::expansion()` with the members of the list, a refusal such as
+    /// `NO_MAILBOX` or, by default, the non-committal `VERIFY_RESPONSE`.
+    fn expn(&mut self, _list: &str) -> Response {
+        response::VERIFY_RESPONSE
+    }
+
+    /// Called when a client sends ETRN (RFC 1985) to request that the mail queued for a node
+    /// is delivered
+    fn etrn(&mut self, _node: &str) -> Response {
+        response::NOT_IMPLEMENTED
+    }
+
+    /// Called when an authenticated client sends ATRN (RFC 2645) to collect the mail queued
+    /// for the given domains. An empty list of domains means all the domains of the client.
+    ///
+    /// Return a 250 response if there is mail waiting. The roles of client and server are then
+    /// reversed and the mail returned by `atrn_transactions` is delivered to the client.
+    fn atrn(&mut self, _domains: &[&str]) -> Response {
+        response::NOT_IMPLEMENTED
+    }
+
+    /// Called after ATRN was accepted to get the queued mail to deliver to the client
+    fn atrn_transactions(&mut self) -> Vec<client::Transaction> {
+        Vec::new()
+    }
+
+    /// Called with the outcome of delivering the mail returned by `atrn_transactions`
+    fn atrn_outcomes(&mut self, _outcomes: &[client::Outcome]) {}
+
     /// Called when a plain authentication request is received
     fn auth_plain(
         &mut self,
@@ -136,6 +224,57 @@ pub trait Handler {
     }
 }
 
+/// The envelope of a message, passed to `Handler::data_start_envelope`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Envelope<'a> {
+    /// The domain given by the client in HELO or EHLO
+    pub domain: &'a str,
+    /// The reverse path given in MAIL FROM
+    pub from: &'a str,
+    /// True if the client declared an 8 bit message body
+    pub is8bit: bool,
+    /// True if the client used the REQUIRETLS parameter (RFC 8689), which means the message
+    /// must only be relayed over TLS
+    pub require_tls: bool,
+    /// The accepted recipients
+    pub to: &'a [String],
+}
+
+/// Details of the TLS session negotiated with a client, for use in `Received` headers and
+/// TLS based policy. Fields are None when the TLS library does not provide them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// The protocol version, e.g. `TLSv1.3`
+    pub protocol: Option<String>,
+    /// The name of the cipher suite as reported by the TLS library
+    pub cipher: Option<String>,
+    /// The server name the client sent with SNI
+    pub server_name: Option<String>,
+    /// The DER encoded certificate of the client, if it sent one
+    pub peer_certificate: Option<Vec<u8>>,
+}
+
+/// The reason a TLS handshake with a client failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TlsFailure {
+    /// The client and server have no cipher suite, key exchange or signature scheme in
+    /// common
+    NoSharedCipher,
+    /// The client does not support a protocol version allowed by the server, or did not
+    /// send TLS at all
+    ProtocolVersion,
+    /// The client does not trust the certificate of the server
+    CertificateUnknown,
+    /// The client closed the connection during the handshake
+    ClientAbort,
+    /// The client took too long to complete the handshake
+    Timeout,
+    /// Any other failure
+    Other,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 /// Supported authentication mechanisms
 pub enum AuthMechanism {
@@ -156,6 +295,23 @@ impl AuthMechanism {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How strictly commands sent by clients are parsed
+pub enum Leniency {
+    /// Only accept commands that follow the RFC 5321 syntax (the default)
+    Strict,
+
+    /// Accept common deviations from RFC 5321 made by broken clients, such as printers and
+    /// legacy appliances:
+    ///
+    /// * Spaces after the colon in `MAIL FROM:` and `RCPT TO:`
+    /// * Addresses without surrounding angle brackets
+    /// * Trailing whitespace at the end of a command
+    /// * `HELO` or `EHLO` without a domain
+    /// * Unknown `MAIL` parameters, which are ignored
+    Lenient,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,14 +1,19 @@
 use nom::branch::alt;
-use nom::bytes::complete::{is_not, tag, tag_no_case, take_while1};
-use nom::character::is_alphanumeric;
-use nom::combinator::{map, map_res, value};
-use nom::sequence::{pair, preceded, separated_pair, terminated};
+use nom::bytes::complete::{is_not, tag, tag_no_case, take_while1, take_while_m_n};
+use nom::character::complete::{line_ending, space0};
+use nom::character::{is_alphanumeric, is_digit};
+use nom::combinator::{all_consuming, map, map_res, peek, value};
+use nom::multi::many0;
+use nom::sequence::{delimited, pair, preceded, separated_pair, terminated, tuple};
 use nom::IResult;
 
 use crate::response::*;
 use crate::smtp::{Cmd, Credentials};
 use std::str;
 
+// The maximum length of a line sent during an authentication exchange (RFC 4954)
+pub(crate) const MAX_AUTH_LINE: usize = 12288;
+
 //----- Parser -----------------------------------------------------------------
 
 // Parse a line from the client
@@ -20,20 +25,65 @@ pub fn parse(line: &[u8]) -> Result<Cmd, Response> {
     })
 }
 
+// Parse a line from the client, accepting common deviations from RFC 5321
+pub fn parse_lenient(line: &[u8]) -> Result<Cmd, Response> {
+    lenient_command(line).map(|r| r.1).map_err(|e| match e {
+        nom::Err::Incomplete(_) => MISSING_PARAMETER,
+        nom::Err::Error(_) => SYNTAX_ERROR,
+        nom::Err::Failure(_) => SYNTAX_ERROR,
+    })
+}
+
+// Returns true if the line is a `TLS-Required: No` message header (RFC 8689)
+pub fn is_tls_not_required(line: &[u8]) -> bool {
+    all_consuming(tls_required_no)(line).is_ok()
+}
+
+// Parse a reply line sent by a server. Returns the reply code, true if this is the last line
+// of the reply, and the text of the line.
+pub fn parse_reply(line: &[u8]) -> Result<(u16, bool, &str), Response> {
+    reply_line(line).map(|r| r.1).map_err(|_| SYNTAX_ERROR)
+}
+
 // Parse an authentication response from the client
-pub fn parse_auth_response(line: &[u8]) -> Result<&[u8], Response> {
-    auth_response(line).map(|r| r.1).map_err(|_| SYNTAX_ERROR)
+pub fn parse_auth_response(line: &[u8]) -> Result<Cmd, Response> {
+    if line.len() > MAX_AUTH_LINE {
+        return Err(AUTH_LINE_TOO_LONG);
+    }
+    auth_response(line).map(|r| r.1).map_err(|_| INVALID_BASE64)
 }
 
 fn command(buf: &[u8]) -> IResult<&[u8], Cmd> {
     terminated(
         alt((
-            helo, ehlo, mail, rcpt, data, rset, quit, vrfy, noop, starttls, auth,
+            helo, ehlo, mail, rcpt, data, rset, quit, vrfy, expn, noop, starttls, auth, etrn, atrn,
         )),
         tag(b"\r\n"),
     )(buf)
 }
 
+fn lenient_command(buf: &[u8]) -> IResult<&[u8], Cmd> {
+    terminated(
+        alt((
+            lenient_helo,
+            lenient_ehlo,
+            lenient_mail,
+            lenient_rcpt,
+            data,
+            rset,
+            quit,
+            vrfy,
+            expn,
+            noop,
+            starttls,
+            auth,
+            etrn,
+            atrn,
+        )),
+        pair(space0, tag(b"\r\n")),
+    )(buf)
+}
+
 fn hello_domain(buf: &[u8]) -> IResult<&[u8], &str> {
     map_res(is_not(b" \t\r\n" as &[u8]), str::from_utf8)(buf)
 }
@@ -48,6 +98,22 @@ fn ehlo(buf: &[u8]) -> IResult<&[u8], Cmd> {
     map(parse_domain, |domain| Cmd::Ehlo { domain })(buf)
 }
 
+// HELO or EHLO with an optional domain
+fn lenient_hello(cmd_tag: &[u8]) -> impl Fn(&[u8]) -> IResult<&[u8], &str> + '_ {
+    move |buf: &[u8]| {
+        let no_domain = map(tag_no_case(cmd_tag), |_| "");
+        alt((preceded(cmd(cmd_tag), hello_domain), no_domain))(buf)
+    }
+}
+
+fn lenient_helo(buf: &[u8]) -> IResult<&[u8], Cmd> {
+    map(lenient_hello(b"helo"), |domain| Cmd::Helo { domain })(buf)
+}
+
+fn lenient_ehlo(buf: &[u8]) -> IResult<&[u8], Cmd> {
+    map(lenient_hello(b"ehlo"), |domain| Cmd::Ehlo { domain })(buf)
+}
+
 fn mail_path(buf: &[u8]) -> IResult<&[u8], &str> {
     map_res(is_not(b" <>\t\r\n" as &[u8]), str::from_utf8)(buf)
 }
@@ -56,27 +122,75 @@ fn take_all(buf: &[u8]) -> IResult<&[u8], &str> {
     map_res(is_not(b"\r\n" as &[u8]), str::from_utf8)(buf)
 }
 
-fn body_eq_8bit(buf: &[u8]) -> IResult<&[u8], bool> {
-    let preamble = pair(space, tag_no_case(b"body="));
+// Parameters that can follow the reverse path of a MAIL command
+#[derive(Clone)]
+enum MailParam {
+    Body { is8bit: bool },
+    RequireTls,
+}
+
+fn body_eq_8bit(buf: &[u8]) -> IResult<&[u8], MailParam> {
     let is8bit = alt((
         value(true, tag_no_case(b"8bitmime")),
         value(false, tag_no_case(b"7bit")),
     ));
-    preceded(preamble, is8bit)(buf)
+    let parser = preceded(tag_no_case(b"body="), is8bit);
+    map(parser, |is8bit| MailParam::Body { is8bit })(buf)
+}
+
+fn require_tls(buf: &[u8]) -> IResult<&[u8], MailParam> {
+    value(MailParam::RequireTls, tag_no_case(b"requiretls"))(buf)
+}
+
+fn mail_params(buf: &[u8]) -> IResult<&[u8], Vec<MailParam>> {
+    many0(preceded(space, alt((body_eq_8bit, require_tls))))(buf)
 }
 
-fn is8bitmime(buf: &[u8]) -> IResult<&[u8], bool> {
-    body_eq_8bit(buf).or(Ok((buf, false)))
+// Skip a parameter that is not understood, e.g SIZE=1000
+fn unknown_param(buf: &[u8]) -> IResult<&[u8], Option<MailParam>> {
+    value(None, is_not(b" \t\r\n" as &[u8]))(buf)
+}
+
+fn lenient_mail_params(buf: &[u8]) -> IResult<&[u8], Vec<MailParam>> {
+    let known = map(alt((body_eq_8bit, require_tls)), Some);
+    let param = terminated(known, peek(alt((space, line_ending))));
+    let params = many0(preceded(space, alt((param, unknown_param))));
+    map(params, |p| p.into_iter().flatten().collect())(buf)
+}
+
+fn mail_cmd<'a>((reverse_path, params): (&'a str, Vec<MailParam>)) -> Cmd<'a> {
+    let mut is8bit = false;
+    let mut require_tls = false;
+    for param in params {
+        match param {
+            MailParam::Body { is8bit: b } => is8bit = b,
+            MailParam::RequireTls => require_tls = true,
+        }
+    }
+    Cmd::Mail {
+        reverse_path,
+        is8bit,
+        require_tls,
+    }
 }
 
 fn mail(buf: &[u8]) -> IResult<&[u8], Cmd> {
     let preamble = pair(cmd(b"mail"), tag_no_case(b"from:<"));
     let mail_path_parser = preceded(preamble, mail_path);
-    let parser = separated_pair(mail_path_parser, tag(b">"), is8bitmime);
-    map(parser, |r| Cmd::Mail {
-        reverse_path: r.0,
-        is8bit: r.1,
-    })(buf)
+    let parser = separated_pair(mail_path_parser, tag(b">"), mail_params);
+    map(parser, mail_cmd)(buf)
+}
+
+// A path with optional spaces before it and optional angle brackets around it
+fn lenient_path(buf: &[u8]) -> IResult<&[u8], &str> {
+    let bracketed = delimited(tag(b"<"), mail_path, tag(b">"));
+    preceded(space0, alt((bracketed, mail_path)))(buf)
+}
+
+fn lenient_mail(buf: &[u8]) -> IResult<&[u8], Cmd> {
+    let preamble = pair(cmd(b"mail"), tag_no_case(b"from:"));
+    let parser = pair(preceded(preamble, lenient_path), lenient_mail_params);
+    map(parser, mail_cmd)(buf)
 }
 
 fn rcpt(buf: &[u8]) -> IResult<&[u8], Cmd> {
@@ -86,6 +200,12 @@ fn rcpt(buf: &[u8]) -> IResult<&[u8], Cmd> {
     map(parser, |path| Cmd::Rcpt { forward_path: path })(buf)
 }
 
+fn lenient_rcpt(buf: &[u8]) -> IResult<&[u8], Cmd> {
+    let preamble = pair(cmd(b"rcpt"), tag_no_case(b"to:"));
+    let parser = preceded(preamble, lenient_path);
+    map(parser, |path| Cmd::Rcpt { forward_path: path })(buf)
+}
+
 fn data(buf: &[u8]) -> IResult<&[u8], Cmd> {
     value(Cmd::Data, tag_no_case(b"data"))(buf)
 }
@@ -99,8 +219,40 @@ fn quit(buf: &[u8]) -> IResult<&[u8], Cmd> {
 }
 
 fn vrfy(buf: &[u8]) -> IResult<&[u8], Cmd> {
-    let preamble = preceded(cmd(b"vrfy"), take_all);
-    value(Cmd::Vrfy, preamble)(buf)
+    let parse_address = preceded(cmd(b"vrfy"), take_all);
+    map(parse_address, |address| Cmd::Vrfy {
+        address: address.trim_end(),
+    })(buf)
+}
+
+fn expn(buf: &[u8]) -> IResult<&[u8], Cmd> {
+    let parse_list = preceded(cmd(b"expn"), take_all);
+    map(parse_list, |list| Cmd::Expn {
+        list: list.trim_end(),
+    })(buf)
+}
+
+fn etrn(buf: &[u8]) -> IResult<&[u8], Cmd> {
+    let parse_node = preceded(cmd(b"etrn"), take_all);
+    map(parse_node, |node| Cmd::Etrn {
+        node: node.trim_end(),
+    })(buf)
+}
+
+fn atrn_domains(buf: &[u8]) -> IResult<&[u8], Vec<&str>> {
+    let domains = preceded(space, take_all);
+    map(domains, |d| {
+        d.split(',')
+            .map(str::trim)
+            .filter(|d| !d.is_empty())
+            .collect()
+    })(buf)
+}
+
+fn atrn(buf: &[u8]) -> IResult<&[u8], Cmd> {
+    let domains = alt((atrn_domains, map(empty, |_| Vec::new())));
+    let parser = preceded(tag_no_case(b"atrn"), domains);
+    map(parser, |domains| Cmd::Atrn { domains })(buf)
 }
 
 fn noop(buf: &[u8]) -> IResult<&[u8], Cmd> {
@@ -111,16 +263,51 @@ fn starttls(buf: &[u8]) -> IResult<&[u8], Cmd> {
     value(Cmd::StartTls, tag_no_case(b"starttls"))(buf)
 }
 
-fn is_base64(chr: u8) -> bool {
-    is_alphanumeric(chr) || (chr == b'+') || (chr == b'/' || chr == b'=')
+fn tls_required_no(buf: &[u8]) -> IResult<&[u8], ()> {
+    let parser = tuple((
+        tag_no_case(b"tls-required:"),
+        space0,
+        tag_no_case(b"no"),
+        space0,
+        tag(b"\r\n"),
+    ));
+    value((), parser)(buf)
 }
 
-fn auth_initial(buf: &[u8]) -> IResult<&[u8], &[u8]> {
-    preceded(space, take_while1(is_base64))(buf)
+fn is_base64(chr: u8) -> bool {
+    is_alphanumeric(chr) || (chr == b'+') || (chr == b'/' || chr == b'=')
 }
 
-fn auth_response(buf: &[u8]) -> IResult<&[u8], &[u8]> {
-    terminated(take_while1(is_base64), tag("\r\n"))(buf)
+// Base64 encoded SASL data, a single "=" is a zero length response (RFC 4954).
+// Decodes to None if the data is not valid base64.
+fn sasl_data(buf: &[u8]) -> IResult<&[u8], Option<Vec<u8>>> {
+    alt((
+        value(Some(Vec::new()), terminated(tag(b"="), peek(line_ending))),
+        map(take_while1(is_base64), |b| base64::decode(b).ok()),
+    ))(buf)
+}
+
+fn auth_initial(buf: &[u8]) -> IResult<&[u8], Option<Option<Vec<u8>>>> {
+    alt((map(preceded(space, sasl_data), Some), map(empty, |_| None)))(buf)
+}
+
+fn auth_response(buf: &[u8]) -> IResult<&[u8], Cmd> {
+    let response = alt((
+        value(Cmd::AuthCancel, tag(b"*")),
+        map(sasl_data, |data| match data {
+            Some(response) => Cmd::AuthResponse { response },
+            None => Cmd::AuthError {
+                response: INVALID_BASE64,
+            },
+        }),
+        value(
+            Cmd::AuthResponse {
+                response: Vec::new(),
+            },
+            empty,
+        ),
+    ));
+    terminated(response, tag(b"\r\n"))(buf)
 }
 
 fn empty(buf: &[u8]) -> IResult<&[u8], &[u8]> {
@@ -128,12 +315,12 @@ fn empty(buf: &[u8]) -> IResult<&[u8], &[u8]> {
 }
 
 fn auth_plain(buf: &[u8]) -> IResult<&[u8], Cmd> {
-    let parser = preceded(tag_no_case(b"plain"), alt((auth_initial, empty)));
+    let parser = preceded(tag_no_case(b"plain"), auth_initial);
     map(parser, sasl_plain_cmd)(buf)
 }
 
 fn auth_login(buf: &[u8]) -> IResult<&[u8], Cmd> {
-    let parser = preceded(tag_no_case(b"login"), alt((auth_initial, empty)));
+    let parser = preceded(tag_no_case(b"login"), auth_initial);
     map(parser, sasl_login_cmd)(buf)
 }
 
@@ -141,11 +328,26 @@ fn auth(buf: &[u8]) -> IResult<&[u8], Cmd> {
     preceded(cmd(b"auth"), alt((auth_plain, auth_login)))(buf)
 }
 
+fn reply_code(buf: &[u8]) -> IResult<&[u8], u16> {
+    let digits = map_res(take_while_m_n(3, 3, is_digit), str::from_utf8);
+    map_res(digits, str::parse)(buf)
+}
+
+fn reply_line(buf: &[u8]) -> IResult<&[u8], (u16, bool, &str)> {
+    let is_last = alt((
+        value(false, tag(b"-")),
+        value(true, tag(b" ")),
+        value(true, peek(line_ending)),
+    ));
+    let text = alt((take_all, map(empty, |_| "")));
+    terminated(tuple((reply_code, is_last, text)), line_ending)(buf)
+}
+
 //---- Helper functions ---------------------------------------------------------
 
 // Return a parser to match the given command
-fn cmd(cmd_tag: &[u8]) -> impl Fn(&[u8]) -> IResult<&[u8], (&[u8], &[u8])> + '_ {
-    move |buf: &[u8]| pair(tag_no_case(cmd_tag), space)(buf)
+fn cmd(cmd_tag: &[u8]) -> impl Fn(&[u8]) -> IResult<&[u8], &[u8]> + '_ {
+    move |buf: &[u8]| terminated(tag_no_case(cmd_tag), space)(buf)
 }
 
 // Match one or more spaces
@@ -153,56 +355,52 @@ fn space(buf: &[u8]) -> IResult<&[u8], &[u8]> {
     take_while1(|b| b == b' ')(buf)
 }
 
-fn sasl_plain_cmd(param: &[u8]) -> Cmd {
-    if param.is_empty() {
-        Cmd::AuthPlainEmpty
-    } else {
-        let creds = decode_sasl_plain(param);
-        Cmd::AuthPlain {
-            authorization_id: creds.authorization_id,
-            authentication_id: creds.authentication_id,
-            password: creds.password,
+fn sasl_plain_cmd<'a>(initial: Option<Option<Vec<u8>>>) -> Cmd<'a> {
+    match initial {
+        None => Cmd::AuthPlainEmpty,
+        Some(None) => Cmd::AuthError {
+            response: INVALID_BASE64,
+        },
+        Some(Some(data)) => {
+            let creds = decode_sasl_plain(&data);
+            Cmd::AuthPlain {
+                authorization_id: creds.authorization_id,
+                authentication_id: creds.authentication_id,
+                password: creds.password,
+            }
         }
     }
 }
 
-fn sasl_login_cmd(param: &[u8]) -> Cmd {
-    if param.is_empty() {
-        Cmd::AuthLoginEmpty
-    } else {
-        Cmd::AuthLogin {
-            username: decode_sasl_login(param),
-        }
+fn sasl_login_cmd<'a>(initial: Option<Option<Vec<u8>>>) -> Cmd<'a> {
+    match initial {
+        None => Cmd::AuthLoginEmpty,
+        Some(None) => Cmd::AuthError {
+            response: INVALID_BASE64,
+        },
+        Some(Some(data)) => Cmd::AuthLogin {
+            username: decode_sasl_login(&data),
+        },
     }
 }
 
-// Decodes the base64 encoded plain authentication parameter
-pub(crate) fn decode_sasl_plain(param: &[u8]) -> Credentials {
-    let decoded = base64::decode(param);
-    if let Ok(bytes) = decoded {
-        let mut fields = bytes.split(|b| b == &0u8);
-        let authorization_id = next_string(&mut fields);
-        let authentication_id = next_string(&mut fields);
-        let password = next_string(&mut fields);
-        Credentials {
-            authorization_id,
-            authentication_id,
-            password,
-        }
-    } else {
-        Credentials {
-            authorization_id: String::default(),
-            authentication_id: String::default(),
-            password: String::default(),
-        }
+// Splits decoded plain authentication data into its NUL separated fields
+pub(crate) fn decode_sasl_plain(data: &[u8]) -> Credentials {
+    let mut fields = data.split(|b| b == &0u8);
+    let authorization_id = next_string(&mut fields);
+    let authentication_id = next_string(&mut fields);
+    let password = next_string(&mut fields);
+    Credentials {
+        authorization_id,
+        authentication_id,
+        password,
     }
 }
 
-// Decodes base64 encoded login authentication parameters (in login auth, username and password are
+// Converts decoded login authentication data (in login auth, username and password are
 // sent in separate lines)
-pub(crate) fn decode_sasl_login(param: &[u8]) -> String {
-    let decoded = base64::decode(param).unwrap_or_default();
-    String::from_utf8(decoded).unwrap_or_default()
+pub(crate) fn decode_sasl_login(data: &[u8]) -> String {
+    String::from_utf8(data.to_vec()).unwrap_or_default()
 }
 
 fn next_string(it: &mut dyn Iterator<Item = &[u8]>) -> String {
@@ -263,4 +461,141 @@ mod tests {
             _ => panic!("Auth login without initial response incorrectly parsed"),
         };
     }
+
+    #[test]
+    fn vrfy_address() {
+        let res = parse(b"vrfy Kraken <kraken@sea.com> \r\n");
+        match res {
+            Ok(Cmd::Vrfy { address }) => {
+                assert_eq!(address, "Kraken <kraken@sea.com>");
+            }
+            _ => panic!("Vrfy incorrectly parsed"),
+        };
+    }
+
+    #[test]
+    fn expn_list() {
+        let res = parse(b"EXPN sea-creatures\r\n");
+        match res {
+            Ok(Cmd::Expn { list }) => {
+                assert_eq!(list, "sea-creatures");
+            }
+            _ => panic!("Expn incorrectly parsed"),
+        };
+    }
+
+    #[test]
+    fn mail_params() {
+        let res = parse(b"mail from:<ship@sea.com> REQUIRETLS body=8bitmime\r\n");
+        match res {
+            Ok(Cmd::Mail {
+                reverse_path,
+                is8bit,
+                require_tls,
+            }) => {
+                assert_eq!(reverse_path, "ship@sea.com");
+                assert!(is8bit);
+                assert!(require_tls);
+            }
+            _ => panic!("Mail with parameters incorrectly parsed"),
+        };
+    }
+
+    #[test]
+    fn tls_required_header() {
+        assert!(is_tls_not_required(b"TLS-Required: No\r\n"));
+        assert!(is_tls_not_required(b"tls-required:no\r\n"));
+        assert!(!is_tls_not_required(b"TLS-Required: Yes\r\n"));
+        assert!(!is_tls_not_required(b"Subject: TLS-Required: No\r\n"));
+    }
+
+    #[test]
+    fn reply_lines() {
+        assert_eq!(
+            parse_reply(b"250-mx.sea.com\r\n"),
+            Ok((250, false, "mx.sea.com"))
+        );
+        assert_eq!(
+            parse_reply(b"250 PIPELINING\r\n"),
+            Ok((250, true, "PIPELINING"))
+        );
+        assert_eq!(parse_reply(b"354\r\n"), Ok((354, true, "")));
+        assert!(parse_reply(b"25 OK\r\n").is_err());
+    }
+
+    #[test]
+    fn etrn_node() {
+        match parse(b"ETRN @sea.com\r\n") {
+            Ok(Cmd::Etrn { node }) => assert_eq!(node, "@sea.com"),
+            _ => panic!("Etrn incorrectly parsed"),
+        };
+    }
+
+    #[test]
+    fn atrn_domain_list() {
+        match parse(b"ATRN sea.com, ocean.com\r\n") {
+            Ok(Cmd::Atrn { domains }) => assert_eq!(domains, vec!["sea.com", "ocean.com"]),
+            _ => panic!("Atrn incorrectly parsed"),
+        };
+        match parse(b"atrn\r\n") {
+            Ok(Cmd::Atrn { domains }) => assert!(domains.is_empty()),
+            _ => panic!("Atrn without domains incorrectly parsed"),
+        };
+    }
+
+    #[test]
+    fn lenient_mail() {
+        let lines: [&[u8]; 4] = [
+            b"MAIL FROM: <ship@sea.com>\r\n",
+            b"MAIL FROM:ship@sea.com\r\n",
+            b"MAIL FROM:<ship@sea.com> \t\r\n",
+            b"mail from: ship@sea.com SIZE=1000 body=8bitmime\r\n",
+        ];
+        for line in lines {
+            assert!(parse(line).is_err());
+            match parse_lenient(line) {
+                Ok(Cmd::Mail { reverse_path, .. }) => assert_eq!(reverse_path, "ship@sea.com"),
+                _ => panic!("Lenient mail incorrectly parsed"),
+            }
+        }
+        match parse_lenient(b"mail from:<ship@sea.com> SIZE=1000 body=8bitmime REQUIRETLS\r\n") {
+            Ok(Cmd::Mail {
+                is8bit,
+                require_tls,
+                ..
+            }) => {
+                assert!(is8bit);
+                assert!(require_tls);
+            }
+            _ => panic!("Lenient mail parameters incorrectly parsed"),
+        }
+    }
+
+    #[test]
+    fn lenient_rcpt() {
+        for line in [
+            b"RCPT TO: <fish@sea.com>\r\n" as &[u8],
+            b"RCPT TO:fish@sea.com \r\n",
+        ] {
+            assert!(parse(line).is_err());
+            match parse_lenient(line) {
+                Ok(Cmd::Rcpt { forward_path }) => assert_eq!(forward_path, "fish@sea.com"),
+                _ => panic!("Lenient rcpt incorrectly parsed"),
+            }
+        }
+    }
+
+    #[test]
+    fn lenient_hello() {
+        assert!(parse(b"HELO\r\n").is_err());
+        match parse_lenient(b"HELO\r\n") {
+            Ok(Cmd::Helo { domain }) => assert_eq!(domain, ""),
+            _ => panic!("Lenient helo incorrectly parsed"),
+        }
+        match parse_lenient(b"EHLO ship.sea.com \r\n") {
+            Ok(Cmd::Ehlo { domain }) => assert_eq!(domain, "ship.sea.com"),
+            _ => panic!("Lenient ehlo incorrectly parsed"),
+        }
+        assert!(matches!(parse_lenient(b"QUIT  \r\n"), Ok(Cmd::Quit)));
+    }
 }
@@ -1,5 +1,6 @@
 use log::trace;
 use std::io;
+use std::time::Duration;
 
 // Empty response that sends nothing back to the client
 pub(crate) const EMPTY_RESPONSE: Response = Response::empty();
@@ -12,8 +13,8 @@ pub const GOODBYE: Response = Response::fixed(221, "Goodbye");
 pub const AUTH_OK: Response = Response::fixed(235, "Authentication succeeded");
 /// OK response
 pub const OK: Response = Response::fixed(250, "OK");
-// Non-commital response to VERIFY command
-pub(crate) const VERIFY_RESPONSE: Response = Response::fixed(252, "Maybe");
+/// Non-commital response to VRFY and EXPN commands
+pub const VERIFY_RESPONSE: Response = Response::fixed(252, "Maybe");
 // Empty response sent as an auth challenge.
 pub(crate) const EMPTY_AUTH_CHALLENGE: Response = Response::fixed(334, "");
 // Username response sent as an auth challenge for the login mechanism.
@@ -24,25 +25,43 @@ pub(crate) const USERNAME_AUTH_CHALLENGE: Response = Response::fixed(334, "VXNlc
 pub(crate) const PASSWORD_AUTH_CHALLENGE: Response = Response::fixed(334, "UGFzc3dvcmQ6");
 /// Response sent to the client before accepting data
 pub const START_DATA: Response = Response::fixed(354, "Start mail input; end with <CRLF>.<CRLF>");
-// State machine is not accepting commands
-pub(crate) const INVALID_STATE: Response =
+/// State machine is not accepting commands
+pub const INVALID_STATE: Response =
     Response::fixed(421, "Internal service error, closing connection");
 /// Service not available
 pub const NO_SERVICE: Response = Response::fixed(421, "Service not available, closing connection");
+/// Too many connections from the client or to the server
+pub const TOO_MANY_CONNECTIONS: Response =
+    Response::fixed(421, "Too many connections, try again later");
+/// The client took too long to send a command or data
+pub const TIMEOUT: Response = Response::fixed(421, "Timeout exceeded, closing connection");
 /// Internal server error
 pub const INTERNAL_ERROR: Response = Response::fixed(451, "Aborted: local error in processing");
 /// Insufficient system storage
 pub const OUT_OF_SPACE: Response = Response::fixed(452, "Insufficient system storage");
 /// Authentication system is not working
 pub const TEMP_AUTH_FAILURE: Response = Response::fixed(454, "Temporary authentication failure");
-// Parser error
-pub(crate) const SYNTAX_ERROR: Response = Response::fixed(500, "Syntax error");
-// Parser found missing parameter
-pub(crate) const MISSING_PARAMETER: Response = Response::fixed(502, "Missing parameter");
-// Command is unexpected for the current state
-pub(crate) const BAD_SEQUENCE_COMMANDS: Response = Response::fixed(503, "Bad sequence of commands");
+/// Parser error
+pub const SYNTAX_ERROR: Response = Response::fixed(500, "Syntax error");
+/// Line sent by the client is too long
+pub const LINE_TOO_LONG: Response = Response::fixed(500, "Line too long");
+/// Line sent during an authentication exchange is too long
+pub const AUTH_LINE_TOO_LONG: Response = Response::fixed(500, "Authentication line too long");
+/// Client cancelled an authentication exchange
+pub const AUTH_CANCELLED: Response = Response::fixed(501, "Authentication cancelled");
+/// Authentication data could not be base64 decoded
+pub const INVALID_BASE64: Response = Response::fixed(501, "Invalid base64 data");
+/// Command is not implemented
+pub const NOT_IMPLEMENTED: Response = Response::fixed(502, "Command not implemented");
+/// Parser found missing parameter
+pub const MISSING_PARAMETER: Response = Response::fixed(502, "Missing parameter");
+/// Command is unexpected for the current state
+pub const BAD_SEQUENCE_COMMANDS: Response = Response::fixed(503, "Bad sequence of commands");
 /// User storage quota exceeded
 pub const NO_STORAGE: Response = Response::fixed(552, "Exceeded storage allocation");
+/// The client must use STARTTLS before sending this command
+pub const ENCRYPTION_REQUIRED: Response =
+    Response::fixed(530, "Must issue a STARTTLS command first");
 /// Authentication required
 pub const AUTHENTICATION_REQUIRED: Response = Response::fixed(530, "Authentication required");
 /// Bad authentication attempt
@@ -69,6 +88,8 @@ pub struct Response {
     pub is_error: bool,
     /// The action to take after sending the response to the client
     pub action: Action,
+    /// How long to wait before sending the response to the client
+    pub delay: Option<Duration>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -90,6 +111,8 @@ pub enum Action {
     NoReply,
     /// Send a reply and keep the connection open
     Reply,
+    /// Send the reply and then act as an SMTP client on the same connection (ODMR, RFC 2645)
+    ReverseRoles,
 }
 
 impl Response {
@@ -112,9 +135,15 @@ impl Response {
             message: Message::Fixed(message),
             is_error: (code < 200 || code >= 400),
             action,
+            delay: None,
         }
     }
 
+    // True if the response has the same code and text as another, whatever its action or delay
+    pub(crate) fn is_same(&self, other: &Response) -> bool {
+        self.code == other.code && self.message == other.message
+    }
+
     /// Create an application defined response.
     pub const fn custom(code: u16, message: String) -> Self {
         Self {
@@ -122,7 +151,64 @@ impl Response {
             message: Message::Custom(message),
             is_error: (code < 200 || code >= 400),
             action: Response::action_from_code(code),
+            delay: None,
+        }
+    }
+
+    /// Ask the transport to wait for the given duration before sending the response.
+    ///
+    /// This can be used to tarpit suspicious clients.
+    /// ```
+    /// # use mailin::response::NO_MAILBOX;
+    /// # use std::time::Duration;
+    /// let res = NO_MAILBOX.with_delay(Duration::from_secs(5));
+    /// assert_eq!(res.delay, Some(Duration::from_secs(5)));
+    /// ```
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Start building an application defined response that can have multiple lines.
+    ///
+    /// ```
+    /// # use mailin::Response;
+    /// let res = Response::builder(550)
+    ///     .enhanced_code(5, 7, 1)
+    ///     .line("Message rejected")
+    ///     .line("See https://example.com/policy")
+    ///     .build();
+    /// assert_eq!(
+    ///     res.buffer().unwrap(),
+    ///     b"550-5.7.1 Message rejected\r\n550 5.7.1 See https://example.com/policy\r\n"
+    /// );
+    /// ```
+    pub fn builder(code: u16) -> ResponseBuilder {
+        ResponseBuilder::new(code)
+    }
+
+    /// Create a multiline 250 response listing the members of an expanded mailing list.
+    /// A list without members cannot be expanded and gives `NO_MAILBOX`.
+    ///
+    /// ```
+    /// # use mailin::Response;
+    /// let res = Response::expansion(vec![
+    ///     "Sea Captain <captain@sea.com>".to_owned(),
+    ///     "<kraken@sea.com>".to_owned(),
+    /// ]);
+    /// assert_eq!(
+    ///     res.buffer().unwrap(),
+    ///     b"250-Sea Captain <captain@sea.com>\r\n250 <kraken@sea.com>\r\n"
+    /// );
+    /// let empty = Response::expansion(Vec::new());
+    /// assert_eq!(empty.buffer().unwrap(), b"550 Mailbox unavailable\r\n");
+    /// ```
+    pub fn expansion(mut members: Vec<String>) -> Self {
+        if members.is_empty() {
+            return NO_MAILBOX;
         }
+        let head = members.remove(0);
+        Self::dynamic(250, head, members)
     }
 
     // A response that is built dynamically and can be a multiline response
@@ -132,6 +218,7 @@ impl Response {
             message: Message::Dynamic(head, tail),
             is_error: false,
             action: Action::Reply,
+            delay: None,
         }
     }
 
@@ -142,6 +229,7 @@ impl Response {
             message: Message::Empty,
             is_error: false,
             action: Action::NoReply,
+            delay: None,
         }
     }
 
@@ -187,3 +275,51 @@ impl Response {
         }
     }
 }
+
+/// Builds an application defined `Response`, optionally with multiple lines and an
+/// enhanced status code (RFC 3463).
+#[derive(Clone, Debug)]
+pub struct ResponseBuilder {
+    code: u16,
+    enhanced_code: Option<(u8, u16, u16)>,
+    lines: Vec<String>,
+}
+
+impl ResponseBuilder {
+    /// Create a builder for a response with the given three digit code
+    pub fn new(code: u16) -> Self {
+        Self {
+            code,
+            enhanced_code: None,
+            lines: Vec::with_capacity(2),
+        }
+    }
+
+    /// Prefix every line of the response with an enhanced status code e.g 5.7.1
+    pub fn enhanced_code(&mut self, class: u8, subject: u16, detail: u16) -> &mut Self {
+        self.enhanced_code = Some((class, subject, detail));
+        self
+    }
+
+    /// Add a line of text to the response
+    pub fn line<S: Into<String>>(&mut self, text: S) -> &mut Self {
+        self.lines.push(text.into());
+        self
+    }
+
+    /// Build the response
+    pub fn build(&self) -> Response {
+        let mut lines = self.lines.iter().map(|line| match self.enhanced_code {
+            Some((class, subject, detail)) => format!("{class}.{subject}.{detail} {line}"),
+            None => line.clone(),
+        });
+        let head = lines.next().unwrap_or_default();
+        Response {
+            code: self.code,
+            message: Message::Dynamic(head, lines.collect()),
+            is_error: (self.code < 200 || self.code >= 400),
+            action: Response::action_from_code(self.code),
+            delay: None,
+        }
+    }
+}
@@ -1,9 +1,11 @@
 use std::net::IpAddr;
 use std::str;
 
-use crate::fsm::StateMachine;
+use crate::client::{Client, ClientBuilder, Outcome};
+use crate::fsm::{LineKind, StateMachine};
 use crate::response::*;
-use crate::{AuthMechanism, Handler};
+use crate::transcript::{redact_command, Entry, Event, Recorder};
+use crate::{AuthMechanism, Handler, Leniency, TlsFailure, TlsInfo};
 use either::{Left, Right};
 
 //------ Types -----------------------------------------------------------------
@@ -20,6 +22,7 @@ pub enum Cmd<'a> {
     Mail {
         reverse_path: &'a str,
         is8bit: bool,
+        require_tls: bool,
     },
     Rcpt {
         forward_path: &'a str,
@@ -29,7 +32,18 @@ pub enum Cmd<'a> {
     Noop,
     StartTls,
     Quit,
-    Vrfy,
+    Vrfy {
+        address: &'a str,
+    },
+    Expn {
+        list: &'a str,
+    },
+    Etrn {
+        node: &'a str,
+    },
+    Atrn {
+        domains: Vec<&'a str>,
+    },
     AuthLogin {
         username: String,
     },
@@ -40,9 +54,15 @@ pub enum Cmd<'a> {
     },
     AuthLoginEmpty,
     AuthPlainEmpty,
-    // Dummy command containing client authentication
+    // Dummy command containing decoded client authentication
     AuthResponse {
-        response: &'a [u8],
+        response: Vec<u8>,
+    },
+    // Dummy command sent when the client cancels an authentication exchange
+    AuthCancel,
+    // Dummy command sent when authentication data cannot be accepted
+    AuthError {
+        response: Response,
     },
     // Dummy command to signify end of data
     DataEnd,
@@ -50,6 +70,23 @@ pub enum Cmd<'a> {
     StartedTls,
 }
 
+/// The phase of an SMTP session, as seen by a server waiting for the client
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Waiting for HELO or EHLO after the greeting
+    Greeting,
+    /// Waiting for MAIL or another command outside of a mail transaction
+    Mail,
+    /// Exchanging authentication data
+    Auth,
+    /// Waiting for RCPT or DATA
+    Rcpt,
+    /// Receiving a message body
+    Data,
+    /// The session has ended
+    Closed,
+}
+
 pub(crate) struct Credentials {
     pub authorization_id: String,
     pub authentication_id: String,
@@ -59,8 +96,12 @@ pub(crate) struct Credentials {
 /// A single smtp session connected to a single client
 pub struct Session<H: Handler> {
     name: String,
+    greeting: String,
+    replacements: Vec<(Response, Response)>,
     handler: H,
     fsm: StateMachine,
+    recorder: Option<Box<dyn Recorder>>,
+    body_bytes: usize,
 }
 
 #[derive(Clone)]
@@ -84,9 +125,14 @@ pub struct Session<H: Handler> {
 ///
 pub struct SessionBuilder {
     name: String,
+    greeting: Option<String>,
+    ehlo_greeting: String,
+    replacements: Vec<(Response, Response)>,
     start_tls_extension: bool,
     insecure_allow_plaintext_auth: bool,
     auth_mechanisms: Vec<AuthMechanism>,
+    extra_extensions: Vec<String>,
+    leniency: Leniency,
 }
 
 impl SessionBuilder {
@@ -94,9 +140,14 @@ impl SessionBuilder {
     pub fn new<S: Into<String>>(name: S) -> Self {
         Self {
             name: name.into(),
+            greeting: None,
+            ehlo_greeting: "server offers extensions:".to_owned(),
+            replacements: Vec::new(),
             start_tls_extension: false,
             insecure_allow_plaintext_auth: false,
             auth_mechanisms: Vec::with_capacity(4),
+            extra_extensions: Vec::new(),
+            leniency: Leniency::Strict,
         }
     }
 
@@ -112,6 +163,19 @@ impl SessionBuilder {
         self
     }
 
+    /// Advertise the ETRN extension (RFC 1985), requests are passed to `Handler::etrn`
+    pub fn enable_etrn(&mut self) -> &mut Self {
+        self.extra_extensions.push("ETRN".to_owned());
+        self
+    }
+
+    /// Advertise the ATRN extension for On-Demand Mail Relay (RFC 2645), requests from
+    /// authenticated clients are passed to `Handler::atrn`
+    pub fn enable_atrn(&mut self) -> &mut Self {
+        self.extra_extensions.push("ATRN".to_owned());
+        self
+    }
+
     /// Allow authentication over plaintext and advertise authentication mechanisms before a connection
     /// was upgraded to TLS with STARTTLS.
     ///
@@ -127,32 +191,149 @@ impl SessionBuilder {
         self
     }
 
+    /// Set how strictly commands from clients are parsed, the default is `Leniency::Strict`.
+    ///
+    /// `Leniency::Lenient` accepts the deviations from RFC 5321 that are listed in
+    /// `Leniency`, which are commonly made by printers and legacy appliances.
+    pub fn set_leniency(&mut self, leniency: Leniency) -> &mut Self {
+        self.leniency = leniency;
+        self
+    }
+
+    /// Set the text of the 220 greeting sent when a client connects.
+    ///
+    /// The default greeting is the server name followed by `ESMTP`. RFC 5321 requires the
+    /// greeting to start with the server name.
+    pub fn set_greeting<S: Into<String>>(&mut self, greeting: S) -> &mut Self {
+        self.greeting = Some(greeting.into());
+        self
+    }
+
+    /// Set the first line of the response to EHLO, which precedes the list of extensions
+    pub fn set_ehlo_greeting<S: Into<String>>(&mut self, ehlo_greeting: S) -> &mut Self {
+        self.ehlo_greeting = ehlo_greeting.into();
+        self
+    }
+
+    /// Replace a built-in response, such as `SYNTAX_ERROR` or `BAD_SEQUENCE_COMMANDS`, with
+    /// an application defined response.
+    ///
+    /// Responses are matched on their code and text. The action of the built-in response,
+    /// and any delay asked for by the handler, are kept.
+    ///
+    /// ```
+    /// # use mailin::{Response, SessionBuilder};
+    /// # use mailin::response::BAD_SEQUENCE_COMMANDS;
+    /// let mut builder = SessionBuilder::new("server_name");
+    /// builder.replace_response(
+    ///     BAD_SEQUENCE_COMMANDS,
+    ///     Response::builder(503)
+    ///         .enhanced_code(5, 5, 1)
+    ///         .line("Bad sequence of commands, see https://example.com/smtp")
+    ///         .build(),
+    /// );
+    /// ```
+    pub fn replace_response(&mut self, builtin: Response, replacement: Response) -> &mut Self {
+        self.replacements.push((builtin, replacement));
+        self
+    }
+
+    /// Get the response to send in place of a built-in response, which is the replacement set
+    /// with `replace_response` if there is one. Servers use this for responses they send
+    /// before a session is built.
+    pub fn response(&self, builtin: Response) -> Response {
+        replace(&self.replacements, builtin)
+    }
+
     /// Build a new session to handle a connection from the given ip address
     pub fn build<H: Handler>(&self, remote: IpAddr, handler: H) -> Session<H> {
+        let greeting = self
+            .greeting
+            .clone()
+            .unwrap_or_else(|| format!("{} ESMTP", self.name));
         Session {
             name: self.name.clone(),
+            greeting,
+            replacements: self.replacements.clone(),
             handler,
             fsm: StateMachine::new(
                 remote,
                 self.auth_mechanisms.clone(),
                 self.start_tls_extension,
                 self.insecure_allow_plaintext_auth,
+                self.ehlo_greeting.clone(),
+                self.extra_extensions.clone(),
+                self.leniency,
             ),
+            recorder: None,
+            body_bytes: 0,
         }
     }
 }
 
 impl<H: Handler> Session<H> {
     /// Get a greeting to send to the client
-    pub fn greeting(&self) -> Response {
-        Response::dynamic(220, format!("{} ESMTP", self.name), Vec::new())
+    pub fn greeting(&mut self) -> Response {
+        let greeting = Response::dynamic(220, self.greeting.clone(), Vec::new());
+        self.record_response(&greeting);
+        greeting
+    }
+
+    /// Record the transcript of this session with the given recorder.
+    ///
+    /// The recorder receives every command line from the client and every response sent by
+    /// the session. Message bodies are replaced by their size and authentication credentials
+    /// are redacted.
+    pub fn set_recorder<R: Recorder + 'static>(&mut self, recorder: R) {
+        self.recorder = Some(Box::new(recorder));
+    }
+
+    /// Report that the client sent data before the greeting was sent.
+    ///
+    /// Returns the response of the handler. If the response has `Action::Close` it should be
+    /// sent to the client instead of the greeting and the connection closed.
+    pub fn early_talker(&mut self) -> Response {
+        let mut response = self.handler.early_talker(self.fsm.ip());
+        if response.is_error {
+            response.action = Action::Close;
+        }
+        let response = replace(&self.replacements, response);
+        response.log();
+        self.record_response(&response);
+        response
+    }
+
+    /// True while the session is receiving a message body after DATA
+    pub fn is_receiving_message(&self) -> bool {
+        self.fsm.line_kind() == LineKind::Body
+    }
+
+    /// The phase of the session, which servers can use to choose timeouts
+    pub fn phase(&self) -> Phase {
+        self.fsm.phase()
     }
 
     /// STARTTLS active
+    ///
+    /// The handler is told that TLS started without any details, servers that know the
+    /// details of the TLS session should call `tls_started` instead.
     pub fn tls_active(&mut self) {
+        self.tls_started(&TlsInfo::default());
+    }
+
+    /// TLS has been negotiated with the client, either after STARTTLS or on connection with
+    /// implicit TLS. The details of the TLS session are passed to `Handler::tls_started`.
+    pub fn tls_started(&mut self, info: &TlsInfo) {
+        self.handler.tls_started(self.fsm.ip(), info);
         self.command(Cmd::StartedTls);
     }
 
+    /// The TLS handshake with the client failed, the failure is passed to
+    /// `Handler::tls_failed`. The session should be closed without a reply.
+    pub fn tls_failed(&mut self, failure: TlsFailure) {
+        self.handler.tls_failed(self.fsm.ip(), failure);
+    }
+
     /// Process a line sent by the client.
     ///
     /// Returns a response that should be written back to the client.
@@ -179,27 +360,106 @@ impl<H: Handler> Session<H> {
     /// assert_eq!(&msg, b"250 OK\r\n");
     /// ```
     pub fn process(&mut self, line: &[u8]) -> Response {
+        self.record_line(line);
         // TODO: process within fsm
         let response = match self.fsm.process_line(&mut self.handler, line) {
             Left(cmd) => self.command(cmd),
             Right(res) => res,
         };
+        let response = replace(&self.replacements, response);
         response.log();
+        self.record_response(&response);
+        response
+    }
+
+    // Record a line sent by the client, if a recorder is set
+    fn record_line(&mut self, line: &[u8]) {
+        let recorder = match self.recorder {
+            Some(ref mut r) => r,
+            None => return,
+        };
+        let event = match self.fsm.line_kind() {
+            LineKind::Body if line == b".\r\n" => Event::Body {
+                bytes: std::mem::take(&mut self.body_bytes),
+            },
+            LineKind::Body => {
+                self.body_bytes += line.len();
+                return;
+            }
+            LineKind::Credentials => Event::Credentials,
+            LineKind::Command => {
+                let line = String::from_utf8_lossy(line);
+                Event::Command(redact_command(line.trim_end_matches(['\r', '\n'])))
+            }
+        };
+        recorder.record(&Entry::now(event));
+    }
+
+    // Record a response sent to the client, if a recorder is set
+    fn record_response(&mut self, response: &Response) {
+        if let Some(ref mut recorder) = self.recorder {
+            if let Some(entry) = Entry::response(response) {
+                recorder.record(&entry);
+            }
+        }
+    }
+
+    /// Get the response to send in place of a built-in response, which is the replacement set
+    /// with `SessionBuilder::replace_response` if there is one. Servers use this for responses
+    /// they send themselves, such as `NO_SERVICE`, and the response is recorded as sent.
+    pub fn response(&mut self, builtin: Response) -> Response {
+        let response = replace(&self.replacements, builtin);
+        self.record_response(&response);
         response
     }
 
+    /// Build a client to deliver the mail queued by `Handler::atrn_transactions` after a
+    /// response with `Action::ReverseRoles`.
+    ///
+    /// The client should be driven over the same connection, which now has the roles of
+    /// client and server reversed (RFC 2645).
+    pub fn reverse_roles(&mut self) -> Client {
+        let transactions = self.handler.atrn_transactions();
+        ClientBuilder::new(self.name.clone()).build(transactions)
+    }
+
+    /// Report the outcomes of a client built by `reverse_roles()` to `Handler::atrn_outcomes`
+    pub fn reversed_outcomes(&mut self, outcomes: &[Outcome]) {
+        self.handler.atrn_outcomes(outcomes);
+    }
+
     fn command(&mut self, cmd: Cmd) -> Response {
         self.fsm.command(&mut self.handler, cmd)
     }
 }
 
+// Substitute any application defined replacement for the given response
+fn replace(replacements: &[(Response, Response)], response: Response) -> Response {
+    match replacements
+        .iter()
+        .find(|(builtin, _)| builtin.is_same(&response))
+    {
+        Some((_, replacement)) => {
+            let mut replaced = replacement.clone();
+            replaced.action = response.action;
+            replaced.delay = response.delay;
+            replaced
+        }
+        None => response,
+    }
+}
+
 //----- Tests ------------------------------------------------------------------
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::client::{ClientAction, Transaction};
     use crate::fsm::SmtpState;
+    use crate::Envelope;
     use std::net::Ipv4Addr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
     use ternop::ternary;
 
     struct EmptyHandler {}
@@ -248,6 +508,51 @@ mod tests {
         assert_state!(session.fsm.current_state(), SmtpState::Hello);
     }
 
+    #[test]
+    fn custom_greetings() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.name");
+        builder
+            .set_greeting("some.name ESMTP Sea Mail")
+            .set_ehlo_greeting("some.name welcomes you");
+        let mut session = builder.build(addr, EmptyHandler {});
+        let greeting = session.greeting().buffer().unwrap();
+        assert_eq!(greeting, b"220 some.name ESMTP Sea Mail\r\n");
+        let res = session.process(b"ehlo a.domain\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"250-some.name welcomes you\r\n250 8BITMIME\r\n"
+        );
+    }
+
+    #[test]
+    fn replaced_errors() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.name");
+        builder
+            .replace_response(
+                SYNTAX_ERROR,
+                Response::builder(500)
+                    .enhanced_code(5, 5, 2)
+                    .line("Syntax error")
+                    .line("See https://sea.com/smtp")
+                    .build(),
+            )
+            .replace_response(
+                BAD_SEQUENCE_COMMANDS,
+                Response::custom(503, "Say hello first".to_owned()),
+            );
+        let mut session = builder.build(addr, EmptyHandler {});
+        let res = session.process(b"bad command\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"500-5.5.2 Syntax error\r\n500 5.5.2 See https://sea.com/smtp\r\n"
+        );
+        assert!(res.is_error);
+        let res = session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(res.buffer().unwrap(), b"503 Say hello first\r\n");
+    }
+
     #[test]
     fn mail_from() {
         let mut session = new_session();
@@ -387,6 +692,273 @@ mod tests {
         assert_state!(session.fsm.current_state(), SmtpState::Mail);
     }
 
+    struct VerifyHandler {}
+    impl Handler for VerifyHandler {
+        fn vrfy(&mut self, address: &str) -> Response {
+            ternary!(
+                address == "captain",
+                Response::custom(250, "Sea Captain <captain@sea.com>".to_owned()),
+                NO_MAILBOX
+            )
+        }
+
+        fn expn(&mut self, list: &str) -> Response {
+            ternary!(
+                list == "crew",
+                Response::expansion(vec![
+                    "Sea Captain <captain@sea.com>".to_owned(),
+                    "<cook@sea.com>".to_owned(),
+                ]),
+                NO_MAILBOX
+            )
+        }
+    }
+
+    #[test]
+    fn vrfy_handler() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build(addr, VerifyHandler {});
+        session.process(b"helo a.domain\r\n");
+        let res = session.process(b"vrfy captain\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"250 Sea Captain <captain@sea.com>\r\n"
+        );
+        let res = session.process(b"vrfy kraken\r\n");
+        assert_eq!(res.code, 550);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    #[test]
+    fn expn_handler() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build(addr, VerifyHandler {});
+        session.process(b"helo a.domain\r\n");
+        let res = session.process(b"expn crew\r\n");
+        assert_eq!(
+            res.buffer().unwrap(),
+            b"250-Sea Captain <captain@sea.com>\r\n250 <cook@sea.com>\r\n"
+        );
+        let res = session.process(b"expn passengers\r\n");
+        assert_eq!(res.code, 550);
+        let res = new_session().process(b"expn crew\r\n");
+        assert_eq!(res.code, 503);
+    }
+
+    struct TarpitHandler {}
+    impl Handler for TarpitHandler {
+        fn rcpt(&mut self, _to: &str) -> Response {
+            NO_MAILBOX.with_delay(Duration::from_secs(10))
+        }
+    }
+
+    #[test]
+    fn delayed_response() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut session = SessionBuilder::new("some.name").build(addr, TarpitHandler {});
+        let res = session.process(b"helo a.domain\r\n");
+        assert_eq!(res.delay, None);
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res = session.process(b"rcpt to:<fish@sea.com>\r\n");
+        assert_eq!(res.code, 550);
+        assert_eq!(res.delay, Some(Duration::from_secs(10)));
+        assert_state!(session.fsm.current_state(), SmtpState::Mail);
+    }
+
+    #[test]
+    fn replaced_delayed_response() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.name");
+        builder
+            .enable_start_tls()
+            .replace_response(NO_MAILBOX, Response::custom(550, "No fish here".to_owned()))
+            .replace_response(START_TLS, Response::custom(220, "Go ahead".to_owned()))
+            .replace_response(NO_SERVICE, Response::custom(421, "Gone fishing".to_owned()));
+        let mut session = builder.build(addr, TarpitHandler {});
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        let res = session.process(b"rcpt to:<fish@sea.com>\r\n");
+        assert_eq!(res.buffer().unwrap(), b"550 No fish here\r\n");
+        assert_eq!(res.delay, Some(Duration::from_secs(10)));
+        session.process(b"rset\r\n");
+        let res = session.process(b"starttls\r\n");
+        assert_eq!(res.buffer().unwrap(), b"220 Go ahead\r\n");
+        assert_eq!(res.action, Action::UpgradeTls);
+        // Responses sent by servers
+        let res = session.response(NO_SERVICE);
+        assert_eq!(res.buffer().unwrap(), b"421 Gone fishing\r\n");
+        assert_eq!(res.action, Action::Close);
+        let res = builder.response(NO_SERVICE);
+        assert_eq!(res.buffer().unwrap(), b"421 Gone fishing\r\n");
+    }
+
+    struct EarlyTalkerHandler {}
+    impl Handler for EarlyTalkerHandler {
+        fn early_talker(&mut self, ip: IpAddr) -> Response {
+            ternary!(ip.is_loopback(), OK, NO_SERVICE)
+        }
+    }
+
+    #[test]
+    fn early_talker() {
+        let local = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let remote = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let builder = SessionBuilder::new("some.name");
+        let mut session = builder.build(local, EarlyTalkerHandler {});
+        let res = session.early_talker();
+        assert_eq!(res.action, Action::Reply);
+        let mut session = builder.build(remote, EarlyTalkerHandler {});
+        let res = session.early_talker();
+        assert_eq!(res.code, 421);
+        assert_eq!(res.action, Action::Close);
+    }
+
+    #[derive(Default)]
+    struct RequireTlsHandler {
+        require_tls: bool,
+        tls_not_required: bool,
+        tls_info: Option<(IpAddr, TlsInfo)>,
+        tls_failure: Option<TlsFailure>,
+    }
+    impl Handler for RequireTlsHandler {
+        fn tls_started(&mut self, ip: IpAddr, info: &TlsInfo) {
+            self.tls_info = Some((ip, info.clone()));
+        }
+
+        fn tls_failed(&mut self, _ip: IpAddr, failure: TlsFailure) {
+            self.tls_failure = Some(failure);
+        }
+
+        fn data_start_envelope(&mut self, envelope: &Envelope) -> Response {
+            self.require_tls = envelope.require_tls;
+            OK
+        }
+
+        fn tls_not_required(&mut self) {
+            self.tls_not_required = true;
+        }
+    }
+
+    fn new_require_tls_session() -> Session<RequireTlsHandler> {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.name");
+        builder.enable_start_tls();
+        builder.build(addr, RequireTlsHandler::default())
+    }
+
+    #[test]
+    fn require_tls_without_tls() {
+        let mut session = new_require_tls_session();
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(!ehlo.contains("REQUIRETLS"));
+        let res = session.process(b"mail from:<ship@sea.com> requiretls\r\n");
+        assert_eq!(res.code, 530);
+        assert_state!(session.fsm.current_state(), SmtpState::Hello);
+    }
+
+    #[test]
+    fn require_tls() {
+        let mut session = new_require_tls_session();
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"starttls\r\n");
+        session.tls_active();
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(ehlo.contains("250 REQUIRETLS\r\n"));
+        let res = session.process(b"mail from:<ship@sea.com> REQUIRETLS\r\n");
+        assert_eq!(res.code, 250);
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        assert!(session.handler.require_tls);
+        session.process(b"Subject: Hello\r\n");
+        session.process(b"TLS-Required: No\r\n");
+        session.process(b"\r\n");
+        session.process(b".\r\n");
+        assert!(session.handler.tls_not_required);
+    }
+
+    #[test]
+    fn tls_started() {
+        let mut session = new_require_tls_session();
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"starttls\r\n");
+        assert_eq!(session.handler.tls_info, None);
+        let info = TlsInfo {
+            protocol: Some("TLSv1.3".to_owned()),
+            cipher: Some("TLS13_AES_256_GCM_SHA384".to_owned()),
+            server_name: Some("mx.sea.com".to_owned()),
+            peer_certificate: None,
+        };
+        session.tls_started(&info);
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        assert_eq!(session.handler.tls_info, Some((ip, info)));
+        let res = session.process(b"mail from:<ship@sea.com> REQUIRETLS\r\n");
+        assert_eq!(res.code, 503);
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"mail from:<ship@sea.com> REQUIRETLS\r\n");
+        assert_eq!(res.code, 250);
+    }
+
+    #[test]
+    fn tls_failed() {
+        let mut session = new_require_tls_session();
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"starttls\r\n");
+        session.tls_failed(TlsFailure::NoSharedCipher);
+        assert_eq!(
+            session.handler.tls_failure,
+            Some(TlsFailure::NoSharedCipher)
+        );
+        assert_eq!(session.handler.tls_info, None);
+    }
+
+    #[test]
+    fn tls_required_in_body() {
+        let mut session = new_require_tls_session();
+        session.process(b"helo a.domain\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        assert!(!session.handler.require_tls);
+        session.process(b"Subject: Hello\r\n");
+        session.process(b"\r\n");
+        session.process(b"TLS-Required: No\r\n");
+        session.process(b".\r\n");
+        assert!(!session.handler.tls_not_required);
+    }
+
+    #[test]
+    fn lenient_session() {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("some.domain");
+        builder.set_leniency(Leniency::Lenient);
+        let mut session = builder.build(addr, EmptyHandler {});
+        assert_eq!(session.process(b"helo\r\n").code, 250);
+        assert_eq!(session.process(b"mail from: ship@sea.com\r\n").code, 250);
+        assert_eq!(session.process(b"rcpt to: <fish@sea.com> \r\n").code, 250);
+        assert_eq!(new_session().process(b"helo\r\n").code, 500);
+    }
+
+    #[test]
+    fn phases() {
+        let mut session = new_session();
+        assert_eq!(session.phase(), Phase::Greeting);
+        session.process(b"helo a.domain\r\n");
+        assert_eq!(session.phase(), Phase::Mail);
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        assert_eq!(session.phase(), Phase::Rcpt);
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        assert_eq!(session.phase(), Phase::Rcpt);
+        session.process(b"data\r\n");
+        assert_eq!(session.phase(), Phase::Data);
+        assert!(session.is_receiving_message());
+        session.process(b".\r\n");
+        assert_eq!(session.phase(), Phase::Mail);
+        session.process(b"quit\r\n");
+        assert_eq!(session.phase(), Phase::Closed);
+    }
+
     struct AuthHandler {}
     impl Handler for AuthHandler {
         fn auth_plain(
@@ -431,6 +1003,97 @@ mod tests {
         session.tls_active();
     }
 
+    struct RelayHandler {
+        outcomes: Vec<bool>,
+    }
+    impl Handler for RelayHandler {
+        fn etrn(&mut self, node: &str) -> Response {
+            ternary!(node == "@sea.com", OK, NO_MAILBOX)
+        }
+
+        fn atrn(&mut self, domains: &[&str]) -> Response {
+            ternary!(
+                domains == ["sea.com"],
+                Response::custom(250, "OK, now reversing the connection".to_owned()),
+                Response::custom(453, "You have no mail".to_owned())
+            )
+        }
+
+        fn atrn_transactions(&mut self) -> Vec<Transaction> {
+            vec![Transaction {
+                from: "ship@ocean.com".to_owned(),
+                to: vec!["fish@sea.com".to_owned()],
+                message: b"Subject: Queued\r\n\r\nHello\r\n".to_vec(),
+            }]
+        }
+
+        fn atrn_outcomes(&mut self, outcomes: &[Outcome]) {
+            self.outcomes = outcomes.iter().map(|o| o.is_delivered()).collect();
+        }
+
+        fn auth_plain(&mut self, _: &str, _: &str, password: &str) -> Response {
+            ternary!(password == "1234", AUTH_OK, INVALID_CREDENTIALS)
+        }
+    }
+
+    fn new_relay_session() -> Session<RelayHandler> {
+        let addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let mut builder = SessionBuilder::new("relay.domain");
+        builder
+            .enable_etrn()
+            .enable_atrn()
+            .enable_auth(AuthMechanism::Plain)
+            .insecure_enable_plaintext_auth();
+        builder.build(addr, RelayHandler { outcomes: vec![] })
+    }
+
+    #[test]
+    fn etrn() {
+        let mut session = new_relay_session();
+        let res = session.process(b"ehlo a.domain\r\n");
+        let ehlo = String::from_utf8(res.buffer().unwrap()).unwrap();
+        assert!(ehlo.contains("250-ETRN\r\n250 ATRN\r\n"));
+        assert_eq!(session.process(b"etrn @sea.com\r\n").code, 250);
+        assert_eq!(session.process(b"etrn @ocean.com\r\n").code, 550);
+        assert_eq!(new_session().process(b"etrn @sea.com\r\n").code, 503);
+    }
+
+    #[test]
+    fn atrn() {
+        let mut relay = new_relay_session();
+        relay.process(b"ehlo a.domain\r\n");
+        let res = relay.process(b"atrn sea.com\r\n");
+        assert_eq!(res.code, 530);
+        relay.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        let res = relay.process(b"atrn ocean.com\r\n");
+        assert_eq!(res.code, 453);
+        assert_eq!(res.action, Action::Reply);
+        let res = relay.process(b"atrn sea.com\r\n");
+        assert_eq!(res.action, Action::ReverseRoles);
+        assert_state!(relay.fsm.current_state(), SmtpState::Invalid);
+
+        // The original client now acts as a server
+        let mut client_session = new_data_session();
+        let mut client = relay.reverse_roles();
+        let mut action = client.process(&client_session.greeting().buffer().unwrap());
+        while let ClientAction::Send(buf) = action {
+            action = ClientAction::Wait;
+            for line in buf.split_inclusive(|b| *b == b'\n') {
+                let reply = client_session.process(line).buffer().unwrap();
+                for reply_line in reply.split_inclusive(|b| *b == b'\n') {
+                    action = client.process(reply_line);
+                }
+            }
+        }
+        assert_eq!(action, ClientAction::Close);
+        relay.reversed_outcomes(client.outcomes());
+        assert_eq!(relay.handler.outcomes, vec![true]);
+        assert_eq!(
+            client_session.handler.0,
+            b"Subject: Queued\r\n\r\nHello\r\n".to_vec()
+        );
+    }
+
     #[test]
     fn noauth_denied() {
         let mut session = new_auth_session(true);
@@ -450,7 +1113,8 @@ mod tests {
         let greeting = String::from_utf8(res.buffer().unwrap()).unwrap();
         assert_eq!(
             greeting,
-            "250-server offers extensions:\r\n250-8BITMIME\r\n250 AUTH PLAIN LOGIN\r\n".to_string()
+            "250-server offers extensions:\r\n250-8BITMIME\r\n250-REQUIRETLS\r\n250 AUTH PLAIN LOGIN\r\n"
+                .to_string()
         )
     }
 
@@ -593,6 +1257,124 @@ mod tests {
         assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
     }
 
+    #[test]
+    fn cancel_auth_challenge() {
+        let mut session = new_auth_session(true);
+        start_tls(&mut session);
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"auth login\r\n");
+        session.process(b"dGVzdA==\r\n"); // "test"
+        let res = session.process(b"*\r\n");
+        assert_eq!(res.code, 501);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+        let res = session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        assert_eq!(res.code, 235);
+    }
+
+    #[test]
+    fn auth_empty_initial_response() {
+        let mut session = new_auth_session(true);
+        start_tls(&mut session);
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth plain =\r\n");
+        assert_eq!(res.code, 535);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+        let res = session.process(b"auth login =\r\n");
+        assert_eq!(res, PASSWORD_AUTH_CHALLENGE);
+        assert_state!(session.fsm.current_state(), SmtpState::Auth);
+    }
+
+    #[test]
+    fn auth_invalid_base64() {
+        let mut session = new_auth_session(true);
+        start_tls(&mut session);
+        session.process(b"ehlo a.domain\r\n");
+        let res = session.process(b"auth plain dGVzd\r\n");
+        assert_eq!(res.code, 501);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+        session.process(b"auth plain\r\n");
+        let res = session.process(b"dGVzd\r\n");
+        assert_eq!(res.code, 501);
+        assert_state!(session.fsm.current_state(), SmtpState::HelloAuth);
+    }
+
+    struct VecRecorder(Arc<Mutex<Vec<Event>>>);
+    impl Recorder for VecRecorder {
+        fn record(&mut self, entry: &Entry) {
+            self.0.lock().unwrap().push(entry.event.clone());
+        }
+    }
+
+    #[test]
+    fn transcript() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut session = new_auth_session(true);
+        session.set_recorder(VecRecorder(events.clone()));
+        session.greeting();
+        start_tls(&mut session);
+        session.process(b"ehlo a.domain\r\n");
+        session.process(b"auth login\r\n");
+        session.process(b"dGVzdA==\r\n");
+        session.process(b"*\r\n");
+        session.process(b"auth plain dGVzdAB0ZXN0ADEyMzQ=\r\n");
+        session.process(b"mail from:<ship@sea.com>\r\n");
+        session.process(b"rcpt to:<fish@sea.com>\r\n");
+        session.process(b"data\r\n");
+        session.process(b"Subject: Hello\r\n");
+        session.process(b"\r\n");
+        session.process(b".\r\n");
+        let events = events.lock().unwrap();
+        let commands: Vec<&str> = events
+            .iter()
+            .filter_map(|e| match e {
+                Event::Command(line) => Some(line.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                "ehlo a.domain",
+                "starttls",
+                "ehlo a.domain",
+                "auth login",
+                "auth plain ****",
+                "mail from:<ship@sea.com>",
+                "rcpt to:<fish@sea.com>",
+                "data",
+            ]
+        );
+        assert_eq!(
+            events.iter().filter(|e| **e == Event::Credentials).count(),
+            2
+        );
+        assert!(events.contains(&Event::Body { bytes: 18 }));
+        match events.first() {
+            Some(Event::Response { code, lines }) => {
+                assert_eq!(*code, 220);
+                assert_eq!(lines, &vec!["220 some.domain ESMTP".to_owned()]);
+            }
+            _ => panic!("Greeting not recorded"),
+        }
+        assert!(matches!(
+            events.last(),
+            Some(Event::Response { code: 250, .. })
+        ));
+    }
+
+    #[test]
+    fn server_response_recorded() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut session = new_auth_session(false);
+        session.set_recorder(VecRecorder(events.clone()));
+        let res = session.response(NO_SERVICE);
+        assert_eq!(res, NO_SERVICE);
+        assert!(matches!(
+            events.lock().unwrap().last(),
+            Some(Event::Response { code: 421, .. })
+        ));
+    }
+
     #[test]
     fn bad_auth_login_username_challenge() {
         let mut session = new_auth_session(true);
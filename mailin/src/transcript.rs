@@ -0,0 +1,214 @@
+use crate::Response;
+use std::fmt::Write as _;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// An event that took place during an SMTP session
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// A command line sent by the client, without the line ending.
+    /// Credentials sent with an AUTH command are replaced with `****`.
+    Command(String),
+    /// A line of authentication data sent by the client, which is not recorded
+    Credentials,
+    /// A message body sent by the client, only the size in bytes is recorded
+    Body {
+        /// The number of bytes in the body, excluding the terminating `.` line
+        bytes: usize,
+    },
+    /// A response sent to the client
+    Response {
+        /// The SMTP reply code
+        code: u16,
+        /// The lines of the response, without line endings
+        lines: Vec<String>,
+    },
+}
+
+/// A timestamped entry in the transcript of an SMTP session
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// When the event took place
+    pub time: SystemTime,
+    /// What took place
+    pub event: Event,
+}
+
+impl Entry {
+    pub(crate) fn now(event: Event) -> Self {
+        Self {
+            time: SystemTime::now(),
+            event,
+        }
+    }
+
+    pub(crate) fn response(response: &Response) -> Option<Self> {
+        let buf = response.buffer().ok()?;
+        if buf.is_empty() {
+            return None;
+        }
+        let lines = String::from_utf8_lossy(&buf)
+            .lines()
+            .map(str::to_owned)
+            .collect();
+        Some(Self::now(Event::Response {
+            code: response.code,
+            lines,
+        }))
+    }
+
+    /// Serialise the entry as a single line JSON object, without a line ending.
+    ///
+    /// The time is given in milliseconds since the unix epoch.
+    /// ```
+    /// # use mailin::transcript::{Entry, Event};
+    /// # use std::time::{Duration, UNIX_EPOCH};
+    /// let entry = Entry {
+    ///     time: UNIX_EPOCH + Duration::from_millis(1500),
+    ///     event: Event::Body { bytes: 42 },
+    /// };
+    /// assert_eq!(entry.to_json(), r#"{"time_ms":1500,"event":"body","bytes":42}"#);
+    /// ```
+    pub fn to_json(&self) -> String {
+        let time_ms = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        let mut json = format!(r#"{{"time_ms":{},"event":"#, time_ms);
+        match &self.event {
+            Event::Command(line) => {
+                json.push_str(r#""command","line":"#);
+                push_json_string(&mut json, line);
+            }
+            Event::Credentials => json.push_str(r#""credentials""#),
+            Event::Body { bytes } => {
+                let _ = write!(json, r#""body","bytes":{}"#, bytes);
+            }
+            Event::Response { code, lines } => {
+                let _ = write!(json, r#""response","code":{},"lines":["#, code);
+                for (i, line) in lines.iter().enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+                    push_json_string(&mut json, line);
+                }
+                json.push(']');
+            }
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// A `Recorder` receives the transcript of a single SMTP session.
+///
+/// A recorder is attached to a session with `Session::set_recorder`.
+pub trait Recorder: Send {
+    /// Called for every entry in the transcript, in the order the events took place
+    fn record(&mut self, entry: &Entry);
+}
+
+/// A `Recorder` that writes the transcript as JSON lines (one JSON object per line).
+///
+/// Write errors are ignored so that a failing transcript does not abort the session.
+pub struct JsonlRecorder<W: io::Write + Send> {
+    out: W,
+}
+
+impl<W: io::Write + Send> JsonlRecorder<W> {
+    /// Create a recorder that writes to the given output
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    /// Return the underlying output
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: io::Write + Send> Recorder for JsonlRecorder<W> {
+    fn record(&mut self, entry: &Entry) {
+        let _ = writeln!(self.out, "{}", entry.to_json());
+    }
+}
+
+// Replace the initial response of an AUTH command, if any, with a placeholder
+pub(crate) fn redact_command(line: &str) -> String {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some(cmd), Some(mechanism), Some(_)) if cmd.eq_ignore_ascii_case("auth") => {
+            format!("{} {} ****", cmd, mechanism)
+        }
+        _ => line.to_owned(),
+    }
+}
+
+fn push_json_string(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn redact_auth() {
+        assert_eq!(
+            redact_command("AUTH PLAIN dGVzdAB0ZXN0ADEyMzQ="),
+            "AUTH PLAIN ****"
+        );
+        assert_eq!(redact_command("auth login"), "auth login");
+        assert_eq!(redact_command("MAIL FROM:<a@b>"), "MAIL FROM:<a@b>");
+    }
+
+    #[test]
+    fn json_lines() {
+        let time = UNIX_EPOCH + Duration::from_millis(7);
+        let entry = Entry {
+            time,
+            event: Event::Command("HELO \"quoted\"\\".to_owned()),
+        };
+        assert_eq!(
+            entry.to_json(),
+            r#"{"time_ms":7,"event":"command","line":"HELO \"quoted\"\\"}"#
+        );
+        let entry = Entry {
+            time,
+            event: Event::Response {
+                code: 250,
+                lines: vec!["250-a.domain".to_owned(), "250 8BITMIME".to_owned()],
+            },
+        };
+        assert_eq!(
+            entry.to_json(),
+            r#"{"time_ms":7,"event":"response","code":250,"lines":["250-a.domain","250 8BITMIME"]}"#
+        );
+        let entry = Entry {
+            time,
+            event: Event::Credentials,
+        };
+        let mut recorder = JsonlRecorder::new(Vec::new());
+        recorder.record(&entry);
+        assert_eq!(
+            recorder.into_inner(),
+            b"{\"time_ms\":7,\"event\":\"credentials\"}\n".to_vec()
+        );
+    }
+}